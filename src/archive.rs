@@ -0,0 +1,467 @@
+//! archive.rs looks inside .zip and .tar archives (including .tar.gz, .tar.xz, ...) for disk images
+//!
+//! Vendors often ship a single .img inside an archive, e.g. Raspberry Pi OS as a .zip
+//! The chosen entry is streamed straight out of the archive, nothing is extracted to disk
+//!
+//! .zip files are read through the `zip` crate's central directory, and the entry's data is then
+//! read directly from the file. .tar files have no index, so they are walked header by header
+
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind, Read, Result, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+
+use flate2::read::DeflateDecoder;
+use zip::{CompressionMethod, ZipArchive};
+
+use crate::image::{self, Compression, CountingReader, read_full};
+
+/// File name endings that are treated as disk images when listing an archive
+const IMAGE_EXTENSIONS: [&str; 7] = [".img", ".iso", ".raw", ".bin", ".dd", ".wic", ".hddimg"];
+/// Long names and pax headers are read into memory, real ones are a few hundred bytes at most
+const MAX_TAR_META: u64 = 1024 * 1024;
+
+/// The kinds of archive tEtcher can look inside
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveKind {
+    Zip,
+    /// A tar archive, possibly compressed as a whole (.tar.gz, .tar.xz, ...)
+    Tar(Compression),
+}
+
+/// One file inside an archive
+#[derive(Debug, Clone)]
+pub struct ArchiveEntry {
+    pub name: String,
+    /// Size of the entry once extracted
+    pub size: u64,
+}
+
+/// An archive entry opened for reading
+pub(crate) struct OpenedEntry {
+    pub reader: Box<dyn Read + Send>,
+    /// How many bytes of the archive file will be read by the time the entry is done, for progress
+    pub total: u64,
+    /// Size of the entry once extracted
+    pub size: u64,
+    /// Short description, e.g. "zip, deflate" or "xz-compressed tar"
+    pub kind: String,
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// True if the entry name looks like a disk image, possibly compressed (e.g. "foo.img.xz")
+fn is_image_name(name: &str) -> bool {
+    let lower = name.to_ascii_lowercase();
+    let stem = [".gz", ".xz", ".zst", ".bz2"]
+        .iter()
+        .find_map(|ext| lower.strip_suffix(ext))
+        .unwrap_or(&lower);
+    IMAGE_EXTENSIONS.iter().any(|ext| stem.ends_with(ext))
+}
+
+/// Checks whether the file is an archive tEtcher can look inside
+///
+/// A plain (possibly compressed) disk image gives `Ok(None)`
+pub fn detect(path: &Path) -> Result<Option<ArchiveKind>> {
+    let mut file = File::open(path)?;
+    let mut magic = [0u8; 4];
+    let n = read_full(&mut file, &mut magic)?;
+    if magic[..n] == *b"PK\x03\x04" {
+        return Ok(Some(ArchiveKind::Zip));
+    }
+
+    file.seek(SeekFrom::Start(0))?;
+    let (compression, stream) = image::detect_compression(Box::new(BufReader::new(file)))?;
+    let mut stream = image::decompress(compression, stream)?;
+    let mut header = [0u8; 512];
+    if read_full(&mut stream, &mut header)? == 512 && &header[257..262] == b"ustar" {
+        return Ok(Some(ArchiveKind::Tar(compression)));
+    }
+    Ok(None)
+}
+
+/// Lists the disk images inside an archive
+///
+/// If nothing in the archive has a disk image file name, every regular file is listed instead,
+/// so oddly named images can still be picked
+pub fn list_images(path: &Path) -> Result<Vec<ArchiveEntry>> {
+    let all = match detect(path)? {
+        Some(ArchiveKind::Zip) => list_zip(path)?,
+        Some(ArchiveKind::Tar(_)) => list_tar(path)?,
+        None => return Err(invalid(format!("{} is not a .zip or .tar archive", path.display()))),
+    };
+
+    let images: Vec<ArchiveEntry> = all.iter().filter(|e| is_image_name(&e.name)).cloned().collect();
+    Ok(if images.is_empty() { all } else { images })
+}
+
+/// Opens one entry of an archive for streaming
+///
+/// Every byte read from the archive file is added to `counter`, so progress can be tracked
+pub(crate) fn open_entry(path: &Path, name: &str, counter: Arc<AtomicU64>) -> Result<OpenedEntry> {
+    match detect(path)? {
+        Some(ArchiveKind::Zip) => open_zip_entry(path, name, counter),
+        Some(ArchiveKind::Tar(compression)) => open_tar_entry(path, name, compression, counter),
+        None => Err(invalid(format!("{} is not a .zip or .tar archive", path.display()))),
+    }
+}
+
+//
+// --- zip ---
+//
+
+fn zip_error(e: zip::result::ZipError) -> io::Error {
+    match e {
+        zip::result::ZipError::Io(e) => e,
+        e => invalid(e.to_string()),
+    }
+}
+
+fn list_zip(path: &Path) -> Result<Vec<ArchiveEntry>> {
+    let mut zip = ZipArchive::new(File::open(path)?).map_err(zip_error)?;
+    let mut entries = Vec::new();
+
+    for i in 0..zip.len() {
+        let file = zip.by_index_raw(i).map_err(zip_error)?;
+        if file.is_file() {
+            entries.push(ArchiveEntry { name: file.name().to_string(), size: file.size() });
+        }
+    }
+    Ok(entries)
+}
+
+fn open_zip_entry(path: &Path, name: &str, counter: Arc<AtomicU64>) -> Result<OpenedEntry> {
+    let mut zip = ZipArchive::new(File::open(path)?).map_err(zip_error)?;
+    let index = zip
+        .index_for_name(name)
+        .ok_or_else(|| invalid(format!("{} is not in {}", name, path.display())))?;
+    let file = zip.by_index_raw(index).map_err(zip_error)?;
+
+    if file.encrypted() {
+        return Err(invalid(format!("{} is encrypted", name)));
+    }
+
+    let (data_start, compressed_size, size, method) =
+        (file.data_start(), file.compressed_size(), file.size(), file.compression());
+    drop(file);
+
+    // Read the entry's data straight from the file, the central directory has already told us where it is
+    let mut raw = File::open(path)?;
+    raw.seek(SeekFrom::Start(data_start))?;
+    let counted = BufReader::with_capacity(
+        1024 * 1024,
+        CountingReader::new(raw.take(compressed_size), counter),
+    );
+
+    let (reader, kind): (Box<dyn Read + Send>, _) = match method {
+        CompressionMethod::Stored => (Box::new(counted), "zip, stored"),
+        CompressionMethod::Deflated => (Box::new(DeflateDecoder::new(counted)), "zip, deflate"),
+        other => return Err(invalid(format!("{} uses unsupported zip compression {}", name, other))),
+    };
+
+    Ok(OpenedEntry { reader, total: compressed_size, size, kind: kind.to_string() })
+}
+
+//
+// --- tar ---
+//
+
+/// One header walked by `TarWalker`
+struct TarHeader {
+    name: String,
+    size: u64,
+    is_file: bool,
+}
+
+/// Walks the headers of a tar stream, following GNU long names and pax path/size overrides
+struct TarWalker {
+    stream: Box<dyn Read + Send>,
+    /// Data bytes (plus padding) of the current entry that have not been read yet
+    pending: u64,
+}
+
+/// Parses a numeric tar field, either octal text or GNU base-256 for large sizes
+fn tar_number(field: &[u8]) -> Result<u64> {
+    if field[0] & 0x80 != 0 {
+        return Ok(field[1..].iter().fold(0u64, |acc, &b| (acc << 8) | u64::from(b)));
+    }
+
+    let text = String::from_utf8_lossy(field);
+    let text = text.trim_matches(|c: char| c == '\0' || c == ' ');
+    if text.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(text, 8).map_err(|_| invalid(format!("bad tar number '{}'", text)))
+}
+
+/// Turns a nul padded tar field into a string
+fn tar_string(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+impl TarWalker {
+    fn open(path: &Path, compression: Compression, counter: Arc<AtomicU64>) -> Result<TarWalker> {
+        let counted = BufReader::with_capacity(1024 * 1024, CountingReader::new(File::open(path)?, counter));
+        let stream = image::decompress(compression, Box::new(counted))?;
+        Ok(TarWalker { stream, pending: 0 })
+    }
+
+    /// Skips over whatever is left of the current entry's data
+    fn skip_pending(&mut self) -> Result<()> {
+        let skipped = io::copy(&mut (&mut self.stream).take(self.pending), &mut io::sink())?;
+        if skipped < self.pending {
+            return Err(invalid("tar archive ends in the middle of an entry".to_string()));
+        }
+        self.pending = 0;
+        Ok(())
+    }
+
+    /// Reads the data of a metadata entry (GNU long name or pax header)
+    fn read_meta(&mut self, size: u64) -> Result<Vec<u8>> {
+        if size > MAX_TAR_META {
+            return Err(invalid(format!("tar metadata entry of {} bytes, the archive is probably corrupted", size)));
+        }
+        let mut data = vec![0u8; size as usize];
+        self.stream.read_exact(&mut data)?;
+        self.pending = size.next_multiple_of(512) - size;
+        self.skip_pending()?;
+        Ok(data)
+    }
+
+    /// Moves to the next real entry, leaving the stream at the start of its data
+    fn next(&mut self) -> Result<Option<TarHeader>> {
+        self.skip_pending()?;
+
+        let mut long_name = None;
+        let mut pax_size = None;
+
+        loop {
+            let mut block = [0u8; 512];
+            if read_full(&mut self.stream, &mut block)? < 512 || block.iter().all(|&b| b == 0) {
+                return Ok(None);
+            }
+
+            let size = tar_number(&block[124..136])?;
+            let typeflag = block[156];
+
+            match typeflag {
+                // GNU long name: the data is the name of the next entry
+                b'L' => {
+                    let data = self.read_meta(size)?;
+                    long_name = Some(tar_string(&data));
+                }
+                // pax extended header: "<len> key=value\n" records
+                b'x' => {
+                    let data = self.read_meta(size)?;
+                    for record in String::from_utf8_lossy(&data).lines() {
+                        let Some((_, kv)) = record.split_once(' ') else { continue };
+                        match kv.split_once('=') {
+                            Some(("path", v)) => long_name = Some(v.to_string()),
+                            Some(("size", v)) => pax_size = v.parse().ok(),
+                            _ => {}
+                        }
+                    }
+                }
+                // pax global header, nothing we need
+                b'g' => {
+                    self.read_meta(size)?;
+                }
+                _ => {
+                    let name = long_name.take().unwrap_or_else(|| {
+                        let name = tar_string(&block[0..100]);
+                        let prefix = tar_string(&block[345..500]);
+                        if &block[257..262] == b"ustar" && !prefix.is_empty() {
+                            format!("{}/{}", prefix, name)
+                        } else {
+                            name
+                        }
+                    });
+                    let size = pax_size.take().unwrap_or(size);
+                    self.pending = size
+                        .checked_next_multiple_of(512)
+                        .ok_or_else(|| invalid(format!("tar entry of {} bytes, the archive is probably corrupted", size)))?;
+
+                    return Ok(Some(TarHeader {
+                        name,
+                        size,
+                        is_file: typeflag == b'0' || typeflag == 0,
+                    }));
+                }
+            }
+        }
+    }
+}
+
+fn list_tar(path: &Path) -> Result<Vec<ArchiveEntry>> {
+    let Some(ArchiveKind::Tar(compression)) = detect(path)? else {
+        return Err(invalid(format!("{} is not a tar archive", path.display())));
+    };
+
+    let mut walker = TarWalker::open(path, compression, Arc::new(AtomicU64::new(0)))?;
+    let mut entries = Vec::new();
+    while let Some(header) = walker.next()? {
+        if header.is_file {
+            entries.push(ArchiveEntry { name: header.name, size: header.size });
+        }
+    }
+    Ok(entries)
+}
+
+fn open_tar_entry(path: &Path, name: &str, compression: Compression, counter: Arc<AtomicU64>) -> Result<OpenedEntry> {
+    let total = std::fs::metadata(path)?.len();
+    let mut walker = TarWalker::open(path, compression, counter)?;
+
+    while let Some(header) = walker.next()? {
+        if header.is_file && header.name == name {
+            let kind = match compression {
+                Compression::None => "tar".to_string(),
+                c => format!("{}-compressed tar", c),
+            };
+            return Ok(OpenedEntry {
+                reader: Box::new(walker.stream.take(header.size)),
+                total,
+                size: header.size,
+                kind,
+            });
+        }
+    }
+
+    Err(invalid(format!("{} is not in {}", name, path.display())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    use crate::testutil::TempPath;
+
+    /// A tar header block, the checksum is left out as nothing here checks it
+    fn header(name: &str, prefix: &str, size: u64, typeflag: u8) -> Vec<u8> {
+        let mut block = vec![0u8; 512];
+        block[..name.len()].copy_from_slice(name.as_bytes());
+        if size < 0o77777777777 {
+            let octal = format!("{:011o}", size);
+            block[124..135].copy_from_slice(octal.as_bytes());
+        } else {
+            // GNU base-256
+            block[124] = 0x80;
+            block[128..136].copy_from_slice(&size.to_be_bytes());
+        }
+        block[156] = typeflag;
+        block[257..263].copy_from_slice(b"ustar\0");
+        block[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+        block
+    }
+
+    /// Adds an entry with its data, padded to a whole block
+    fn entry(tar: &mut Vec<u8>, name: &str, prefix: &str, typeflag: u8, data: &[u8]) {
+        tar.extend(header(name, prefix, data.len() as u64, typeflag));
+        tar.extend(data);
+        tar.resize(tar.len().next_multiple_of(512), 0);
+    }
+
+    fn read_entry(path: &Path, name: &str) -> Vec<u8> {
+        let mut opened = open_entry(path, name, Arc::new(AtomicU64::new(0))).unwrap();
+        let mut data = Vec::new();
+        opened.reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data.len() as u64, opened.size);
+        data
+    }
+
+    fn sample_tar() -> Vec<u8> {
+        let long = format!("{}/disk.img", "d".repeat(120));
+        let mut tar = Vec::new();
+        entry(&mut tar, "README", "", b'0', b"read me");
+        entry(&mut tar, "images", "", b'5', b"");
+        entry(&mut tar, "boot.img", "images", b'0', &[1; 700]);
+        entry(&mut tar, "././@LongLink", "", b'L', format!("{}\0", long).as_bytes());
+        entry(&mut tar, "truncated-name", "", b'0', &[2; 10]);
+        entry(&mut tar, "pax", "", b'x', b"30 path=pax/renamed.img.xz\n12 size=5\n");
+        entry(&mut tar, "ignored", "", b'0', &[3; 5]);
+        tar.extend([0; 1024]);
+        tar
+    }
+
+    #[test]
+    fn tar_walker_follows_prefixes_long_names_and_pax() {
+        let path = TempPath::file("sample.tar", sample_tar());
+        assert_eq!(detect(&path).unwrap(), Some(ArchiveKind::Tar(Compression::None)));
+
+        let names: Vec<(String, u64)> = list_tar(&path).unwrap().into_iter().map(|e| (e.name, e.size)).collect();
+        let long = format!("{}/disk.img", "d".repeat(120));
+        assert_eq!(
+            names,
+            [("README".to_string(), 7), ("images/boot.img".to_string(), 700), (long.clone(), 10), ("pax/renamed.img.xz".to_string(), 5)]
+        );
+        // Only the images are offered
+        assert_eq!(list_images(&path).unwrap().len(), 3);
+
+        assert_eq!(read_entry(&path, "images/boot.img"), [1; 700]);
+        assert_eq!(read_entry(&path, &long), [2; 10]);
+        assert_eq!(read_entry(&path, "pax/renamed.img.xz"), [3; 5]);
+        assert!(open_entry(&path, "missing.img", Arc::new(AtomicU64::new(0))).is_err());
+    }
+
+    #[test]
+    fn compressed_tar_is_walked_too() {
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        gz.write_all(&sample_tar()).unwrap();
+        let path = TempPath::file("sample.tar.gz", gz.finish().unwrap());
+        assert_eq!(detect(&path).unwrap(), Some(ArchiveKind::Tar(Compression::Gzip)));
+        assert_eq!(read_entry(&path, "images/boot.img"), [1; 700]);
+    }
+
+    #[test]
+    fn broken_tars_are_errors() {
+        // Ends in the middle of an entry
+        let mut tar = Vec::new();
+        entry(&mut tar, "a.img", "", b'0', &[1; 600]);
+        entry(&mut tar, "b.img", "", b'0', &[1; 10]);
+        let path = TempPath::file("short.tar", &tar[..700]);
+        assert!(list_tar(&path).is_err());
+
+        // Sizes no real archive has
+        for (typeflag, size) in [(b'L', 1u64 << 40), (b'0', u64::MAX)] {
+            let mut tar = header("huge", "", size, typeflag);
+            tar.extend([0; 1024]);
+            std::fs::write(&path, &tar).unwrap();
+            assert!(list_tar(&path).is_err());
+        }
+    }
+
+    #[test]
+    fn numbers_and_names() {
+        assert_eq!(tar_number(b"00000001750\0").unwrap(), 1000);
+        assert_eq!(tar_number(b"\0\0\0\0").unwrap(), 0);
+        assert!(tar_number(b"12389\0").is_err());
+        assert_eq!(tar_number(&[0x80, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0]).unwrap(), 1 << 32);
+
+        assert!(is_image_name("raspios.IMG.xz") && is_image_name("disk.wic") && is_image_name("x.iso.zst"));
+        assert!(!is_image_name("README.md") && !is_image_name("img.xz"));
+    }
+
+    #[test]
+    fn zip_entries_stream_out() {
+        let path = TempPath::file("sample.zip", b"");
+        let mut zip = zip::ZipWriter::new(File::create(&path).unwrap());
+        let stored = zip::write::SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+        let deflated = zip::write::SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+        zip.start_file("notes.txt", stored).unwrap();
+        zip.write_all(b"notes").unwrap();
+        zip.start_file("os.img", deflated).unwrap();
+        zip.write_all(&[9; 100_000]).unwrap();
+        zip.finish().unwrap();
+
+        assert_eq!(detect(&path).unwrap(), Some(ArchiveKind::Zip));
+        let images = list_images(&path).unwrap();
+        assert_eq!(images.iter().map(|e| (e.name.as_str(), e.size)).collect::<Vec<_>>(), [("os.img", 100_000)]);
+        assert_eq!(read_entry(&path, "os.img"), [9; 100_000]);
+        assert_eq!(read_entry(&path, "notes.txt"), b"notes");
+    }
+}
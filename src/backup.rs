@@ -0,0 +1,331 @@
+//! backup.rs reads a whole drive back into an image file, the opposite direction to flashing
+//!
+//! Handy for capturing a configured SD card or USB stick as a golden image
+//! The image can be compressed with zstd or xz on the way, and a "<image>.sha256" file is written
+//! next to it in the same format as `sha256sum`, so it can be checked with "sha256sum -c"
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Result, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use crossterm::style::Stylize;
+use sha2::{Digest, Sha256};
+use xz2::stream::{Check, MtStreamBuilder};
+use xz2::write::XzEncoder;
+
+use crate::image::read_full;
+use crate::progress::Progress;
+use crate::targ;
+use crate::ui;
+use crate::units;
+use crate::verify;
+
+/// How the backup image is compressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackupCompression {
+    None,
+    Zstd,
+    Xz,
+}
+
+impl BackupCompression {
+    /// File name ending for this kind of image
+    pub fn extension(self) -> &'static str {
+        match self {
+            BackupCompression::None => ".img",
+            BackupCompression::Zstd => ".img.zst",
+            BackupCompression::Xz => ".img.xz",
+        }
+    }
+
+    /// Parses "none", "zstd" or "xz", as given on the command line
+    pub fn parse(name: &str) -> Option<BackupCompression> {
+        match name.to_ascii_lowercase().as_str() {
+            "none" | "raw" => Some(BackupCompression::None),
+            "zstd" | "zst" => Some(BackupCompression::Zstd),
+            "xz" => Some(BackupCompression::Xz),
+            _ => None,
+        }
+    }
+}
+
+/// What a finished backup produced
+#[derive(Debug)]
+pub struct BackupResult {
+    /// Bytes read from the drive
+    pub bytes_read: u64,
+    /// Size of the image file written
+    pub output_size: u64,
+    /// SHA-256 of the image file as written (compressed, if it is), this is what the sidecar holds
+    pub sha256: String,
+    /// SHA-256 of the raw drive contents, the same as `sha256` for an uncompressed image
+    pub raw_sha256: String,
+    /// Where the checksum file was written
+    pub sidecar: PathBuf,
+}
+
+/// Passes writes through while hashing and counting everything that goes into the file
+struct HashingWriter<W> {
+    inner: W,
+    hash: Sha256,
+    written: u64,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hash.update(&buf[..n]);
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Whichever compressor is in use, `finish` hands back the writer underneath
+enum Encoder<W: Write> {
+    None(W),
+    Zstd(zstd::Encoder<'static, W>),
+    Xz(XzEncoder<W>),
+}
+
+impl<W: Write> Encoder<W> {
+    fn new(compression: BackupCompression, inner: W) -> Result<Encoder<W>> {
+        Ok(match compression {
+            BackupCompression::None => Encoder::None(inner),
+            BackupCompression::Zstd => Encoder::Zstd(zstd::Encoder::new(inner, 3)?),
+            BackupCompression::Xz => {
+                // xz is slow on a single core, so use them all
+                let threads = std::thread::available_parallelism().map_or(1, |n| n.get() as u32);
+                let stream = MtStreamBuilder::new()
+                    .threads(threads)
+                    .preset(6)
+                    .check(Check::Crc64)
+                    .encoder()
+                    .map_err(io::Error::other)?;
+                Encoder::Xz(XzEncoder::new_stream(inner, stream))
+            }
+        })
+    }
+
+    fn finish(self) -> Result<W> {
+        match self {
+            Encoder::None(w) => Ok(w),
+            Encoder::Zstd(e) => e.finish(),
+            Encoder::Xz(e) => e.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match self {
+            Encoder::None(w) => w.write(buf),
+            Encoder::Zstd(e) => e.write(buf),
+            Encoder::Xz(e) => e.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            Encoder::None(w) => w.flush(),
+            Encoder::Zstd(e) => e.flush(),
+            Encoder::Xz(e) => e.flush(),
+        }
+    }
+}
+
+/// `path` with `suffix` added to the end of its file name, e.g. "golden.img.zst.sha256"
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Reads the whole of `device_path` into `output`, printing progress as it goes
+///
+/// The image is written as "<output>.part" and only renamed to `output` once all of it is safely
+/// on disk, so a backup that fails part way never passes for a finished one. The .sha256 comes last
+///
+/// This does no prompting of its own, so it is shared by the menus and the command-line mode
+pub fn backup(device_path: &str, output: &Path, compression: BackupCompression) -> Result<BackupResult> {
+    let start = Instant::now();
+    let part = with_suffix(output, ".part");
+
+    let result = write_image(device_path, &part, output, compression).and_then(|result| {
+        fs::rename(&part, output)?;
+        Ok(result)
+    });
+    let result = match result {
+        Ok(result) => result,
+        Err(e) => {
+            let _ = fs::remove_file(&part);
+            return Err(e);
+        }
+    };
+
+    // Same layout as sha256sum: "<hex>  <file name>"
+    let file_name = output.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    fs::write(&result.sidecar, format!("{}  {}\n", result.sha256, file_name))?;
+
+    println!("Finished in {:.2?}\r", start.elapsed());
+    Ok(result)
+}
+
+/// Reads the drive into `part`, synced to disk when it returns. `output` is the name it will get
+fn write_image(device_path: &str, part: &Path, output: &Path, compression: BackupCompression) -> Result<BackupResult> {
+    const BS: usize = 4 * 1024 * 1024; // 4 MB buffer
+
+    let mut device = verify::open_device(device_path)?;
+    let device_size = device.seek(SeekFrom::End(0))?;
+    device.seek(SeekFrom::Start(0))?;
+
+    let file = File::create(part)?;
+    let hashing = HashingWriter { inner: BufWriter::with_capacity(BS, file), hash: Sha256::new(), written: 0 };
+    let mut encoder = Encoder::new(compression, hashing)?;
+
+    let mut raw_hash = Sha256::new();
+    let mut buffer = vec![0u8; BS];
+    let mut bytes_read: u64 = 0;
+    let mut progress = Progress::new(Some(device_size));
+
+    println!("Backing up {} ({}) → {}\r", device_path, units::human_size(device_size), output.display());
+
+    loop {
+        let n = read_full(&mut device, &mut buffer)?;
+        if n == 0 {
+            break;
+        }
+
+        raw_hash.update(&buffer[..n]);
+        encoder.write_all(&buffer[..n])?;
+        bytes_read += n as u64;
+        progress.print(bytes_read)?;
+    }
+    progress.finish(bytes_read)?;
+
+    let mut hashing = encoder.finish()?;
+    hashing.flush()?;
+    hashing.inner.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+    let sha256 = format!("{:x}", hashing.hash.finalize());
+    let raw_sha256 = format!("{:x}", raw_hash.finalize());
+    let sidecar = with_suffix(output, ".sha256");
+    Ok(BackupResult { bytes_read, output_size: hashing.written, sha256, raw_sha256, sidecar })
+}
+
+/// Prints what a backup produced
+pub fn print_result(output: &Path, result: &BackupResult) {
+    println!("{}\r", "✅ Backup complete".green().bold());
+    println!("  Read:       {} bytes ({})\r", result.bytes_read, units::human_size(result.bytes_read));
+    println!("  Image:      {} ({})\r", output.display(), units::human_size(result.output_size));
+    println!("  SHA-256:    {}\r", result.sha256);
+    if result.raw_sha256 != result.sha256 {
+        println!("  Raw SHA-256 (uncompressed): {}\r", result.raw_sha256);
+    }
+    println!("  Checksum:   {}\r", result.sidecar.display());
+}
+
+/// Menu flow for backing up: pick the drive, the compression, the folder and the file name
+pub fn menu(current_dir: &mut PathBuf) -> Result<()> {
+    let Some(drive) = targ::pick_drive("Which drive do you want to back up?")? else {
+        return Ok(());
+    };
+
+    let compressions = [BackupCompression::None, BackupCompression::Zstd, BackupCompression::Xz];
+    let Some(choice) = ui::choose(
+        &"How should the image be compressed?".blue().bold().to_string(),
+        &["None (.img)", "zstd (.img.zst) — fast", "xz (.img.xz) — smallest, slowest"],
+    )?
+    else {
+        return Ok(());
+    };
+    let compression = compressions[choice];
+
+    let Some(dir) = crate::browse("Choose the folder to save the image in", current_dir, true)? else {
+        return Ok(());
+    };
+
+    // Suggest a name based on the device, e.g. "sdb-backup.img.zst"
+    let device_name = Path::new(&drive.path)
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "drive".to_string());
+    let default_name = format!("{}-backup{}", device_name, compression.extension());
+
+    let Some(name) = ui::prompt("File name for the image:", &default_name)? else {
+        return Ok(());
+    };
+    let output = dir.join(name.trim());
+
+    if output.exists() && !ui::confirm(&format!("{} already exists. Overwrite it?", output.display()))? {
+        return Ok(());
+    }
+    if !ui::confirm(&format!("Back up {} to {}?", drive.label(), output.display()))? {
+        return Ok(());
+    }
+
+    print!("\x1B[H\x1B[2J");
+    match backup(&drive.path, &output, compression) {
+        Ok(result) => print_result(&output, &result),
+        Err(e) => println!("{}\r", format!("❌ Backup failed: {}", e).red().bold()),
+    }
+    ui::pause()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    use crate::image::{ImageReader, ImageSource};
+    use crate::testutil::TempPath;
+
+    #[test]
+    fn compression_names() {
+        assert_eq!(BackupCompression::parse("ZSTD"), Some(BackupCompression::Zstd));
+        assert_eq!(BackupCompression::parse("raw"), Some(BackupCompression::None));
+        assert_eq!(BackupCompression::parse("gzip"), None);
+    }
+
+    #[test]
+    fn backup_reads_back_as_the_drive() {
+        let data: Vec<u8> = (0..(5 << 20) + 77).map(|i: usize| (i % 241) as u8).collect();
+        let drive = TempPath::file("backup.drive", &data);
+        let raw_sha256 = format!("{:x}", Sha256::digest(&data));
+
+        for compression in [BackupCompression::None, BackupCompression::Zstd, BackupCompression::Xz] {
+            let dir = TempPath::dir("backup");
+            let output = dir.join(format!("drive{}", compression.extension()));
+            let result = backup(drive.as_str(), &output, compression).unwrap();
+            assert_eq!(result.bytes_read, data.len() as u64);
+            assert_eq!(result.raw_sha256, raw_sha256);
+
+            // The sidecar is for the file as written, which is what sha256sum -c checks
+            let written = std::fs::read(&output).unwrap();
+            assert_eq!(result.output_size, written.len() as u64);
+            assert_eq!(result.sha256, format!("{:x}", Sha256::digest(&written)));
+            let name = output.file_name().unwrap().to_string_lossy();
+            assert_eq!(std::fs::read_to_string(&result.sidecar).unwrap(), format!("{}  {}\n", result.sha256, name));
+
+            let mut back = Vec::new();
+            ImageReader::open(&ImageSource::file(&output)).unwrap().read_to_end(&mut back).unwrap();
+            assert!(back == data, "{:?} does not read back", compression);
+            assert!(!with_suffix(&output, ".part").exists());
+        }
+    }
+
+    #[test]
+    fn failed_backups_leave_nothing_behind() {
+        // A folder opens and seeks fine, but cannot be read, so this fails once the image is being written
+        let not_a_drive = TempPath::dir("backup.drive");
+        let dir = TempPath::dir("backup");
+        let output = dir.join("drive.img");
+        assert!(backup(not_a_drive.as_str(), &output, BackupCompression::None).is_err());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,420 @@
+//! bmap.rs reads the .bmap files that Yocto, Tizen and other embedded builds ship next to their images
+//!
+//! A bmap lists which blocks of the image actually hold data ("mapped"), with a checksum per range.
+//! The rest is empty space that does not need writing, which is how bmaptool turns a 16 GB rootfs
+//! flash into a few minutes. Unlike skipping zeros (see sparse.rs) the unmapped parts are left as
+//! they were on the drive, so a bmap flash is verified range by range and not byte for byte
+//!
+//! ```text
+//! <bmap version="2.0">
+//!     <ImageSize> 821752 </ImageSize>
+//!     <BlockSize> 4096 </BlockSize>
+//!     <BlocksCount> 201 </BlocksCount>
+//!     <ChecksumType> sha256 </ChecksumType>
+//!     <BmapFileChecksum> d9cf...4a1b </BmapFileChecksum>
+//!     <BlockMap>
+//!         <Range chksum="9eaf...e500"> 0-1 </Range>
+//!         <Range chksum="0a3c...77d2"> 9 </Range>
+//!     </BlockMap>
+//! </bmap>
+//! ```
+
+use std::io::{self, Result};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use sha2::digest::DynDigest;
+
+use crate::devio::{BlockWriter, DeviceWriter};
+use crate::image::ImageSource;
+use crate::units;
+
+/// The checksums a bmap can use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumType {
+    Sha1,
+    Sha256,
+}
+
+impl ChecksumType {
+    fn parse(name: &str) -> Result<ChecksumType> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "sha1" => Ok(ChecksumType::Sha1),
+            "sha256" => Ok(ChecksumType::Sha256),
+            other => Err(invalid(format!("{} checksums are not supported", other))),
+        }
+    }
+
+    /// A fresh hasher for this checksum
+    pub fn hasher(self) -> Box<dyn DynDigest + Send> {
+        match self {
+            ChecksumType::Sha1 => Box::new(<sha1::Sha1 as sha2::Digest>::new()),
+            ChecksumType::Sha256 => Box::new(<sha2::Sha256 as sha2::Digest>::new()),
+        }
+    }
+}
+
+/// One run of mapped blocks, as bytes of the image
+#[derive(Debug, Clone)]
+pub struct MappedRange {
+    pub bytes: Range<u64>,
+    /// Lower case hex, `None` if the bmap gives no checksum for it
+    pub checksum: Option<String>,
+}
+
+/// A parsed .bmap file
+#[derive(Debug, Clone)]
+pub struct Bmap {
+    /// The .bmap file it came from
+    pub path: PathBuf,
+    pub image_size: u64,
+    pub checksum_type: ChecksumType,
+    /// The mapped ranges, in order
+    pub ranges: Vec<MappedRange>,
+}
+
+/// Which .bmap to flash with
+#[derive(Debug, Clone, Default)]
+pub enum BmapChoice {
+    /// Use one found next to the image, if there is one
+    #[default]
+    Auto,
+    /// Use this one
+    File(PathBuf),
+    /// Flash the whole image even if there is a bmap
+    Ignore,
+}
+
+impl BmapChoice {
+    /// Loads the bmap to use for `image`, `None` if there is none
+    ///
+    /// A bmap that is there but broken is an error rather than quietly flashing the whole image
+    pub fn load(&self, image: &ImageSource) -> Result<Option<Bmap>> {
+        let path = match self {
+            BmapChoice::Auto => find(image),
+            BmapChoice::File(path) => Some(path.clone()),
+            BmapChoice::Ignore => None,
+        };
+        path.map(|p| load(&p)).transpose()
+    }
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+impl Bmap {
+    /// Bytes of the image that will be written
+    pub fn mapped_bytes(&self) -> u64 {
+        self.ranges.iter().map(|r| r.bytes.end - r.bytes.start).sum()
+    }
+
+    /// One line for the confirmation screens, e.g. "Using foo.bmap: 1.20 GiB of 16.00 GiB is written (7%)"
+    pub fn describe(&self) -> String {
+        let name = self.path.file_name().map_or(String::new(), |n| n.to_string_lossy().into_owned());
+        let percent = (self.mapped_bytes() * 100).checked_div(self.image_size).unwrap_or(100);
+        format!(
+            "Using {}: {} of {} is written ({}%)",
+            name,
+            units::human_size(self.mapped_bytes()),
+            units::human_size(self.image_size),
+            percent
+        )
+    }
+}
+
+/// Looks for a .bmap next to the image: "foo.wic.xz.bmap", then "foo.wic.bmap", then "foo.bmap"
+pub fn find(image: &ImageSource) -> Option<PathBuf> {
+    let mut name = image.path.file_name()?.to_string_lossy().into_owned();
+    loop {
+        let candidate = image.path.with_file_name(format!("{}.bmap", name));
+        if candidate.is_file() {
+            return Some(candidate);
+        }
+        name = name.rsplit_once('.')?.0.to_string();
+    }
+}
+
+/// Reads and checks a .bmap file
+pub fn load(path: &Path) -> Result<Bmap> {
+    parse(&std::fs::read_to_string(path)?, path)
+}
+
+/// Checks the text of the .bmap at `path`
+fn parse(text: &str, path: &Path) -> Result<Bmap> {
+    let doc = roxmltree::Document::parse(text)
+        .map_err(|e| invalid(format!("{} is not a valid bmap: {}", path.display(), e)))?;
+    let root = doc.root_element();
+    if !root.has_tag_name("bmap") {
+        return Err(invalid(format!("{} is not a bmap file", path.display())));
+    }
+
+    let field = |name: &str| {
+        root.children()
+            .find(|n| n.has_tag_name(name))
+            .and_then(|n| n.text())
+            .map(str::trim)
+            .ok_or_else(|| invalid(format!("{} has no {}", path.display(), name)))
+    };
+    let number = |name: &str| field(name)?.parse::<u64>().map_err(|_| invalid(format!("{} has a bad {}", path.display(), name)));
+
+    let image_size = number("ImageSize")?;
+    let block_size = number("BlockSize")?;
+    if block_size == 0 {
+        return Err(invalid(format!("{} has a block size of 0", path.display())));
+    }
+    // Before version 1.4 there was no ChecksumType and everything was SHA-1
+    let checksum_type = match field("ChecksumType") {
+        Ok(name) => ChecksumType::parse(name)?,
+        Err(_) => ChecksumType::Sha1,
+    };
+
+    // The file's own checksum is taken with the checksum itself replaced by zeros
+    if let Ok(own) = field("BmapFileChecksum") {
+        let zeroed = text.replacen(own, &"0".repeat(own.len()), 1);
+        let mut hasher = checksum_type.hasher();
+        hasher.update(zeroed.as_bytes());
+        if hex(&hasher.finalize()) != own.to_ascii_lowercase() {
+            return Err(invalid(format!("{} is corrupted, its own checksum does not match", path.display())));
+        }
+    }
+
+    let map = root
+        .children()
+        .find(|n| n.has_tag_name("BlockMap"))
+        .ok_or_else(|| invalid(format!("{} has no BlockMap", path.display())))?;
+
+    let mut ranges: Vec<MappedRange> = Vec::new();
+    for range in map.children().filter(|n| n.has_tag_name("Range")) {
+        let text = range.text().unwrap_or("").trim();
+        let bad = || invalid(format!("{} has a bad range \"{}\"", path.display(), text));
+        let (first, last) = text.split_once('-').unwrap_or((text, text));
+        let first = first.trim().parse::<u64>().map_err(|_| bad())?;
+        let last = last.trim().parse::<u64>().map_err(|_| bad())?;
+
+        // The numbers come from the file, so one far past the image must not overflow
+        let start = first.checked_mul(block_size).ok_or_else(bad)?;
+        let end = last.checked_add(1).and_then(|end| end.checked_mul(block_size)).ok_or_else(bad)?.min(image_size);
+        if last < first || start >= end || ranges.last().is_some_and(|r| r.bytes.end > start) {
+            return Err(bad());
+        }
+        // Version 1.x called the attribute "sha1"
+        let checksum = range.attribute("chksum").or(range.attribute("sha1")).map(|c| c.trim().to_ascii_lowercase());
+        ranges.push(MappedRange { bytes: start..end, checksum });
+    }
+
+    Ok(Bmap { path: path.to_path_buf(), image_size, checksum_type, ranges })
+}
+
+/// Lower case hex of a digest
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Checks the image against the bmap's checksums as it streams past, one block at a time
+///
+/// This is what catches a corrupted image, since only part of it ends up on the drive
+pub struct RangeChecker {
+    ranges: Vec<MappedRange>,
+    /// The range being hashed
+    next: usize,
+    hasher: Box<dyn DynDigest + Send>,
+    pos: u64,
+}
+
+impl RangeChecker {
+    pub fn new(bmap: &Bmap) -> RangeChecker {
+        RangeChecker {
+            ranges: bmap.ranges.clone(),
+            next: 0,
+            hasher: bmap.checksum_type.hasher(),
+            pos: 0,
+        }
+    }
+
+    /// Feeds the next block of the image through, failing on the first range that does not match
+    pub fn feed(&mut self, block: &[u8]) -> Result<()> {
+        let end = self.pos + block.len() as u64;
+
+        while let Some(range) = self.ranges.get(self.next) {
+            if range.bytes.start >= end {
+                break;
+            }
+            let from = range.bytes.start.max(self.pos);
+            let to = range.bytes.end.min(end);
+            self.hasher.update(&block[(from - self.pos) as usize..(to - self.pos) as usize]);
+
+            if range.bytes.end > end {
+                break;
+            }
+            // The whole range has been seen
+            let actual = hex(&self.hasher.finalize_reset());
+            if range.checksum.as_ref().is_some_and(|expected| *expected != actual) {
+                return Err(invalid(format!(
+                    "the image does not match its bmap at bytes {}..{}, it is probably corrupted",
+                    range.bytes.start, range.bytes.end
+                )));
+            }
+            self.next += 1;
+        }
+
+        self.pos = end;
+        Ok(())
+    }
+
+    /// The image should have covered every range, a short one is as bad as a corrupted one
+    pub fn finish(&self) -> Result<()> {
+        match self.ranges.get(self.next) {
+            Some(range) => Err(invalid(format!(
+                "the image ended at {} bytes, before the bmap's range at {}..{}",
+                self.pos, range.bytes.start, range.bytes.end
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Writes only the mapped ranges of each block to the target, seeking over the rest
+pub struct MappedWriter {
+    device: DeviceWriter,
+    ranges: Vec<Range<u64>>,
+    /// The first range that has not been fully written yet
+    next: usize,
+    pos: u64,
+    /// Bytes of the image that were not written
+    skipped: u64,
+}
+
+impl MappedWriter {
+    pub fn new(device: DeviceWriter, bmap: &Bmap) -> MappedWriter {
+        let ranges = bmap.ranges.iter().map(|r| r.bytes.clone()).collect();
+        MappedWriter { device, ranges, next: 0, pos: 0, skipped: 0 }
+    }
+}
+
+impl BlockWriter for MappedWriter {
+    /// Writes the mapped parts of the next block of the image
+    fn write_block(&mut self, block: &[u8]) -> Result<()> {
+        let end = self.pos + block.len() as u64;
+        let mut written = 0;
+
+        while let Some(range) = self.ranges.get(self.next) {
+            if range.start >= end {
+                break;
+            }
+            let from = range.start.max(self.pos);
+            let to = range.end.min(end);
+            self.device.write_at(from, &block[(from - self.pos) as usize..(to - self.pos) as usize])?;
+            written += to - from;
+
+            if range.end > end {
+                break;
+            }
+            self.next += 1;
+        }
+
+        self.skipped += block.len() as u64 - written;
+        self.pos = end;
+        Ok(())
+    }
+
+    fn skip(&mut self, len: u64) {
+        self.pos += len;
+        // A range that carries on past `pos` is picked up part way by `write_block`
+        while self.ranges.get(self.next).is_some_and(|r| r.end <= self.pos) {
+            self.next += 1;
+        }
+    }
+
+    fn device(&mut self) -> &mut DeviceWriter {
+        &mut self.device
+    }
+
+    fn finish(&mut self) -> Result<u64> {
+        self.device.finish()?;
+        Ok(self.skipped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+
+    fn sha256(data: &[u8]) -> String {
+        hex(&Sha256::digest(data))
+    }
+
+    fn bmap_text(image_size: u64, ranges: &[(&str, String)]) -> String {
+        let ranges: String = ranges.iter().map(|(r, sum)| format!("<Range chksum=\"{}\"> {} </Range>\n", sum, r)).collect();
+        format!(
+            "<bmap version=\"2.0\"><ImageSize> {} </ImageSize><BlockSize> 4 </BlockSize>\
+             <ChecksumType> sha256 </ChecksumType><BlockMap>{}</BlockMap></bmap>",
+            image_size, ranges
+        )
+    }
+
+    fn parse_text(text: &str) -> Result<Bmap> {
+        parse(text, Path::new("test.bmap"))
+    }
+
+    #[test]
+    fn ranges_are_read_as_bytes() {
+        let bmap = parse_text(&bmap_text(18, &[("0-1", "ab".into()), ("4", "CD".into())])).unwrap();
+        assert_eq!(bmap.image_size, 18);
+        assert_eq!(bmap.checksum_type, ChecksumType::Sha256);
+        let ranges: Vec<_> = bmap.ranges.iter().map(|r| (r.bytes.clone(), r.checksum.clone().unwrap())).collect();
+        // The last block is cut short by the image size
+        assert_eq!(ranges, vec![(0..8, "ab".to_string()), (16..18, "cd".to_string())]);
+        assert_eq!(bmap.mapped_bytes(), 10);
+    }
+
+    #[test]
+    fn bad_ranges_are_refused() {
+        for range in ["2-1", "1-1x", "4611686018427387904", "0-18446744073709551615", "9"] {
+            assert!(parse_text(&bmap_text(18, &[(range, String::new())])).is_err(), "{}", range);
+        }
+        // Overlapping or out of order
+        assert!(parse_text(&bmap_text(64, &[("2-3", String::new()), ("3", String::new())])).is_err());
+    }
+
+    #[test]
+    fn own_checksum_is_checked() {
+        let zeros = "0".repeat(64);
+        let text = bmap_text(8, &[("0-1", String::new())]).replace("<BlockMap>", &format!("<BmapFileChecksum>{}</BmapFileChecksum><BlockMap>", zeros));
+        let own = sha256(text.as_bytes());
+        assert!(parse_text(&text.replacen(&zeros, &own, 1)).is_ok());
+        assert!(parse_text(&text.replacen(&zeros, &"1".repeat(64), 1)).is_err());
+    }
+
+    #[test]
+    fn checker_follows_ranges_across_blocks() {
+        let image: Vec<u8> = (0..24).collect();
+        let text = bmap_text(24, &[("1-3", sha256(&image[4..16])), ("5", sha256(&image[20..24]))]);
+        let bmap = parse_text(&text).unwrap();
+
+        let mut checker = RangeChecker::new(&bmap);
+        for block in image.chunks(5) {
+            checker.feed(block).unwrap();
+        }
+        checker.finish().unwrap();
+
+        // Stopping before the last range is an error
+        let mut checker = RangeChecker::new(&bmap);
+        checker.feed(&image[..18]).unwrap();
+        assert!(checker.finish().is_err());
+
+        // A changed byte outside the ranges is fine
+        let mut changed = image.clone();
+        changed[0] = 0xff;
+        let mut checker = RangeChecker::new(&bmap);
+        checker.feed(&changed).unwrap();
+        checker.finish().unwrap();
+
+        // One inside a range is caught once the whole range has been fed
+        changed[9] = 0xff;
+        let mut checker = RangeChecker::new(&bmap);
+        assert!(checker.feed(&changed[..8]).is_ok());
+        assert!(checker.feed(&changed[8..]).is_err());
+    }
+}
@@ -0,0 +1,94 @@
+//! cancel.rs is how a flash is stopped part way without leaving a mess behind
+//!
+//! In the menus the terminal is in raw mode, so Esc and Ctrl-C arrive as keys and flash.rs asks for a
+//! stop itself. From the command line (or `kill`) they arrive as SIGINT/SIGTERM instead, and the
+//! handler installed here asks for the stop. Either way the flash stops at the next block, syncs what
+//! was written and reports the drive as incomplete. A second signal quits straight away
+//!
+//! The handler and the panic hook both put the terminal back the way it was before quitting, so a
+//! crash never leaves the shell in raw mode with the cursor hidden
+
+use std::io::{self, stdout};
+use std::process::exit;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crossterm::{cursor, execute, style::ResetColor, terminal};
+
+/// Exit code after being stopped by a signal, as the shell would give for SIGINT
+pub const EXIT_CANCELLED: i32 = 130;
+
+/// Set while a flash is running, the only time a signal does not quit straight away
+static FLASHING: AtomicBool = AtomicBool::new(false);
+/// Set once a stop has been asked for
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+/// Set if the stop came from a signal, in which case the program quits once the flash has stopped
+static SIGNALLED: AtomicBool = AtomicBool::new(false);
+
+/// Sets up the panic hook and the SIGINT/SIGTERM (Ctrl-C on Windows) handler, once at start up
+pub fn install() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        restore_terminal();
+        default_hook(info);
+    }));
+
+    // Runs on a thread of its own, not in the signal handler, so it can do as it likes
+    let handled = ctrlc::set_handler(|| {
+        if FLASHING.load(Ordering::SeqCst) && !CANCELLED.swap(true, Ordering::SeqCst) {
+            SIGNALLED.store(true, Ordering::SeqCst);
+            return;
+        }
+        restore_terminal();
+        exit(EXIT_CANCELLED);
+    });
+    if let Err(e) = handled {
+        eprintln!("tetcher: Ctrl-C will not stop a flash cleanly: {}", e);
+    }
+}
+
+/// Puts the terminal back to normal: out of raw mode, cursor shown, colours reset
+pub fn restore_terminal() {
+    let _ = terminal::disable_raw_mode();
+    let _ = execute!(stdout(), ResetColor, cursor::Show);
+}
+
+/// Marks a flash as running until it is dropped, and clears any stop left over from the last one
+pub struct Flashing;
+
+impl Flashing {
+    pub fn start() -> Flashing {
+        CANCELLED.store(false, Ordering::SeqCst);
+        SIGNALLED.store(false, Ordering::SeqCst);
+        FLASHING.store(true, Ordering::SeqCst);
+        Flashing
+    }
+}
+
+impl Drop for Flashing {
+    fn drop(&mut self) {
+        FLASHING.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Asks the running flash to stop, e.g. because Esc was pressed
+pub fn request() {
+    CANCELLED.store(true, Ordering::SeqCst);
+}
+
+/// Whether a stop has been asked for
+pub fn requested() -> bool {
+    CANCELLED.load(Ordering::SeqCst)
+}
+
+/// If the last flash was stopped by a signal, puts the terminal back and quits, as the signal asked
+pub fn exit_if_signalled() {
+    if SIGNALLED.load(Ordering::SeqCst) {
+        restore_terminal();
+        exit(EXIT_CANCELLED);
+    }
+}
+
+/// The error a drive is left with when the flash is stopped before the whole image is on it
+pub fn incomplete() -> io::Error {
+    io::Error::new(io::ErrorKind::Interrupted, "cancelled, the drive is incomplete and will not work until it is flashed again")
+}
@@ -0,0 +1,285 @@
+//! checksum.rs checks a downloaded image against the checksum file published next to it, before
+//! anything is written, since a corrupted download is where most bad flashes start
+//!
+//! Checksum files are looked for in the image's folder: SHA256SUMS, SHA512SUMS, "<image>.sha256",
+//! "<image>.sha512" and any other *.sha256 / *.sha512 file that lists the image
+//! Both line styles are understood:
+//!
+//! ```text
+//! GNU:  4ddeba38...  ubuntu-24.04-desktop-amd64.iso      (or " *ubuntu..." for binary mode)
+//! BSD:  SHA256 (ubuntu-24.04-desktop-amd64.iso) = 4ddeba38...
+//! ```
+//!
+//! The file on disk is what gets hashed, so for an archive it is the archive itself, as downloaded
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Result};
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::progress::Progress;
+use crate::units;
+
+/// Checksum files bigger than this are not read, real ones are a few KiB
+const MAX_CHECKSUM_FILE: u64 = 1024 * 1024;
+
+/// The digests tEtcher can check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Sha256,
+    Sha512,
+}
+
+impl Algorithm {
+    /// Works the algorithm out from the length of the hex digest
+    fn from_hex_len(len: usize) -> Option<Algorithm> {
+        match len {
+            64 => Some(Algorithm::Sha256),
+            128 => Some(Algorithm::Sha512),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Algorithm::Sha256 => "SHA-256",
+            Algorithm::Sha512 => "SHA-512",
+        })
+    }
+}
+
+/// A digest a checksum file gives for the image
+#[derive(Debug, Clone)]
+pub struct Expected {
+    pub algorithm: Algorithm,
+    /// Lower case hex
+    pub hex: String,
+    /// The checksum file it came from
+    pub source: PathBuf,
+}
+
+/// The outcome of hashing the image
+#[derive(Debug)]
+pub struct CheckResult {
+    pub expected: Expected,
+    /// What the image actually hashes to
+    pub actual: String,
+}
+
+impl CheckResult {
+    pub fn is_ok(&self) -> bool {
+        self.actual == self.expected.hex
+    }
+
+    /// One line for the screen, e.g. "SHA-256 matches SHA256SUMS"
+    pub fn describe(&self) -> String {
+        let file = self.expected.source.file_name().map_or(String::new(), |n| n.to_string_lossy().into_owned());
+        if self.is_ok() {
+            format!("{} matches {}", self.expected.algorithm, file)
+        } else {
+            format!(
+                "{} does NOT match {}\n  expected {}\n  found    {}",
+                self.expected.algorithm, file, self.expected.hex, self.actual
+            )
+        }
+    }
+}
+
+/// True if the file name looks like a checksum file
+fn is_checksum_name(name: &str) -> bool {
+    let lower = name.to_ascii_lowercase();
+    let plain = ["sha256sums", "sha512sums", "sha256sum.txt", "sha512sum.txt", "sha256sums.txt", "sha512sums.txt", "checksums", "checksum"];
+    let endings = [".sha256", ".sha512", ".sha256sum", ".sha512sum"];
+    plain.contains(&lower.as_str()) || endings.iter().any(|e| lower.ends_with(e))
+}
+
+/// A hex digest of a length this knows about, lower-cased
+fn digest(text: &str) -> Option<(Algorithm, String)> {
+    let algorithm = Algorithm::from_hex_len(text.len())?;
+    text.bytes().all(|b| b.is_ascii_hexdigit()).then(|| (algorithm, text.to_ascii_lowercase()))
+}
+
+/// Finds the line for `image_name` in the text of a checksum file
+///
+/// With `bare_ok` a file holding nothing but a digest counts too, that is how "<image>.sha256" files
+/// are often written
+fn find_in(text: &str, image_name: &str, bare_ok: bool) -> Option<(Algorithm, String)> {
+    let lines: Vec<&str> = text.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')).collect();
+
+    for line in &lines {
+        // BSD: "SHA256 (name) = hex"
+        if let Some((left, hex)) = line.rsplit_once(") = ")
+            && let Some((_, name)) = left.split_once(" (")
+            && same_name(name, image_name) {
+            return digest(hex.trim());
+        }
+
+        // GNU: "hex  name" or "hex *name"
+        if let Some((hex, name)) = line.split_once(char::is_whitespace)
+            && same_name(name.trim_start().trim_start_matches('*'), image_name) {
+            return digest(hex);
+        }
+    }
+
+    match lines.as_slice() {
+        [only] if bare_ok => digest(only.split_whitespace().next().unwrap_or("")),
+        _ => None,
+    }
+}
+
+/// Compares the name in a checksum line with the image's, ignoring any "./" or folders in front
+fn same_name(listed: &str, image_name: &str) -> bool {
+    let listed = listed.trim();
+    let listed = listed.rsplit(['/', '\\']).next().unwrap_or(listed);
+    listed == image_name
+}
+
+/// Reads the digest for `image` from a checksum file, `None` if the file does not list it
+pub fn from_file(checksum_file: &Path, image: &Path) -> Result<Option<Expected>> {
+    if fs::metadata(checksum_file)?.len() > MAX_CHECKSUM_FILE {
+        return Ok(None);
+    }
+    let text = String::from_utf8_lossy(&fs::read(checksum_file)?).into_owned();
+    let image_name = image.file_name().map_or(String::new(), |n| n.to_string_lossy().into_owned());
+
+    // "foo.iso.sha256" is about foo.iso even if it holds just the digest
+    let checksum_name = checksum_file.file_name().map_or(String::new(), |n| n.to_string_lossy().into_owned());
+    let bare_ok = checksum_name.starts_with(&format!("{}.", image_name));
+
+    Ok(find_in(&text, &image_name, bare_ok)
+        .map(|(algorithm, hex)| Expected { algorithm, hex, source: checksum_file.to_path_buf() }))
+}
+
+/// Looks in the image's folder for checksum files that list it
+///
+/// SHA-512 is preferred over SHA-256 when both are there, only the strongest one is returned
+pub fn find(image: &Path) -> Option<Expected> {
+    let dir = image.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let mut found: Vec<Expected> = fs::read_dir(dir)
+        .ok()?
+        .flatten()
+        .filter(|e| is_checksum_name(&e.file_name().to_string_lossy()))
+        .filter_map(|e| from_file(&e.path(), image).ok().flatten())
+        .collect();
+
+    found.sort_by_key(|e| e.algorithm == Algorithm::Sha256);
+    found.into_iter().next()
+}
+
+/// Hashes the image file, printing progress, and compares it with what the checksum file says
+pub fn check(image: &Path, expected: &Expected) -> Result<CheckResult> {
+    const BS: usize = 4 * 1024 * 1024; // 4 MB buffer
+
+    let mut file = File::open(image)?;
+    let size = file.metadata()?.len();
+    let mut buf = vec![0u8; BS];
+    let mut done: u64 = 0;
+    let mut progress = Progress::new(Some(size));
+
+    let mut sha256 = Sha256::new();
+    let mut sha512 = Sha512::new();
+
+    println!(
+        "Checking the {} of {} ({}) against {}\r",
+        expected.algorithm,
+        image.display(),
+        units::human_size(size),
+        expected.source.display()
+    );
+
+    loop {
+        let n = match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        match expected.algorithm {
+            Algorithm::Sha256 => sha256.update(&buf[..n]),
+            Algorithm::Sha512 => sha512.update(&buf[..n]),
+        }
+        done += n as u64;
+        progress.print(done)?;
+    }
+    progress.finish(done)?;
+
+    let actual = match expected.algorithm {
+        Algorithm::Sha256 => format!("{:x}", sha256.finalize()),
+        Algorithm::Sha512 => format!("{:x}", sha512.finalize()),
+    };
+    Ok(CheckResult { expected: expected.clone(), actual })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempPath;
+
+    const HELLO_256: &str = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+
+    #[test]
+    fn gnu_and_bsd_lines() {
+        let text = format!("# made by sha256sum\n{}  other.iso\n{} *./images/debian.iso\n", "0".repeat(64), HELLO_256);
+        assert_eq!(find_in(&text, "debian.iso", false), Some((Algorithm::Sha256, HELLO_256.to_string())));
+        assert_eq!(find_in(&text, "missing.iso", false), None);
+
+        let upper = HELLO_256.to_ascii_uppercase();
+        let bsd = format!("SHA256 (debian.iso) = {}\n", upper);
+        assert_eq!(find_in(&bsd, "debian.iso", false), Some((Algorithm::Sha256, HELLO_256.to_string())));
+
+        let sha512 = "a".repeat(128);
+        assert_eq!(find_in(&format!("{}  win\\debian.iso", sha512), "debian.iso", false), Some((Algorithm::Sha512, sha512)));
+    }
+
+    #[test]
+    fn digests_must_have_a_known_length() {
+        assert_eq!(find_in(&format!("{}  debian.iso", &HELLO_256[..40]), "debian.iso", true), None);
+        assert_eq!(find_in(&format!("{}  debian.iso", HELLO_256.replace('b', "x")), "debian.iso", true), None);
+    }
+
+    #[test]
+    fn bare_digests_only_when_the_file_is_named_after_the_image() {
+        let text = format!("{}\n", HELLO_256);
+        assert_eq!(find_in(&text, "debian.iso", true), Some((Algorithm::Sha256, HELLO_256.to_string())));
+        assert_eq!(find_in(&text, "debian.iso", false), None);
+        // Two digests and no names say nothing about which one is the image's
+        assert_eq!(find_in(&format!("{}\n{}\n", HELLO_256, HELLO_256), "debian.iso", true), None);
+    }
+
+    #[test]
+    fn checksum_file_names() {
+        for name in ["SHA256SUMS", "sha512sum.txt", "CHECKSUM", "debian.iso.sha256", "debian.iso.SHA512SUM"] {
+            assert!(is_checksum_name(name), "{}", name);
+        }
+        for name in ["debian.iso", "SHA256SUMS.gpg", "sha256"] {
+            assert!(!is_checksum_name(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn found_next_to_the_image_and_checked() {
+        let dir = TempPath::dir("checksum");
+        let image = dir.join("hello.img");
+        fs::write(&image, "hello world").unwrap();
+        assert!(find(&image).is_none());
+
+        fs::write(dir.join("hello.img.sha256"), HELLO_256).unwrap();
+        let expected = find(&image).unwrap();
+        assert_eq!((expected.algorithm, expected.hex.as_str()), (Algorithm::Sha256, HELLO_256));
+        let result = check(&image, &expected).unwrap();
+        assert!(result.is_ok());
+        assert_eq!(result.describe(), "SHA-256 matches hello.img.sha256");
+
+        // SHA-512 wins when both are there, and a wrong one is reported
+        fs::write(dir.join("SHA512SUMS"), format!("{}  hello.img\n", "0".repeat(128))).unwrap();
+        let expected = find(&image).unwrap();
+        assert_eq!(expected.algorithm, Algorithm::Sha512);
+        let result = check(&image, &expected).unwrap();
+        assert!(!result.is_ok());
+        assert!(result.describe().starts_with("SHA-512 does NOT match SHA512SUMS"));
+    }
+}
@@ -0,0 +1,714 @@
+//! cli.rs lets tEtcher run without any menus, so it can be used from scripts and CI jobs
+//!
+//! For example: "tetcher --image foo.iso --target /dev/sdb --yes --verify"
+//! or, to read a drive back into an image: "tetcher --backup /dev/sdb --output golden.img.zst --compress zstd"
+//!
+//! The exit code tells the caller what happened:
+//! 0 - flashed (and verified, if asked)
+//! 1 - the flash finished but verification failed
+//! 2 - an I/O error stopped the flash or the verification
+//! 3 - the image did not match its checksum, or the checksum file's signature was bad, so nothing was written
+//!
+//! With several targets, the worst outcome of any drive decides the exit code
+//! 64 - the arguments were wrong, or the user did not confirm
+//! 130 - stopped by Ctrl-C or SIGTERM, run again with --resume to carry on
+
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+use crate::archive;
+use crate::backup::{self, BackupCompression};
+use crate::bmap::BmapChoice;
+use crate::cancel;
+use crate::checksum;
+use crate::flash::{self, FlashOptions};
+use crate::image::ImageSource;
+use crate::iso;
+use crate::rescue;
+use crate::resume;
+use crate::safety;
+use crate::signature;
+use crate::verify;
+
+pub const EXIT_OK: i32 = 0;
+pub const EXIT_VERIFY_FAILED: i32 = 1;
+pub const EXIT_IO_ERROR: i32 = 2;
+pub const EXIT_IMAGE_REJECTED: i32 = 3;
+pub const EXIT_USAGE: i32 = 64;
+
+const USAGE: &str = "\
+Usage: tetcher [--image <file> [--entry <name>] --target <device>... [--yes] [--verify] [--skip-zeros] [--bmap <file> | --no-bmap]
+               [--checksum <file>] [--force] [--resume] [--no-rescue]]
+       tetcher --backup <device> --output <file> [--compress none|zstd|xz] [--yes]
+       tetcher --verify-only (--image <file> | --sha256 <hex> --size <bytes>) --target <device>...
+       tetcher --restore <rescue file> --target <device> [--unmount] [--yes]
+       tetcher --list-rescue
+
+With no arguments, tEtcher starts the interactive menus.
+
+Options:
+  -i, --image <file>      Image to flash, may be compressed or a .zip/.tar archive
+  -e, --entry <name>      Image to use inside the archive, only needed if it holds several
+  -k, --checksum <file>   SHA256SUMS-style file to check the image against before flashing
+                          (by default one is looked for next to the image)
+  -f, --force             Flash even if the image does not match its checksum, or the
+                          checksum file's signature is bad
+  -t, --target <device>   Device to overwrite, e.g. /dev/sdb or \\\\.\\PHYSICALDRIVE1
+                          Repeat to write several devices at once
+  -y, --yes               Do not ask for confirmation before overwriting the device
+  -v, --verify            Read the device back and compare it with the image
+  -z, --skip-zeros        Discard the device, then skip the all-zero blocks of the image
+                          instead of writing them (much faster for mostly empty images)
+      --bmap <file>       Only write the blocks a .bmap file lists, checking each range's checksum
+                          (by default a <image>.bmap next to the image is used if there is one)
+      --no-bmap           Write the whole image even if there is a .bmap next to it
+      --resume            Carry on an earlier flash of the same image that was stopped part way,
+                          after checking the last 64 MiB it wrote are still on the drive
+      --verify-only       Only check a drive flashed earlier, against --image or --sha256 and --size
+      --sha256 <hex>      SHA-256 of the image, for --verify-only when the image is not at hand
+      --size <bytes>      Size of the image the --sha256 covers
+  -u, --unmount           Unmount the target's partitions if any are mounted (the system disk is always refused)
+  -b, --backup <device>   Read the whole device into an image file instead of flashing
+  -o, --output <file>     Where to write the backup image, a <file>.sha256 is written next to it
+  -c, --compress <kind>   Compress the backup with zstd or xz (default: none)
+  -r, --restore <file>    Undo a flash: put the saved start and end of a drive back onto --target
+                          (the start and end of every drive are saved before it is flashed)
+      --no-rescue         Flash without saving the start and end of the drive first, so the flash
+                          cannot be undone (a drive they cannot be saved from is refused otherwise)
+      --list-rescue       List the saved rescue files, newest first
+  -h, --help              Show this message";
+
+/// Everything that can be set from the command line
+#[derive(Debug, Default)]
+struct Options {
+    image: Option<String>,
+    entry: Option<String>,
+    targets: Vec<String>,
+    checksum: Option<String>,
+    force: bool,
+    yes: bool,
+    verify: bool,
+    skip_zeros: bool,
+    bmap: Option<String>,
+    no_bmap: bool,
+    resume: bool,
+    no_rescue: bool,
+    verify_only: bool,
+    sha256: Option<String>,
+    size: Option<String>,
+    unmount: bool,
+    backup: Option<String>,
+    output: Option<String>,
+    compress: Option<String>,
+    restore: Option<String>,
+    list_rescue: bool,
+    help: bool,
+}
+
+/// Parses the arguments (without the program name)
+///
+/// Both "--image foo.iso" and "--image=foo.iso" are accepted
+fn parse(args: &[String]) -> Result<Options, String> {
+    let mut opts = Options::default();
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => (flag, Some(value.to_string())),
+            _ => (arg.as_str(), None),
+        };
+
+        // Takes the value either from "--flag=value" or from the next argument
+        let mut value = |name: &str| -> Result<String, String> {
+            match inline.clone() {
+                Some(v) => Ok(v),
+                None => args.next().cloned().ok_or(format!("{} needs a value", name)),
+            }
+        };
+
+        match flag {
+            "-i" | "--image" => opts.image = Some(value("--image")?),
+            "-e" | "--entry" => opts.entry = Some(value("--entry")?),
+            "-t" | "--target" => opts.targets.push(value("--target")?),
+            "-k" | "--checksum" => opts.checksum = Some(value("--checksum")?),
+            "-f" | "--force" => opts.force = true,
+            "-y" | "--yes" => opts.yes = true,
+            "-v" | "--verify" => opts.verify = true,
+            "-z" | "--skip-zeros" => opts.skip_zeros = true,
+            "--bmap" => opts.bmap = Some(value("--bmap")?),
+            "--no-bmap" => opts.no_bmap = true,
+            "--resume" => opts.resume = true,
+            "--no-rescue" => opts.no_rescue = true,
+            "--verify-only" => opts.verify_only = true,
+            "--sha256" => opts.sha256 = Some(value("--sha256")?),
+            "--size" => opts.size = Some(value("--size")?),
+            "-u" | "--unmount" => opts.unmount = true,
+            "-b" | "--backup" => opts.backup = Some(value("--backup")?),
+            "-o" | "--output" => opts.output = Some(value("--output")?),
+            "-c" | "--compress" => opts.compress = Some(value("--compress")?),
+            "-r" | "--restore" => opts.restore = Some(value("--restore")?),
+            "--list-rescue" => opts.list_rescue = true,
+            "-h" | "--help" => opts.help = true,
+            _ => return Err(format!("unknown argument '{}'", arg)),
+        }
+    }
+
+    if !opts.help {
+        check_mode(&opts)?;
+    }
+    Ok(opts)
+}
+
+/// The flags that were given, by their long names
+fn given(opts: &Options) -> Vec<&'static str> {
+    [
+        ("--image", opts.image.is_some()),
+        ("--entry", opts.entry.is_some()),
+        ("--target", !opts.targets.is_empty()),
+        ("--checksum", opts.checksum.is_some()),
+        ("--force", opts.force),
+        ("--yes", opts.yes),
+        ("--verify", opts.verify),
+        ("--skip-zeros", opts.skip_zeros),
+        ("--bmap", opts.bmap.is_some()),
+        ("--no-bmap", opts.no_bmap),
+        ("--resume", opts.resume),
+        ("--no-rescue", opts.no_rescue),
+        ("--verify-only", opts.verify_only),
+        ("--sha256", opts.sha256.is_some()),
+        ("--size", opts.size.is_some()),
+        ("--unmount", opts.unmount),
+        ("--backup", opts.backup.is_some()),
+        ("--output", opts.output.is_some()),
+        ("--compress", opts.compress.is_some()),
+        ("--restore", opts.restore.is_some()),
+        ("--list-rescue", opts.list_rescue),
+    ]
+    .into_iter()
+    .filter_map(|(flag, set)| set.then_some(flag))
+    .collect()
+}
+
+/// Refuses flags from another mode, e.g. "--backup /dev/sdb --image foo.iso", rather than quietly
+/// ignoring them
+fn check_mode(opts: &Options) -> Result<(), String> {
+    let (mode, allowed): (&str, &[&str]) = if opts.backup.is_some() {
+        ("with --backup", &["--backup", "--output", "--compress", "--yes"])
+    } else if opts.list_rescue {
+        ("with --list-rescue", &["--list-rescue"])
+    } else if opts.restore.is_some() {
+        ("with --restore", &["--restore", "--target", "--unmount", "--yes"])
+    } else if opts.verify_only {
+        ("with --verify-only", &["--verify-only", "--image", "--entry", "--target", "--sha256", "--size", "--bmap", "--no-bmap"])
+    } else {
+        (
+            "when flashing",
+            &[
+                "--image", "--entry", "--target", "--checksum", "--force", "--yes", "--verify", "--skip-zeros", "--bmap",
+                "--no-bmap", "--resume", "--no-rescue", "--unmount",
+            ],
+        )
+    };
+
+    match given(opts).into_iter().find(|flag| !allowed.contains(flag)) {
+        Some(flag) => Err(format!("{} cannot be used {}", flag, mode)),
+        None => Ok(()),
+    }
+}
+
+/// Works out which image to use, picking the archive entry if the file is an archive
+fn image_source(image: &str, entry: Option<String>) -> Result<ImageSource, String> {
+    let path = Path::new(image);
+    let kind = archive::detect(path).map_err(|e| format!("cannot read {}: {}", image, e))?;
+
+    match (kind, entry) {
+        (None, None) => Ok(ImageSource::file(path)),
+        (None, Some(_)) => Err(format!("{} is not a .zip or .tar archive, --entry does not apply", image)),
+        (Some(_), Some(entry)) => Ok(ImageSource::archive_entry(path, entry)),
+        (Some(_), None) => {
+            let entries = archive::list_images(path).map_err(|e| format!("cannot read {}: {}", image, e))?;
+            match entries.as_slice() {
+                [only] => Ok(ImageSource::archive_entry(path, only.name.clone())),
+                [] => Err(format!("{} does not contain any files", image)),
+                _ => {
+                    let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+                    Err(format!("{} holds several images, pick one with --entry: {}", image, names.join(", ")))
+                }
+            }
+        }
+    }
+}
+
+/// Refuses the system disk, and a disk with mounted partitions unless `unmount` is set
+///
+/// Nothing is unmounted here, that waits until the user has said yes (see `unmount_target`). Returns
+/// the report of a disk that still needs unmounting
+fn check_target(target: &str, unmount: bool) -> io::Result<Option<safety::SafetyReport>> {
+    let report = safety::check(target)?;
+    if report.is_clear() {
+        return Ok(None);
+    }
+    if !unmount || !report.can_unmount() {
+        return Err(report.to_error());
+    }
+    Ok(Some(report))
+}
+
+/// Unmounts what `check_target` found, then makes sure nothing got mounted again in between
+fn unmount_target(report: &safety::SafetyReport) -> io::Result<()> {
+    for m in &report.mounts {
+        println!("Unmounting {} from {}", m.source, m.mount_point);
+    }
+    safety::unmount(report)?;
+    safety::ensure_safe(&report.device)
+}
+
+/// Asks on stdin before the device gets overwritten, only "yes" continues
+fn confirm(image: &ImageSource, targets: &[String], to_unmount: &[safety::SafetyReport]) -> io::Result<bool> {
+    for line in flash::partition_preview(image, targets) {
+        println!("{}", line);
+    }
+    for m in to_unmount.iter().flat_map(|r| &r.mounts) {
+        println!("{} will be unmounted from {} (--unmount)", m.source, m.mount_point);
+    }
+    println!();
+    print!("Flash {} to {}? THIS WILL OVERWRITE *ALL* DISK CONTENTS. Type 'yes' to continue: ", image, targets.join(", "));
+    io::stdout().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(answer.trim().eq_ignore_ascii_case("yes"))
+}
+
+/// Runs tEtcher non-interactively and returns the process exit code
+pub fn run(args: &[String]) -> i32 {
+    let opts = match parse(args) {
+        Ok(opts) => opts,
+        Err(e) => {
+            eprintln!("tetcher: {}\n\n{}", e, USAGE);
+            return EXIT_USAGE;
+        }
+    };
+
+    if opts.help {
+        println!("{}", USAGE);
+        return EXIT_OK;
+    }
+
+    if let Some(device) = &opts.backup {
+        return run_backup(device, &opts);
+    }
+
+    if opts.list_rescue {
+        return list_rescue();
+    }
+
+    if let Some(file) = &opts.restore {
+        return run_restore(file, &opts);
+    }
+
+    if opts.verify_only {
+        return run_verify_only(opts);
+    }
+
+    let mut flash_options = FlashOptions { skip_zeros: opts.skip_zeros, bmap: bmap_choice(&opts), skip_rescue: opts.no_rescue, ..Default::default() };
+    let Some(image) = opts.image else {
+        eprintln!("tetcher: --image is required\n\n{}", USAGE);
+        return EXIT_USAGE;
+    };
+    if opts.targets.is_empty() {
+        eprintln!("tetcher: at least one --target is required\n\n{}", USAGE);
+        return EXIT_USAGE;
+    }
+
+    let image = match image_source(&image, opts.entry) {
+        Ok(image) => image,
+        Err(e) => {
+            eprintln!("tetcher: {}", e);
+            return EXIT_USAGE;
+        }
+    };
+
+    // A corrupted download is refused before anything is touched, unless --force
+    let expected = match &opts.checksum {
+        Some(file) => match checksum::from_file(Path::new(file), &image.path) {
+            Ok(Some(expected)) => Some(expected),
+            Ok(None) => {
+                eprintln!("tetcher: {} does not list {}", file, image.path.display());
+                return EXIT_USAGE;
+            }
+            Err(e) => {
+                eprintln!("tetcher: cannot read {}: {}", file, e);
+                return EXIT_USAGE;
+            }
+        },
+        None => checksum::find(&image.path),
+    };
+    if let Some(expected) = expected {
+        // A bad signature on the checksum file is refused the same way
+        if let Some(check) = signature::check(&expected.source) {
+            if !check.is_bad() {
+                println!("{}", check);
+            } else if opts.force {
+                eprintln!("tetcher: warning: {}, flashing anyway (--force)", check);
+            } else {
+                eprintln!("tetcher: {}\nThe checksum file may have been tampered with, add --force to flash anyway", check);
+                return EXIT_IMAGE_REJECTED;
+            }
+        }
+
+        match checksum::check(&image.path, &expected) {
+            Ok(result) if result.is_ok() => println!("{}", result.describe()),
+            Ok(result) if opts.force => eprintln!("tetcher: warning: {}, flashing anyway (--force)", result.describe()),
+            Ok(result) => {
+                eprintln!("tetcher: {}\nThe download is probably corrupted, add --force to flash it anyway", result.describe());
+                return EXIT_IMAGE_REJECTED;
+            }
+            Err(e) => {
+                eprintln!("tetcher: cannot check {}: {}", image.path.display(), e);
+                return EXIT_IO_ERROR;
+            }
+        }
+    }
+
+    // Non-hybrid ISOs will not boot from a USB drive, worth saying even though nothing stops here
+    if let Ok(Some(info)) = iso::inspect(&image)
+        && let Some(warning) = info.warning() {
+        eprintln!("tetcher: warning: {}", warning.replace('\n', " "));
+    }
+
+    // Refuse anything in use before asking. With --unmount, what is mounted is only unmounted once
+    // the user has said yes, so answering no leaves everything as it was
+    let mut to_unmount = Vec::new();
+    for target in &opts.targets {
+        match check_target(target, opts.unmount) {
+            Ok(Some(report)) => to_unmount.push(report),
+            Ok(None) => {}
+            Err(e) => {
+                eprintln!("tetcher: {}", e);
+                return EXIT_USAGE;
+            }
+        }
+    }
+
+    // Only the blocks a .bmap lists get written, a broken one stops here rather than halfway through
+    let bmap = match flash_options.bmap.load(&image) {
+        Ok(bmap) => bmap,
+        Err(e) => {
+            eprintln!("tetcher: {}", e);
+            return EXIT_USAGE;
+        }
+    };
+
+    // A flash of this image that was stopped part way is only carried on when asked for
+    for target in &opts.targets {
+        match resume::Journal::find(&image, target, &flash_options) {
+            Some(journal) if opts.resume => {
+                println!("Carrying on the earlier flash to {}, {}", target, journal.describe());
+                flash_options.resume.push(journal);
+            }
+            Some(journal) => {
+                println!("An earlier flash of {} to {} {}, add --resume to carry on from there", image, target, journal.describe());
+            }
+            None if opts.resume => println!("Nothing to carry on for {}, flashing it from the beginning", target),
+            None => {}
+        }
+    }
+
+    if !opts.yes {
+        match confirm(&image, &opts.targets, &to_unmount) {
+            Ok(true) => {}
+            Ok(false) => {
+                eprintln!("Aborted, nothing was written.");
+                return EXIT_USAGE;
+            }
+            Err(e) => {
+                eprintln!("tetcher: {}", e);
+                return EXIT_IO_ERROR;
+            }
+        }
+    }
+
+    for report in &to_unmount {
+        if let Err(e) = unmount_target(report) {
+            eprintln!("tetcher: {}", e);
+            return EXIT_USAGE;
+        }
+    }
+
+    let results = match flash::flash_iso(&image, &opts.targets, &flash_options) {
+        Ok(results) => results,
+        Err(e) => {
+            eprintln!("tetcher: reading {} failed: {}", image, e);
+            return EXIT_IO_ERROR;
+        }
+    };
+
+    let mut exit = EXIT_OK;
+    for result in &results {
+        let code = if let Some(e) = &result.error {
+            eprintln!("tetcher: flashing {} to {} failed: {}", image, result.device, e);
+            EXIT_IO_ERROR
+        } else if !opts.verify {
+            EXIT_OK
+        } else {
+            match verify::verify_flashed(&image, &result.device, bmap.as_ref()) {
+                Ok(report) if report.is_ok() => EXIT_OK,
+                Ok(_) => EXIT_VERIFY_FAILED,
+                Err(e) => {
+                    eprintln!("tetcher: verifying {} failed: {}", result.device, e);
+                    EXIT_IO_ERROR
+                }
+            }
+        };
+        exit = exit.max(code);
+    }
+
+    if cancel::requested() {
+        return cancel::EXIT_CANCELLED;
+    }
+    exit
+}
+
+/// Which .bmap the options ask for, --no-bmap wins over everything
+fn bmap_choice(opts: &Options) -> BmapChoice {
+    match (&opts.bmap, opts.no_bmap) {
+        (_, true) => BmapChoice::Ignore,
+        (Some(file), false) => BmapChoice::File(file.into()),
+        (None, false) => BmapChoice::Auto,
+    }
+}
+
+/// Backup mode: reads a device into an image file
+fn run_backup(device: &str, opts: &Options) -> i32 {
+    let Some(output) = &opts.output else {
+        eprintln!("tetcher: --backup needs --output\n\n{}", USAGE);
+        return EXIT_USAGE;
+    };
+
+    let compression = match opts.compress.as_deref().map(BackupCompression::parse) {
+        None => BackupCompression::None,
+        Some(Some(c)) => c,
+        Some(None) => {
+            eprintln!("tetcher: --compress must be none, zstd or xz\n\n{}", USAGE);
+            return EXIT_USAGE;
+        }
+    };
+
+    let output = Path::new(output);
+    if output.exists() && !opts.yes {
+        eprintln!("tetcher: {} already exists, add --yes to overwrite it", output.display());
+        return EXIT_USAGE;
+    }
+
+    match backup::backup(device, output, compression) {
+        Ok(result) => {
+            backup::print_result(output, &result);
+            EXIT_OK
+        }
+        Err(e) => {
+            eprintln!("tetcher: backing up {} failed: {}", device, e);
+            EXIT_IO_ERROR
+        }
+    }
+}
+
+/// Lists the rescue files, newest first
+fn list_rescue() -> i32 {
+    match rescue::list() {
+        Ok(files) => {
+            if files.is_empty() {
+                println!("No rescue files yet, one is saved every time a drive is flashed");
+            }
+            for file in files {
+                println!("{}\n    {}", file.path.display(), file.label());
+            }
+            EXIT_OK
+        }
+        Err(e) => {
+            eprintln!("tetcher: {}", e);
+            EXIT_IO_ERROR
+        }
+    }
+}
+
+/// Restore mode: puts the saved start and end of a drive back
+fn run_restore(file: &str, opts: &Options) -> i32 {
+    let [target] = opts.targets.as_slice() else {
+        eprintln!("tetcher: --restore needs exactly one --target\n\n{}", USAGE);
+        return EXIT_USAGE;
+    };
+
+    let rescue = match rescue::read(Path::new(file)) {
+        Ok(rescue) => rescue,
+        Err(e) => {
+            eprintln!("tetcher: {}", e);
+            return EXIT_USAGE;
+        }
+    };
+
+    let to_unmount = match check_target(target, opts.unmount) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("tetcher: {}", e);
+            return EXIT_USAGE;
+        }
+    };
+
+    if !opts.yes {
+        for m in to_unmount.iter().flat_map(|r| &r.mounts) {
+            println!("{} will be unmounted from {} (--unmount)", m.source, m.mount_point);
+        }
+        print!("Restore the start and end of {} onto {}? Type 'yes' to continue: ", rescue.label(), target);
+        let mut answer = String::new();
+        if io::stdout().flush().and_then(|_| io::stdin().lock().read_line(&mut answer)).is_err()
+            || !answer.trim().eq_ignore_ascii_case("yes")
+        {
+            eprintln!("Aborted, nothing was written.");
+            return EXIT_USAGE;
+        }
+    }
+
+    if let Some(report) = &to_unmount
+        && let Err(e) = unmount_target(report) {
+        eprintln!("tetcher: {}", e);
+        return EXIT_USAGE;
+    }
+
+    match rescue::restore(&rescue, target) {
+        Ok(()) => {
+            println!("Restored {} from {}", target, rescue.path.display());
+            EXIT_OK
+        }
+        Err(e) => {
+            eprintln!("tetcher: restoring {} failed: {}", target, e);
+            EXIT_IO_ERROR
+        }
+    }
+}
+
+/// Verify-only mode: checks drives flashed earlier against an image or a known SHA-256
+fn run_verify_only(opts: Options) -> i32 {
+    if opts.targets.is_empty() {
+        eprintln!("tetcher: at least one --target is required\n\n{}", USAGE);
+        return EXIT_USAGE;
+    }
+    let bmap_choice = bmap_choice(&opts);
+
+    // The reference is either the image, or a SHA-256 together with the size it covers
+    let reference = match (opts.image, opts.sha256, opts.size) {
+        (Some(image), None, None) => match image_source(&image, opts.entry) {
+            Ok(image) => Ok(image),
+            Err(e) => {
+                eprintln!("tetcher: {}", e);
+                return EXIT_USAGE;
+            }
+        },
+        (None, Some(sha256), Some(size)) => {
+            let (Some(sha256), Ok(size)) = (verify::parse_sha256(&sha256), size.trim().parse::<u64>()) else {
+                eprintln!("tetcher: --sha256 must be 64 hex digits and --size a number of bytes");
+                return EXIT_USAGE;
+            };
+            Err((sha256, size))
+        }
+        _ => {
+            eprintln!("tetcher: --verify-only needs either --image, or both --sha256 and --size\n\n{}", USAGE);
+            return EXIT_USAGE;
+        }
+    };
+
+    // A drive flashed with a bmap only holds the mapped ranges, so only those are checked
+    let bmap = match &reference {
+        Ok(image) => match bmap_choice.load(image) {
+            Ok(bmap) => bmap,
+            Err(e) => {
+                eprintln!("tetcher: {}", e);
+                return EXIT_USAGE;
+            }
+        },
+        Err(_) => None,
+    };
+
+    let mut exit = EXIT_OK;
+    for target in &opts.targets {
+        let outcome = match &reference {
+            Ok(image) => verify::verify_flashed(image, target, bmap.as_ref()).map(|r| r.is_ok()),
+            Err((sha256, size)) => verify::verify_sha256(target, sha256, *size).map(|r| r.is_ok()),
+        };
+        let code = match outcome {
+            Ok(true) => EXIT_OK,
+            Ok(false) => EXIT_VERIFY_FAILED,
+            Err(e) => {
+                eprintln!("tetcher: verifying {} failed: {}", target, e);
+                EXIT_IO_ERROR
+            }
+        };
+        exit = exit.max(code);
+    }
+    exit
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn flags_and_values() {
+        let opts = parse(&args("--image=foo.iso -t /dev/sdb --target=/dev/sdc --yes -v --skip-zeros")).unwrap();
+        assert_eq!(opts.image.as_deref(), Some("foo.iso"));
+        assert_eq!(opts.targets, ["/dev/sdb", "/dev/sdc"]);
+        assert!(opts.yes && opts.verify && opts.skip_zeros);
+        assert!(!opts.force && !opts.unmount && !opts.help);
+
+        // Only long flags take "=value"
+        assert!(parse(&args("-t=/dev/sdb")).is_err());
+    }
+
+    #[test]
+    fn values_may_contain_equals_signs() {
+        let opts = parse(&args("--backup=/dev/sdb --output out=1.img")).unwrap();
+        assert_eq!(opts.backup.as_deref(), Some("/dev/sdb"));
+        assert_eq!(opts.output.as_deref(), Some("out=1.img"));
+        let opts = parse(&args("--image=dir/a=b.iso -t /dev/sdb")).unwrap();
+        assert_eq!(opts.image.as_deref(), Some("dir/a=b.iso"));
+    }
+
+    #[test]
+    fn missing_values_and_unknown_flags_are_errors() {
+        assert_eq!(parse(&args("--image")).err().unwrap(), "--image needs a value");
+        assert_eq!(parse(&args("-t /dev/sdb --frobnicate")).err().unwrap(), "unknown argument '--frobnicate'");
+        assert!(parse(&args("/dev/sdb")).is_err());
+    }
+
+    #[test]
+    fn modes_are_not_mixed() {
+        let error = |line: &str| parse(&args(line)).err();
+        assert_eq!(error("--backup /dev/sdb -o out.img --image foo.iso").as_deref(), Some("--image cannot be used with --backup"));
+        assert_eq!(error("--backup /dev/sdb -o out.img -t /dev/sdc").as_deref(), Some("--target cannot be used with --backup"));
+        assert_eq!(error("--backup /dev/sdb -o out.img --verify").as_deref(), Some("--verify cannot be used with --backup"));
+        assert_eq!(error("--list-rescue --yes").as_deref(), Some("--yes cannot be used with --list-rescue"));
+        assert_eq!(error("--list-rescue --backup /dev/sdb").as_deref(), Some("--list-rescue cannot be used with --backup"));
+        assert_eq!(error("--restore x.rescue -t /dev/sdb --verify").as_deref(), Some("--verify cannot be used with --restore"));
+        assert_eq!(error("--verify-only -t /dev/sdb --image foo.iso --yes").as_deref(), Some("--yes cannot be used with --verify-only"));
+        assert_eq!(error("-i foo.iso -t /dev/sdb --compress xz").as_deref(), Some("--compress cannot be used when flashing"));
+
+        // Each mode with its own flags is fine, and --help goes with anything
+        assert!(error("--backup /dev/sdb -o out.img -c zstd --yes").is_none());
+        assert!(error("--list-rescue").is_none());
+        assert!(error("--restore x.rescue -t /dev/sdb -u --yes").is_none());
+        assert!(error("--verify-only -t /dev/sdb --sha256 abc --size 1").is_none());
+        assert!(error("--backup /dev/sdb --image foo.iso --help").is_none());
+    }
+
+    #[test]
+    fn no_bmap_wins() {
+        let choice = |line: &str| bmap_choice(&parse(&args(line)).unwrap());
+        assert!(matches!(choice(""), BmapChoice::Auto));
+        assert!(matches!(choice("--bmap foo.bmap"), BmapChoice::File(p) if p == Path::new("foo.bmap")));
+        assert!(matches!(choice("--bmap foo.bmap --no-bmap"), BmapChoice::Ignore));
+    }
+}
@@ -0,0 +1,427 @@
+//! devio.rs is how flash.rs puts bytes onto a drive, so the progress shown is data that really
+//! reached the drive and not just the page cache
+//!
+//! On Linux, block devices are opened with O_DIRECT: writes skip the page cache and go straight to
+//! the drive. O_DIRECT needs the buffer, the offset and the length all lined up with the drive's
+//! logical sector size (BLKSSZGET), so data that is not already page aligned is copied into an
+//! aligned buffer, and a partial sector (the end of an image that is not a whole number of sectors)
+//! is read, patched and written back
+//!
+//! Built with the `uring` feature, direct writes go through io_uring instead when the kernel has it,
+//! see uring.rs
+//!
+//! Everywhere else, or if the drive refuses O_DIRECT, writes are buffered and the data is flushed
+//! out with fdatasync every `SYNC_EVERY` bytes
+
+use std::alloc::{self, Layout};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Result, Seek, SeekFrom, Write};
+use std::ops::{Deref, DerefMut};
+
+#[cfg(all(target_os = "linux", feature = "uring"))]
+use crate::uring::WriteRing;
+
+/// Buffered writes are synced to the drive after this many bytes
+const SYNC_EVERY: u64 = 32 * 1024 * 1024;
+/// Size of the aligned buffer direct writes go through
+const DIRECT_BUF: usize = 4 * 1024 * 1024;
+/// Buffers are aligned to a page, which covers every logical sector size in use
+const PAGE: usize = 4096;
+
+/// A zeroed buffer whose start is page aligned, as O_DIRECT wants
+pub struct AlignedBuf {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// SAFETY: AlignedBuf owns its memory outright, like a Vec<u8>
+unsafe impl Send for AlignedBuf {}
+// SAFETY: as above, shared access only ever reads
+unsafe impl Sync for AlignedBuf {}
+
+impl AlignedBuf {
+    pub fn new(len: usize) -> AlignedBuf {
+        let layout = Layout::from_size_align(len.max(1), PAGE).expect("buffer size overflows");
+        // SAFETY: the layout has a non-zero size
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        AlignedBuf { ptr, len }
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: `ptr` points at `len` initialised bytes owned by this buffer
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // SAFETY: as above, and `&mut self` makes the access unique
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        let layout = Layout::from_size_align(self.len.max(1), PAGE).expect("buffer size overflows");
+        // SAFETY: allocated in `new` with this same layout
+        unsafe { alloc::dealloc(self.ptr, layout) }
+    }
+}
+
+/// Linux: BLKSSZGET, the logical sector size of a block device
+#[cfg(target_os = "linux")]
+const BLKSSZGET: libc::c_ulong = 0x1268;
+
+/// The drive's logical sector size, 512 if it cannot be found out
+#[cfg(target_os = "linux")]
+fn sector_size(file: &File) -> u64 {
+    use std::os::unix::io::AsRawFd;
+
+    let mut size: libc::c_int = 0;
+    // SAFETY: BLKSSZGET writes a single int into `size`
+    if unsafe { libc::ioctl(file.as_raw_fd(), BLKSSZGET, &mut size) } == 0 && size > 0 {
+        size as u64
+    } else {
+        512
+    }
+}
+
+/// How the writes get to the drive
+enum Mode {
+    /// O_DIRECT, through an aligned buffer, in whole sectors
+    Direct { sector: u64, buf: AlignedBuf },
+    /// Through the page cache, with an fdatasync every `SYNC_EVERY` bytes
+    Buffered { unsynced: u64 },
+    /// O_DIRECT through io_uring with several writes in flight, waited for every `SYNC_EVERY` bytes
+    ///
+    /// `tested` is set once a blocking write has gone through, so a drive that refuses O_DIRECT is
+    /// found out before anything is in flight
+    #[cfg(all(target_os = "linux", feature = "uring"))]
+    Uring { sector: u64, buf: AlignedBuf, ring: Box<WriteRing>, in_flight: u64, tested: bool },
+}
+
+/// The target drive (or file) opened for flashing
+pub struct DeviceWriter {
+    file: File,
+    mode: Mode,
+}
+
+impl DeviceWriter {
+    /// Opens `device` for writing, with O_DIRECT if it is a block device on Linux
+    pub fn open(device: &str) -> Result<DeviceWriter> {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+
+            let is_block = std::fs::metadata(device).is_ok_and(|m| m.file_type().is_block_device());
+            // Read access too, a partial last sector is read back before it is written
+            let direct = is_block
+                .then(|| OpenOptions::new().read(true).write(true).custom_flags(libc::O_DIRECT).open(device).ok())
+                .flatten();
+            if let Some(file) = direct {
+                let sector = sector_size(&file);
+                let buf = AlignedBuf::new(DIRECT_BUF);
+                #[cfg(feature = "uring")]
+                if let Some(ring) = WriteRing::new(&file) {
+                    return Ok(DeviceWriter { file, mode: Mode::Uring { sector, buf, ring: Box::new(ring), in_flight: 0, tested: false } });
+                }
+                return Ok(DeviceWriter { file, mode: Mode::Direct { sector, buf } });
+            }
+        }
+
+        let file = OpenOptions::new().write(true).open(device)?;
+        Ok(DeviceWriter { file, mode: Mode::Buffered { unsynced: 0 } })
+    }
+
+    /// The open file, for the ioctls in sparse.rs
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Writes `data` at `offset`
+    pub fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        match &mut self.mode {
+            Mode::Direct { sector, buf } => match write_direct(&self.file, *sector, buf, offset, data) {
+                // Some drivers take O_DIRECT at open and refuse it on the first write
+                Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+                    self.drop_direct()?;
+                    self.write_at(offset, data)
+                }
+                result => result,
+            },
+            Mode::Buffered { unsynced } => {
+                self.file.seek(SeekFrom::Start(offset))?;
+                self.file.write_all(data)?;
+                *unsynced += data.len() as u64;
+                Ok(())
+            }
+            #[cfg(all(target_os = "linux", feature = "uring"))]
+            Mode::Uring { sector, buf, ring, in_flight, tested } => {
+                if *tested && offset.is_multiple_of(*sector) && (data.len() as u64).is_multiple_of(*sector) {
+                    *in_flight += data.len() as u64;
+                    return ring.write(offset, data);
+                }
+                // The first write, and any that is not whole sectors, waits for the ring and blocks
+                ring.drain()?;
+                *in_flight = 0;
+                match write_direct(&self.file, *sector, buf, offset, data) {
+                    Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+                        self.drop_direct()?;
+                        self.write_at(offset, data)
+                    }
+                    result => {
+                        *tested = true;
+                        result
+                    }
+                }
+            }
+        }
+    }
+
+    /// Makes sure what was written so far is on the drive, if it is time to
+    ///
+    /// Returns true when everything written so far is known to be on the drive, so the progress can move on
+    pub fn checkpoint(&mut self) -> Result<bool> {
+        match &mut self.mode {
+            Mode::Direct { .. } => Ok(true),
+            Mode::Buffered { unsynced } if *unsynced >= SYNC_EVERY => {
+                *unsynced = 0;
+                self.file.sync_data()?;
+                Ok(true)
+            }
+            Mode::Buffered { .. } => Ok(false),
+            #[cfg(all(target_os = "linux", feature = "uring"))]
+            Mode::Uring { ring, in_flight, .. } => {
+                if *in_flight >= SYNC_EVERY {
+                    ring.drain()?;
+                    *in_flight = 0;
+                }
+                Ok(*in_flight == 0)
+            }
+        }
+    }
+
+    /// Flushes everything out, including the drive's own write cache
+    pub fn finish(&mut self) -> Result<()> {
+        match &mut self.mode {
+            Mode::Buffered { unsynced } => *unsynced = 0,
+            #[cfg(all(target_os = "linux", feature = "uring"))]
+            Mode::Uring { ring, in_flight, .. } => {
+                ring.drain()?;
+                *in_flight = 0;
+            }
+            Mode::Direct { .. } => {}
+        }
+        self.file.sync_data()
+    }
+
+    /// Turns O_DIRECT off on the open file and carries on buffered
+    fn drop_direct(&mut self) -> Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::io::AsRawFd;
+
+            let fd = self.file.as_raw_fd();
+            // SAFETY: F_GETFL and F_SETFL only take and return plain flags
+            let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+            if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_DIRECT) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        self.mode = Mode::Buffered { unsynced: 0 };
+        Ok(())
+    }
+}
+
+/// O_DIRECT write of `data` at `offset`, in whole sectors through the aligned buffer
+///
+/// A sector that is only partly covered (at either end) is read back, patched and rewritten
+#[cfg(unix)]
+fn write_direct(file: &File, sector: u64, buf: &mut AlignedBuf, offset: u64, data: &[u8]) -> Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    let mut pos = offset;
+    let mut data = data;
+
+    // Read-modify-write of one sector that `data` only partly covers
+    let patch = |pos: u64, data: &[u8], buf: &mut AlignedBuf| -> Result<usize> {
+        let start = pos - pos % sector;
+        let at = (pos - start) as usize;
+        let n = data.len().min(sector as usize - at);
+        let sector_buf = &mut buf[..sector as usize];
+        sector_buf.fill(0);
+        // Past the end of what is there it reads nothing, which leaves zeros
+        let mut read = 0;
+        while read < sector_buf.len() {
+            match file.read_at(&mut sector_buf[read..], start + read as u64) {
+                Ok(0) => break,
+                Ok(got) => read += got,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        sector_buf[at..at + n].copy_from_slice(&data[..n]);
+        file.write_all_at(sector_buf, start)?;
+        Ok(n)
+    };
+
+    if !pos.is_multiple_of(sector) {
+        let n = patch(pos, data, buf)?;
+        pos += n as u64;
+        data = &data[n..];
+    }
+
+    let whole = data.len() - data.len() % sector as usize;
+    // Blocks from pool.rs are already page aligned and can go out as they are, without the copy
+    if (data.as_ptr() as usize).is_multiple_of(PAGE) {
+        file.write_all_at(&data[..whole], pos)?;
+        pos += whole as u64;
+    } else {
+        for chunk in data[..whole].chunks(buf.len()) {
+            buf[..chunk.len()].copy_from_slice(chunk);
+            file.write_all_at(&buf[..chunk.len()], pos)?;
+            pos += chunk.len() as u64;
+        }
+    }
+    data = &data[whole..];
+
+    if !data.is_empty() {
+        patch(pos, data, buf)?;
+    }
+    Ok(())
+}
+
+/// Direct I/O is only used on Linux, this is never reached elsewhere
+#[cfg(not(unix))]
+fn write_direct(_file: &File, _sector: u64, _buf: &mut AlignedBuf, _offset: u64, _data: &[u8]) -> Result<()> {
+    Err(io::Error::other("direct I/O is not supported here"))
+}
+
+/// Something that takes the image block by block and writes it to a drive
+///
+/// flash.rs has three: everything as is (`PlainWriter`), skipping zeros (sparse.rs) and only
+/// the mapped ranges of a bmap (bmap.rs)
+pub trait BlockWriter: Send {
+    /// Writes the next block of the image
+    fn write_block(&mut self, block: &[u8]) -> Result<()>;
+
+    /// Moves past `len` bytes of the image that are already on the drive, when carrying on an
+    /// earlier flash (see resume.rs). Only comes before anything is written
+    fn skip(&mut self, len: u64);
+
+    /// The drive underneath
+    fn device(&mut self) -> &mut DeviceWriter;
+
+    /// See `DeviceWriter::checkpoint`
+    fn checkpoint(&mut self) -> Result<bool> {
+        self.device().checkpoint()
+    }
+
+    /// Finishes off and flushes everything to the drive, returning how many bytes were skipped
+    fn finish(&mut self) -> Result<u64>;
+}
+
+/// Writes every byte of the image, in order
+pub struct PlainWriter {
+    device: DeviceWriter,
+    pos: u64,
+}
+
+impl PlainWriter {
+    pub fn new(device: DeviceWriter) -> PlainWriter {
+        PlainWriter { device, pos: 0 }
+    }
+}
+
+impl BlockWriter for PlainWriter {
+    fn write_block(&mut self, block: &[u8]) -> Result<()> {
+        self.device.write_at(self.pos, block)?;
+        self.pos += block.len() as u64;
+        Ok(())
+    }
+
+    fn skip(&mut self, len: u64) {
+        self.pos += len;
+    }
+
+    fn device(&mut self) -> &mut DeviceWriter {
+        &mut self.device
+    }
+
+    fn finish(&mut self) -> Result<u64> {
+        self.device.finish()?;
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempPath;
+
+    #[test]
+    fn aligned_buffers_are_page_aligned_and_zeroed() {
+        for len in [0, 1, 4095, 3 * PAGE + 1] {
+            let buf = AlignedBuf::new(len);
+            assert!((buf.as_ptr() as usize).is_multiple_of(PAGE));
+            assert_eq!(buf.len(), len);
+            assert!(buf.iter().all(|&b| b == 0));
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn direct_writes_patch_partial_sectors() {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let mut expected = vec![0xffu8; 4096];
+        let path = TempPath::file("direct", &expected);
+        // Not every filesystem takes O_DIRECT, the sector handling is the same without it
+        let open = |flags| OpenOptions::new().read(true).write(true).custom_flags(flags).open(&path);
+        let file = open(libc::O_DIRECT).or_else(|_| open(0)).unwrap();
+        let mut buf = AlignedBuf::new(PAGE);
+
+        let data: Vec<u8> = (0..2000u32).map(|i| i as u8).collect();
+        let mut write = |offset: usize, data: &[u8]| {
+            write_direct(&file, 512, &mut buf, offset as u64, data).unwrap();
+            if expected.len() < offset + data.len() {
+                expected.resize((offset + data.len()).next_multiple_of(512), 0);
+            }
+            expected[offset..offset + data.len()].copy_from_slice(data);
+        };
+
+        // Inside one sector, across several, and past the end of the file from an unaligned pointer
+        write(100, &data[..10]);
+        write(300, &data[..1500]);
+        write(3900, &data[1..1000]);
+        // Whole sectors from an aligned buffer go out as they are
+        let mut aligned = AlignedBuf::new(1024);
+        aligned.fill(7);
+        write(5120, &aligned);
+
+        drop(file);
+        assert!(std::fs::read(&path).unwrap() == expected);
+    }
+
+    #[test]
+    fn plain_files_are_written_buffered() {
+        let path = TempPath::file("buffered", [0xffu8; 100]);
+        let mut device = DeviceWriter::open(path.as_str()).unwrap();
+        assert!(matches!(device.mode, Mode::Buffered { .. }));
+
+        device.write_at(10, b"hello").unwrap();
+        // Nowhere near `SYNC_EVERY` yet, so nothing is known to be on the drive
+        assert!(!device.checkpoint().unwrap());
+        device.finish().unwrap();
+
+        let written = std::fs::read(&path).unwrap();
+        assert_eq!(&written[8..17], b"\xff\xffhello\xff\xff");
+    }
+}
@@ -1,30 +1,160 @@
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Write, Result, stdout};
+use std::io::{self, IsTerminal, Write, Result, stdout};
 use std::process::exit;
-use std::time::Instant;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
 use crossterm::terminal::disable_raw_mode;
 use crossterm::{
     execute,
     terminal::{self, ClearType, enable_raw_mode},
     cursor,
     style::{Stylize},
-    event::{self, Event, KeyCode},
+    event::{self, Event, KeyCode, KeyEvent, KeyModifiers},
 };
+use crate::bmap::{BmapChoice, MappedWriter, RangeChecker};
+use crate::cancel;
+use crate::devio::{BlockWriter, DeviceWriter, PlainWriter};
+use crate::image::{ImageReader, ImageSource, read_full};
+use crate::parts;
+use crate::pool::{self, BufferPool, PooledBuf};
+use crate::probe;
+use crate::progress::{self, Progress};
+use crate::rescue;
+use crate::resume::{self, Journal, TailCheck};
+use crate::safety;
+use crate::sparse::SparseWriter;
+use crate::targ;
+use crate::ui;
+use crate::units;
 use crate::verify;
 
-pub fn menu(iso: &str, device: &str) -> Result<()> {
+/// Makes sure none of the drives is in use before the final warning
+///
+/// The system disk is dropped from the list outright, for other disks with mounted partitions
+/// the user is shown the mounts and offered to unmount them. Returns the drives that are safe to flash
+fn safety_menu(devices: &[String]) -> Result<Vec<String>> {
+    let mut safe = Vec::new();
+
+    for device in devices {
+        let report = safety::check(device)?;
+        if report.is_clear() {
+            safe.push(device.clone());
+            continue;
+        }
+
+        if let Some(reason) = &report.system_reason {
+            print!("\x1B[H\x1B[2J");
+            print!("{}", format!("{} is the system disk and will not be flashed:", device).red().bold());
+            print!("\r\n  {}", reason);
+            ui::pause()?;
+            continue;
+        }
+
+        // Unmounting would not be enough, the volume group or mapping has to be closed first
+        if !report.holders.is_empty() {
+            print!("\x1B[H\x1B[2J");
+            print!("{}", format!("{} is in use and will not be flashed:", device).red().bold());
+            print!("\r\n  {}", report.to_error());
+            ui::pause()?;
+            continue;
+        }
+
+        let mut title = format!("{}\n", format!("{} is in use, these partitions are mounted:", device).yellow().bold());
+        for m in &report.mounts {
+            title.push_str(&format!("  {} on {} ({})\n", m.source, m.mount_point, m.fs_type));
+        }
+
+        if ui::choose(&title, &["Unmount them and continue", "Skip this drive"])? == Some(0) {
+            match safety::unmount(&report).and_then(|_| safety::ensure_safe(device)) {
+                Ok(()) => safe.push(device.clone()),
+                Err(e) => {
+                    print!("\x1B[H\x1B[2J");
+                    print!("{}", format!("Skipping {}: {}", device, e).red());
+                    ui::pause()?;
+                }
+            }
+        }
+    }
+
+    Ok(safe)
+}
+
+/// What the image's partition table will create, and what each drive has on it now
+///
+/// Each drive also gets a one line summary of the filesystems that are about to be destroyed
+///
+/// Read errors are shown in place of a table rather than stopping anything, the flash reports those properly
+pub(crate) fn partition_preview(iso: &ImageSource, devices: &[String]) -> Vec<String> {
+    let describe = |table: Result<Option<parts::PartitionTable>>| match table {
+        Ok(Some(table)) => table.describe(),
+        Ok(None) => vec!["no partition table".to_string()],
+        Err(e) => vec![format!("could not be read: {}", e)],
+    };
+
+    let mut lines = vec!["The image will create:".bold().to_string()];
+    lines.extend(describe(parts::read_image(iso)).into_iter().map(|l| format!("  {}", l)));
+
+    for device in devices {
+        lines.push(String::new());
+        lines.push(format!("{} currently has:", device).bold().to_string());
+        lines.extend(describe(parts::read_device(device)).into_iter().map(|l| format!("  {}", l)));
+
+        // In words, so a wrong stick is noticed: "2 partitions: FAT32 'BACKUPS' 28.00 GiB, ext4 'rootfs' ..."
+        let destroy = match probe::probe_device(device) {
+            Ok(areas) => probe::summary(&areas),
+            Err(e) => format!("(could not look: {})", e),
+        };
+        lines.push(format!("  You are about to destroy: {}", destroy).red().bold().to_string());
+    }
+    lines
+}
+
+/// Final warning before the selected drives get overwritten
+pub fn menu(iso: &ImageSource, drives: &[targ::DriveInfo]) -> Result<()> {
     enable_raw_mode()?;
     let mut stdout = stdout();
 
-    let warn = vec!["Yes", "No"];
+    let paths: Vec<String> = drives.iter().map(|d| d.path.clone()).collect();
+    let devices = safety_menu(&paths)?;
+    if devices.is_empty() {
+        terminal::disable_raw_mode()?;
+        return Ok(());
+    }
+    let drives: Vec<targ::DriveInfo> = drives.iter().filter(|d| devices.contains(&d.path)).cloned().collect();
+
+    let warn = ["Yes", "Yes, and skip the empty (all-zero) parts of the image", "No"];
     let mut selected = 0;
+    let mut preview = partition_preview(iso, &devices);
+
+    // A .bmap next to the image means only part of it gets written
+    let mut options = FlashOptions::default();
+    match options.bmap.load(iso) {
+        Ok(Some(bmap)) => {
+            preview.push(String::new());
+            preview.push(bmap.describe().green().to_string());
+        }
+        Ok(None) => {}
+        Err(e) => {
+            preview.push(String::new());
+            preview.push(format!("Ignoring the .bmap next to the image: {}", e).yellow().to_string());
+            options.bmap = BmapChoice::Ignore;
+        }
+    }
 
     loop {
         execute!(stdout, cursor::MoveTo(0, 0), terminal::Clear(ClearType::FromCursorDown))?;
-        println!("{}", "Do you wish to flash the ISO? (THIS WILL OVERWRITE *ALL* DISK CONTENTS)".blue().bold());
+        print!("{}", "Do you wish to flash the ISO? (THIS WILL OVERWRITE *ALL* DISK CONTENTS)".blue().bold());
 
+        for (i, line) in preview.iter().enumerate() {
+            execute!(stdout, cursor::MoveTo(0, (i + 2) as u16))?;
+            print!("{}", line);
+        }
+
+        let options_row = preview.len() + 3;
         for (i, item) in warn.iter().enumerate() {
-            execute!(stdout, cursor::MoveTo(0, (i + 1) as u16))?;
+            execute!(stdout, cursor::MoveTo(0, (i + options_row) as u16))?;
             if i == selected {
                 print!("{}", item.on_white().black());
             } else {
@@ -36,15 +166,31 @@ pub fn menu(iso: &str, device: &str) -> Result<()> {
 
         if let Event::Key(key) = event::read()? {
             match key.code {
-                KeyCode::Up => if selected > 0 { selected -= 1; },
-                KeyCode::Down => if selected < warn.len() - 1 { selected += 1; },
+                KeyCode::Up => selected = selected.saturating_sub(1),
+                KeyCode::Down if selected < warn.len() - 1 => selected += 1,
                 KeyCode::Enter => {
                     match selected {
-                        0 => {
-                            flash_iso(iso, device)?;
-                            verify_menu(iso, device)?;
+                        0 | 1 => {
+                            println!("\x1B[H\x1B[2J");
+                            // The warning may have been up a while, a stick swapped in the meantime
+                            // could have the same path as the one that was picked
+                            if let Err(e) = targ::ensure_unchanged(&drives) {
+                                print!("{}", e.to_string().red().bold());
+                                ui::pause()?;
+                                break;
+                            }
+                            options.skip_zeros = selected == 1;
+                            options.resume = resume_menu(iso, &devices, &options)?;
+                            let devices = rescue_menu(&devices, &options)?;
+                            if devices.is_empty() {
+                                break;
+                            }
+                            options.skip_rescue = true;
+                            let results = flash_iso(iso, &devices, &options)?;
+                            cancel::exit_if_signalled();
+                            verify_menu(iso, &options, results)?;
                         }
-                        1 => break,
+                        2 => break,
                         _ => {}
                     }
                 }
@@ -58,12 +204,69 @@ pub fn menu(iso: &str, device: &str) -> Result<()> {
     Ok(())
 }
 
-fn verify_menu(iso: &str, device: &str) -> Result<()> {
+/// What a drive's progress line ends with
+fn writer_state(finished: bool) -> &'static str {
+    match (finished, cancel::requested()) {
+        (true, false) => "  done",
+        (true, true) => "  stopped",
+        (false, true) => "  stopping",
+        (false, false) => "",
+    }
+}
+
+/// Saves the start and end of each drive (see rescue.rs) before the flash, so it can be undone
+///
+/// A drive they cannot be saved from is only flashed if the user says so. Returns the drives to flash
+fn rescue_menu(devices: &[String], options: &FlashOptions) -> Result<Vec<String>> {
+    let mut flash = Vec::new();
+    for device in devices {
+        // Carrying on an earlier flash, the start of the drive already holds the image
+        if options.resume.iter().any(|j| j.device == *device) {
+            flash.push(device.clone());
+            continue;
+        }
+        let Err(e) = rescue::save_before_flash(device) else {
+            flash.push(device.clone());
+            continue;
+        };
+
+        let title = format!("{}\n{}\n", format!("Could not save the start and end of {}", device).yellow().bold(), e);
+        let choice = ui::choose(&title, &["Skip this drive", "Flash it anyway, it cannot be undone afterwards"])?;
+        print!("\x1B[H\x1B[2J");
+        if choice == Some(1) {
+            flash.push(device.clone());
+        }
+    }
+    Ok(flash)
+}
+
+/// For each drive with an earlier flash of this image that was stopped part way, asks whether to
+/// carry on from there or start again. Returns the journals to carry on from
+fn resume_menu(iso: &ImageSource, devices: &[String], options: &FlashOptions) -> Result<Vec<Journal>> {
+    let mut resume = Vec::new();
+    for device in devices {
+        let Some(journal) = Journal::find(iso, device, options) else {
+            continue;
+        };
+
+        let title = format!("{}\n", format!("An earlier flash of {} to {} {}", iso, device, journal.describe()).yellow().bold());
+        let choice = ui::choose(&title, &["Carry on from there (the last 64 MiB written are checked first)", "Start again from the beginning"])?;
+        if choice == Some(0) {
+            resume.push(journal);
+        }
+    }
+    println!("\x1B[H\x1B[2J");
+    Ok(resume)
+}
+
+/// Offers to verify every drive that was flashed without errors, then prints how each drive got on
+fn verify_menu(iso: &ImageSource, options: &FlashOptions, mut results: Vec<DriveResult>) -> Result<()> {
     let mut stdout = stdout();
-    let verify_opts = vec!["Yes", "No"];
+    let verify_opts = ["Yes", "No"];
     let mut verselected = 0;
 
-    loop {
+    // Nothing to verify if every drive failed or was cancelled, straight to the results
+    let wanted = results.iter().any(|r| r.error.is_none()) && loop {
         execute!(stdout, cursor::MoveTo(0, 0), terminal::Clear(ClearType::FromCursorDown))?;
         println!("{}", "Do you wish to verify the ISO?".blue().bold());
 
@@ -80,56 +283,512 @@ fn verify_menu(iso: &str, device: &str) -> Result<()> {
 
         if let Event::Key(key) = event::read()? {
             match key.code {
-                KeyCode::Up => if verselected > 0 { verselected -= 1; },
-                KeyCode::Down => if verselected < verify_opts.len() - 1 { verselected += 1; },
-                KeyCode::Enter => {
-                    if verselected == 0 {
-                        println!("\x1B[H\x1B[2J");
-                        println!("Verifying...");
-                        let is_verified = verify::verify(iso, device)?;
-                        if is_verified {
-                            println!("Verification succeeded!");
-                        } else {
-                            println!("Verification failed!");
+                KeyCode::Up => verselected = verselected.saturating_sub(1),
+                KeyCode::Down if verselected < verify_opts.len() - 1 => verselected += 1,
+                KeyCode::Enter => break verselected == 0,
+                _ => {}
+            }
+        }
+    };
+
+    println!("\x1B[H\x1B[2J");
+    if wanted {
+        // It was loaded fine for the flash, so it loads fine again
+        let bmap = options.bmap.load(iso).ok().flatten();
+        for result in results.iter_mut().filter(|r| r.error.is_none()) {
+            println!("Verifying {}...", result.device);
+            // A drive that cannot be read back counts as failed, the others still get checked
+            result.verified = Some(match verify::verify_flashed(iso, &result.device, bmap.as_ref()) {
+                Ok(report) => report.is_ok(),
+                Err(e) => {
+                    println!("{}", format!("Could not read {}: {}", result.device, e).red());
+                    false
+                }
+            });
+        }
+    }
+    disable_raw_mode()?;
+    println!("\nResults:");
+    for result in &results {
+        println!("  {}", result.summary());
+    }
+    execute!(stdout, cursor::Show)?;
+    exit(0);
+}
+
+/// How one drive got on, so one bad stick does not hide how the others went
+#[derive(Debug)]
+pub struct DriveResult {
+    pub device: String,
+    /// Bytes that were handed to the device
+    pub written: u64,
+    /// Bytes that were skipped rather than written, with `skip_zeros` or a bmap
+    pub skipped: u64,
+    /// Set if writing to this drive failed, the other drives carry on regardless
+    pub error: Option<io::Error>,
+    /// Set once the drive has been verified
+    pub verified: Option<bool>,
+}
+
+impl DriveResult {
+    /// One line summary, e.g. "/dev/sdb — flashed, verified"
+    pub fn summary(&self) -> String {
+        let flashed = match &self.error {
+            Some(e) if e.kind() == io::ErrorKind::Interrupted => format!(
+                "INCOMPLETE, cancelled after {} bytes ({}) reached the drive, flash it again before using it",
+                self.written,
+                units::human_size(self.written)
+            )
+            .red()
+            .bold()
+            .to_string(),
+            Some(e) => format!("FAILED after {} bytes: {}", self.written, e).red().bold().to_string(),
+            None if self.skipped > 0 => format!(
+                "flashed {} bytes ({} of empty space skipped)",
+                self.written,
+                units::human_size(self.skipped)
+            )
+            .green()
+            .to_string(),
+            None => format!("flashed {} bytes", self.written).green().to_string(),
+        };
+        let verified = match self.verified {
+            Some(true) => ", verified".green().to_string(),
+            Some(false) => ", VERIFICATION FAILED".red().bold().to_string(),
+            None => String::new(),
+        };
+        format!("{} — {}{}", self.device, flashed, verified)
+    }
+}
+
+/// Esc, or Ctrl-C, which raw mode hands over as a key rather than a signal
+fn is_stop_key(key: &KeyEvent) -> bool {
+    key.code == KeyCode::Esc || (key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL))
+}
+
+/// Running off the end of a drive only gives "No space left on device", so say what actually happened
+///
+/// This catches images whose size was not known before writing, e.g. gzip compressed ones
+fn explain_write_error(e: io::Error, device: &str, total: Option<u64>) -> io::Error {
+    if !matches!(e.kind(), io::ErrorKind::StorageFull | io::ErrorKind::WriteZero) {
+        return e;
+    }
+    let size = total.map(|t| format!(" ({})", units::human_size(t))).unwrap_or_default();
+    io::Error::new(
+        io::ErrorKind::StorageFull,
+        format!("{} is full, the image{} is bigger than the drive", device, size),
+    )
+}
+
+/// How to flash, beyond which image goes where
+#[derive(Debug, Clone, Default)]
+pub struct FlashOptions {
+    /// Seek over the blocks of the image that are all zeros instead of writing them, see sparse.rs
+    ///
+    /// The drive still ends up identical to the image, so verifying works the same
+    pub skip_zeros: bool,
+    /// Which .bmap to use, if any, see bmap.rs. With a bmap, `skip_zeros` is not needed and ignored
+    pub bmap: BmapChoice,
+    /// Earlier flashes to carry on from rather than starting again, see resume.rs
+    pub resume: Vec<Journal>,
+    /// Do not save the start and end of each drive first (see rescue.rs), either because the user
+    /// said so or because it has been done already
+    pub skip_rescue: bool,
+}
+
+/// Writes one image to every device at once, printing a progress line per device
+///
+/// The image is read (and decompressed) only once, by a reader thread that hands each 4 MB block
+/// to one writer thread per device. A device that fails is dropped and the rest carry on,
+/// so the returned results say how each one got on
+///
+/// This does no prompting of its own, so it is shared by the menus and the command-line mode
+///
+/// An `Err` is only returned if the image itself could not be read
+pub fn flash_iso(iso_path: &ImageSource, devices: &[String], options: &FlashOptions) -> Result<Vec<DriveResult>> {
+    /// How often the progress lines are redrawn
+    const REDRAW: Duration = Duration::from_millis(200);
+    /// How often the resume journals are brought up to date
+    const JOURNAL: Duration = Duration::from_secs(1);
+
+    let mut iso_file = ImageReader::open(iso_path)?;
+    let total = iso_file.uncompressed_size;
+    let start = Instant::now();
+
+    let bmap = options.bmap.load(iso_path)?;
+    if let Some(bmap) = &bmap
+        && total.is_some_and(|total| total != bmap.image_size) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is for a {} byte image, but the image is {} bytes", bmap.path.display(), bmap.image_size, total.unwrap_or(0)),
+        ));
+    }
+
+    println!("Flashing {} ({}) → {}\r", iso_path, iso_file.describe(), devices.join(", "));
+    if let Some(bmap) = &bmap {
+        println!("{}\r", bmap.describe());
+    }
+    let mode = resume::mode(bmap.as_ref(), options.skip_zeros);
+
+    // Open every device up front, one that cannot be opened (or is in use) is simply reported as failed
+    let mut results: Vec<DriveResult> = Vec::new();
+    let mut targets = Vec::new();
+    for device in devices {
+        let mut result = DriveResult { device: device.clone(), written: 0, skipped: 0, error: None, verified: None };
+        let checked = safety::ensure_safe(device).and_then(|_| targ::check_fits(device, total));
+
+        // Carrying on from an earlier flash, or a fresh journal in case this one gets interrupted too
+        let resuming = options.resume.iter().find(|j| j.device == *device).cloned();
+        let resume_from = resuming.as_ref().map_or(0, |j| j.synced);
+        let journal = resuming.or_else(|| Journal::new(iso_path, device, mode.clone(), total).ok());
+        if resume_from > 0 {
+            println!(
+                "Carrying on with {} from {}, the last {} written are checked first\r",
+                device,
+                units::human_size(resume_from),
+                units::human_size(resume::TAIL.min(resume_from))
+            );
+        }
+
+        // Keep the start and end of the drive, so a flash to the wrong drive can be undone. Not when
+        // carrying on, the drive already holds the start of the image
+        let checked = match checked {
+            Ok(()) if resume_from == 0 && !options.skip_rescue => rescue::save_before_flash(device),
+            checked => checked,
+        };
+
+        let opened = checked.and_then(|_| DeviceWriter::open(device));
+        match opened {
+            Ok(target) => targets.push((results.len(), target, journal, resume_from)),
+            Err(e) => result.error = Some(e),
+        }
+        results.push(result);
+    }
+
+    // Every block comes out of one pool, shared by all the writers, so the reader waits once it is
+    // `pool::BLOCKS` ahead of the slowest one
+    let pool = BufferPool::new();
+
+    let _flashing = cancel::Flashing::start();
+    thread::scope(|scope| -> Result<()> {
+        // Set once the whole image has been read and sent, a writer whose blocks stop coming without
+        // it was stopped part way (Esc, Ctrl-C, or the image could not be read)
+        let read_all = Arc::new(AtomicBool::new(false));
+        let mut senders = Vec::new();
+        let mut writers = Vec::new();
+        let mut journals = Vec::new();
+
+        for (index, target, journal, resume_from) in targets {
+            let (tx, rx) = mpsc::sync_channel::<Arc<PooledBuf>>(pool::BLOCKS);
+            let written = Arc::new(AtomicU64::new(0));
+            let progress = Arc::clone(&written);
+
+            let device = results[index].device.clone();
+            let mut writer: Box<dyn BlockWriter + '_> = match &bmap {
+                Some(bmap) => Box::new(MappedWriter::new(target, bmap)),
+                // Discards the whole drive, so the skipped parts can read back as zeros
+                None if options.skip_zeros && resume_from == 0 => Box::new(SparseWriter::new(target, &device)),
+                None if options.skip_zeros => Box::new(SparseWriter::resume(target, &device)),
+                None => Box::new(PlainWriter::new(target)),
+            };
+
+            // What is already on the drive shows on the bar, but does not count towards the speed
+            let mut line = Progress::new(total).resumed_from(resume_from);
+            if total.is_none() {
+                line = line.estimated_by(iso_file.progress_meter());
+            }
+
+            // What was written before is read back from the drive, to check it is still there
+            let tail = (resume_from > 0).then(|| TailCheck::open(&device, bmap.as_ref()));
+            let read_all = Arc::clone(&read_all);
+
+            journals.push((index, journal));
+            writers.push((index, written, line, scope.spawn(move || -> Result<u64> {
+                let explain = |e| explain_write_error(e, &device, total);
+                // Progress only moves on once the data is known to be on the drive, not in the page cache
+                let mut pending = 0;
+                let mut stopped = false;
+                let mut pos = 0;
+                let mut tail = tail.transpose()?;
+                for block in rx {
+                    // Stop between blocks, what was written so far still gets synced below
+                    if cancel::requested() {
+                        stopped = true;
+                        break;
+                    }
+
+                    // Already on the drive from the flash being carried on, the last of it is read
+                    // back and checked rather than written again
+                    let len = block.len() as u64;
+                    if pos + len <= resume_from {
+                        if pos + len > resume_from.saturating_sub(resume::TAIL)
+                            && let Some(tail) = &mut tail
+                            && let Err(e) = tail.check(pos, &block) {
+                            // Nothing of the earlier flash can be trusted, so nothing is left to carry on from
+                            if resume::is_mismatch(&e) {
+                                progress.store(0, Ordering::Relaxed);
+                            }
+                            return Err(e);
                         }
+                        writer.skip(len);
+                        pos += len;
+                        progress.fetch_add(len, Ordering::Relaxed);
+                        continue;
+                    }
+
+                    writer.write_block(&block).map_err(explain)?;
+                    pos += len;
+                    pending += len;
+                    if writer.checkpoint().map_err(explain)? {
+                        progress.fetch_add(pending, Ordering::Relaxed);
+                        pending = 0;
                     }
-                    disable_raw_mode()?;
-                    execute!(stdout, cursor::Show)?;
-                    exit(0);
                 }
-                _ => {}
+                if !read_all.load(Ordering::Acquire) {
+                    stopped = true;
+                }
+                let skipped = writer.finish().map_err(explain)?;
+                progress.fetch_add(pending, Ordering::Relaxed);
+                if stopped {
+                    return Err(cancel::incomplete());
+                }
+                Ok(skipped)
+            })));
+            senders.push(tx);
+        }
+
+        // With a bmap, the image is checked against its range checksums as it is read. A range is
+        // only checked once its last block has been read, the blocks before it may already be on the
+        // drive by then, so a mismatch fails the flash rather than keeping the drive untouched
+        let mut checker = bmap.as_ref().map(RangeChecker::new);
+
+        // Returns whether the whole image was read, i.e. it was not cancelled part way
+        let reader = scope.spawn(move || -> Result<bool> {
+            loop {
+                if cancel::requested() {
+                    return Ok(false);
+                }
+                let mut block = pool.get();
+                let bytes_read = read_full(&mut iso_file, &mut block)?;
+                if bytes_read == 0 || senders.is_empty() {
+                    if let Some(checker) = &checker
+                        && !senders.is_empty() {
+                        checker.finish()?;
+                    }
+                    // Before the senders are dropped, so the writers see it once their blocks run out
+                    read_all.store(true, Ordering::Release);
+                    return Ok(true);
+                }
+                block.truncate(bytes_read);
+                if let Some(checker) = &mut checker {
+                    checker.feed(&block)?;
+                }
+                let block = Arc::new(block);
+
+                // A writer that has hung up has failed, stop feeding it
+                senders.retain(|tx| tx.send(Arc::clone(&block)).is_ok());
             }
+        });
+
+        // Redraw the progress lines until every writer is done. In the menus the terminal is in raw
+        // mode, so Esc and Ctrl-C come in as keys and are looked for in between
+        let mut stdout = stdout();
+        let keys = terminal::is_raw_mode_enabled().unwrap_or(false);
+        if keys {
+            println!("{}\r", "Press Esc to stop".dark_grey());
         }
+        let mut first = true;
+        let mut journalled = Instant::now();
+        let tty = stdout.is_terminal();
+        let mut logged = Instant::now();
+        loop {
+            let done = reader.is_finished() && writers.iter().all(|(_, _, _, w)| w.is_finished());
+
+            // Only what is synced counts as written, so the journal never claims more than is on the drive.
+            // Failing to save it only means less can be carried on from, so it is not worth stopping for
+            if journalled.elapsed() >= JOURNAL {
+                journalled = Instant::now();
+                for ((_, written, _, _), (_, journal)) in writers.iter().zip(&mut journals) {
+                    let written = written.load(Ordering::Relaxed);
+                    if let Some(journal) = journal
+                        && written > journal.synced {
+                        let _ = journal.save(written);
+                    }
+                }
+            }
+
+            // Not a terminal (e.g. a CI log): no redrawing in place, just a plain line now and then
+            if !tty {
+                if done || logged.elapsed() >= progress::LOG_EVERY {
+                    logged = Instant::now();
+                    for (index, written, line, writer) in &mut writers {
+                        let state = writer_state(writer.is_finished());
+                        println!("  {}  {}{}", results[*index].device, line.line(written.load(Ordering::Relaxed), 100), state);
+                    }
+                }
+                if done {
+                    break;
+                }
+                thread::sleep(REDRAW);
+                continue;
+            }
+
+            if !first {
+                print!("\x1B[{}A", writers.len());
+            }
+            first = false;
+            let width = progress::terminal_width();
+            for (index, written, line, writer) in &mut writers {
+                let device = &results[*index].device;
+                let state = writer_state(writer.is_finished());
+                // Less one column, a line that fills the terminal exactly would wrap and break the redraw
+                let room = width.saturating_sub(device.chars().count() + state.len() + 5);
+                let line = line.line(written.load(Ordering::Relaxed), room);
+                print!("\r  {}  {}{}\x1B[K\r\n", device, line, state);
+            }
+            stdout.flush()?;
+
+            if done {
+                break;
+            }
+            if !keys {
+                thread::sleep(REDRAW);
+            } else if event::poll(REDRAW)?
+                && let Event::Key(key) = event::read()?
+                && is_stop_key(&key) {
+                cancel::request();
+            }
+        }
+
+        for (index, written, _, writer) in writers {
+            let result = &mut results[index];
+            result.written = written.load(Ordering::Relaxed);
+            result.error = match writer.join() {
+                Ok(Ok(skipped)) => {
+                    result.skipped = skipped;
+                    None
+                }
+                Ok(Err(e)) => Some(e),
+                Err(_) => Some(io::Error::other("writer thread panicked")),
+            };
+        }
+
+        let read_all = match reader.join() {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::other("reader thread panicked")),
+        };
+
+        // Stopped before the whole image was read, so none of the drives got all of it
+        if !matches!(read_all, Ok(true)) {
+            for result in results.iter_mut().filter(|r| r.error.is_none()) {
+                result.error = Some(cancel::incomplete());
+            }
+        }
+
+        // Only now is it known which drives got the whole image. Keep the journal of a flash that got
+        // part way, so it can be carried on from next time. It only goes once the drive is done, or
+        // is known to no longer hold the earlier flash, not when the drive just could not be read back
+        for (index, journal) in journals {
+            let result = &results[index];
+            let Some(mut journal) = journal else {
+                continue;
+            };
+            match &result.error {
+                Some(e) if !resume::is_mismatch(e) && result.written > journal.synced => {
+                    let _ = journal.save(result.written);
+                }
+                Some(e) if !resume::is_mismatch(e) && journal.synced > 0 => {}
+                _ => journal.remove(),
+            }
+        }
+        read_all.map(drop)
+    })?;
+
+    if cancel::requested() {
+        println!("{}\r", format!("Stopped after {:.2?}", start.elapsed()).yellow().bold());
+    } else {
+        println!("Finished in {:.2?}\r", start.elapsed());
+    }
+    for result in &results {
+        println!("  {}\r", result.summary());
     }
+    Ok(results)
 }
 
-fn flash_iso(iso_path: &str, device_path: &str) -> Result<()> {
-    println!("\x1B[H\x1B[2J");
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
 
-    let bs: usize = 4 * 1024 * 1024; // 4 MB buffer
-    let mut iso_file = File::open(iso_path)?;
-    let mut device_file = OpenOptions::new().write(true).open(device_path)?;
+    use crate::testutil::{DataDir, TempPath};
 
-    let iso_size = iso_file.metadata()?.len();
-    let mut written: u64 = 0;
-    let mut buffer = vec![0u8; bs];
-    let start = Instant::now();
+    /// Flashes `image` to temp file targets (each `size` bytes of 0xff, none for a size of 0) and
+    /// returns the results with what each target held afterwards
+    fn flash_files(image: &[u8], sizes: &[usize], options: FlashOptions) -> Vec<(DriveResult, Vec<u8>)> {
+        let image_path = TempPath::file("flash.img", image);
+        let targets: Vec<TempPath> = sizes
+            .iter()
+            .map(|&size| match size {
+                0 => TempPath::new("flash.target"),
+                size => TempPath::file("flash.target", vec![0xff; size]),
+            })
+            .collect();
+        let devices: Vec<String> = targets.iter().map(|t| t.as_str().to_string()).collect();
 
-    println!("Flashing {} → {}", iso_path, device_path);
+        let options = FlashOptions { skip_rescue: true, bmap: BmapChoice::Ignore, ..options };
+        let results = flash_iso(&ImageSource::file(&*image_path), &devices, &options).unwrap();
+        results.into_iter().zip(&targets).map(|(result, target)| (result, fs::read(target).unwrap_or_default())).collect()
+    }
 
-    loop {
-        let bytes_read = iso_file.read(&mut buffer)?;
-        if bytes_read == 0 { break; }
+    fn image(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 253) as u8).collect()
+    }
+
+    #[test]
+    fn one_image_to_several_drives() {
+        let image = image((3 << 20) + 123);
+        let results = flash_files(&image, &[4 << 20, 0, 4 << 20], FlashOptions::default());
 
-        device_file.write_all(&buffer[..bytes_read])?;
-        written += bytes_read as u64;
+        for i in [0, 2] {
+            let (result, data) = &results[i];
+            assert!(result.error.is_none(), "{:?}", result.error);
+            assert_eq!(result.written, image.len() as u64);
+            assert_eq!(&data[..image.len()], &image[..]);
+            // Past the image the drive is left alone
+            assert!(data[image.len()..].iter().all(|&b| b == 0xff));
+        }
 
-        let percent = (written as f64 / iso_size as f64) * 100.0;
-        print!("\rProgress: {:>6.2}%", percent);
-        stdout().flush()?;
+        // Not there, the others carry on without it
+        let (result, data) = &results[1];
+        assert_eq!(result.error.as_ref().map(|e| e.kind()), Some(io::ErrorKind::NotFound));
+        assert_eq!(result.written, 0);
+        assert!(data.is_empty());
     }
 
-    device_file.flush()?;
-    println!("\nCompleted in {:.2?}", start.elapsed());
-    return Ok(())
+    #[test]
+    fn an_image_that_ends_early_is_not_a_finished_flash() {
+        use std::io::Write;
+
+        // The journal is what this is about, so there has to be somewhere to keep it
+        let _data_dir = DataDir::new();
+
+        // A gzip cut off part way through, the reader fails after handing out the first blocks
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        gz.write_all(&image(12 << 20)).unwrap();
+        let gz = gz.finish().unwrap();
+        let image_path = TempPath::file("cut.img.gz", &gz[..gz.len() / 2]);
+        let target = TempPath::file("cut.target", vec![0xff; 16 << 20]);
+        let source = ImageSource::file(&*image_path);
+        let devices = [target.as_str().to_string()];
+
+        let options = FlashOptions { skip_rescue: true, bmap: BmapChoice::Ignore, ..FlashOptions::default() };
+        assert!(flash_iso(&source, &devices, &options).is_err());
+
+        // Any journal left behind may only claim what really is on the target
+        if let Some(journal) = Journal::find(&source, &devices[0], &options) {
+            let data = fs::read(&target).unwrap();
+            assert!(data[..journal.synced as usize] == image(journal.synced as usize)[..]);
+            journal.remove();
+        }
+    }
 }
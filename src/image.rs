@@ -0,0 +1,524 @@
+//! image.rs opens the file picked in the browser and hands back a plain stream of disk-image bytes
+//!
+//! Compressed images (.gz, .xz, .zst, .bz2) are recognised by their magic bytes rather than the
+//! file extension, and are decompressed on the fly while flashing and verifying
+//!
+//! The file can also be an entry inside a .zip or .tar archive, see archive.rs
+//!
+//! The bytes read from the file itself are counted, so progress can be shown against the size of
+//! the file on disk even when the decompressed size is not known up front
+
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, Cursor, ErrorKind, Read, Result, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use bzip2::read::MultiBzDecoder;
+use flate2::read::MultiGzDecoder;
+use xz2::read::XzDecoder;
+
+use crate::archive;
+
+/// What the user picked: a file on disk, or one entry inside a .zip or .tar archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageSource {
+    pub path: PathBuf,
+    /// Name of the entry inside the archive, `None` for a plain image file
+    pub entry: Option<String>,
+}
+
+impl ImageSource {
+    /// A plain image file, possibly compressed
+    pub fn file(path: impl Into<PathBuf>) -> ImageSource {
+        ImageSource { path: path.into(), entry: None }
+    }
+
+    /// One entry inside an archive
+    pub fn archive_entry(path: impl Into<PathBuf>, entry: impl Into<String>) -> ImageSource {
+        ImageSource { path: path.into(), entry: Some(entry.into()) }
+    }
+}
+
+impl fmt::Display for ImageSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.entry {
+            Some(entry) => write!(f, "{} » {}", self.path.display(), entry),
+            None => write!(f, "{}", self.path.display()),
+        }
+    }
+}
+
+/// The compression formats tEtcher can stream through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Xz,
+    Zstd,
+    Bzip2,
+}
+
+impl Compression {
+    /// Works out the format from the first few bytes of the file
+    fn detect(magic: &[u8]) -> Compression {
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            Compression::Gzip
+        } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Compression::Xz
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Compression::Zstd
+        } else if magic.starts_with(b"BZh") {
+            Compression::Bzip2
+        } else {
+            Compression::None
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Compression::None => "raw",
+            Compression::Gzip => "gzip",
+            Compression::Xz => "xz",
+            Compression::Zstd => "zstd",
+            Compression::Bzip2 => "bzip2",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Wraps the file on disk and keeps a running total of how many bytes have been read from it
+pub(crate) struct CountingReader<R> {
+    inner: R,
+    count: Arc<AtomicU64>,
+}
+
+impl<R> CountingReader<R> {
+    pub(crate) fn new(inner: R, count: Arc<AtomicU64>) -> CountingReader<R> {
+        CountingReader { inner, count }
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+/// Peeks at the first bytes of a stream to find out how it is compressed
+///
+/// The peeked bytes are put back in front, so the returned stream still starts at the beginning
+pub(crate) fn detect_compression(mut stream: Box<dyn Read + Send>) -> Result<(Compression, Box<dyn Read + Send>)> {
+    let mut magic = vec![0u8; 6];
+    let n = read_full(&mut stream, &mut magic)?;
+    magic.truncate(n);
+    let compression = Compression::detect(&magic);
+    Ok((compression, Box::new(Cursor::new(magic).chain(stream))))
+}
+
+/// Wraps a stream in the matching decompressor
+pub(crate) fn decompress(compression: Compression, stream: Box<dyn Read + Send>) -> Result<Box<dyn Read + Send>> {
+    Ok(match compression {
+        Compression::None => stream,
+        Compression::Gzip => Box::new(MultiGzDecoder::new(stream)),
+        Compression::Xz => Box::new(XzDecoder::new_multi_decoder(stream)),
+        Compression::Zstd => Box::new(zstd::Decoder::new(stream)?),
+        Compression::Bzip2 => Box::new(MultiBzDecoder::new(stream)),
+    })
+}
+
+/// A readable disk image, decompressed if needed
+pub struct ImageReader {
+    reader: Box<dyn Read + Send>,
+    /// How the image itself is compressed (inside the archive, if there is one)
+    pub compression: Compression,
+    /// The archive the image comes from, e.g. "zip, deflate", if any
+    pub container: Option<String>,
+    /// How many bytes of the file on disk will be read in total, i.e. the total for `consumed`
+    pub file_size: u64,
+    /// Size of the disk image once decompressed, if the format records it
+    pub uncompressed_size: Option<u64>,
+    consumed: Arc<AtomicU64>,
+}
+
+impl ImageReader {
+    /// Opens an image (or archive entry) and sets up a decompressor for it if one is needed
+    pub fn open(source: &ImageSource) -> Result<ImageReader> {
+        match &source.entry {
+            Some(entry) => ImageReader::open_entry(&source.path, entry),
+            None => ImageReader::open_file(&source.path),
+        }
+    }
+
+    fn open_file(path: &Path) -> Result<ImageReader> {
+        let mut file = File::open(path)?;
+        let file_size = file.metadata()?.len();
+
+        let mut magic = [0u8; 6];
+        let magic_len = read_full(&mut file, &mut magic)?;
+        let compression = Compression::detect(&magic[..magic_len]);
+
+        let uncompressed_size = match compression {
+            Compression::None => Some(file_size),
+            Compression::Xz => xz_uncompressed_size(&mut file, file_size).ok(),
+            Compression::Zstd => zstd_uncompressed_size(&mut file, file_size).ok().flatten(),
+            // gzip only stores the size modulo 4 GiB and bzip2 not at all
+            Compression::Gzip | Compression::Bzip2 => None,
+        };
+
+        file.seek(SeekFrom::Start(0))?;
+        let consumed = Arc::new(AtomicU64::new(0));
+        let counted = BufReader::with_capacity(1024 * 1024, CountingReader::new(file, Arc::clone(&consumed)));
+        let reader = decompress(compression, Box::new(counted))?;
+
+        Ok(ImageReader { reader, compression, container: None, file_size, uncompressed_size, consumed })
+    }
+
+    fn open_entry(path: &Path, entry: &str) -> Result<ImageReader> {
+        let consumed = Arc::new(AtomicU64::new(0));
+        let opened = archive::open_entry(path, entry, Arc::clone(&consumed))?;
+
+        // The entry itself may be compressed too, e.g. "foo.img.xz" inside a .zip
+        let (compression, stream) = detect_compression(opened.reader)?;
+        let reader = decompress(compression, stream)?;
+        let uncompressed_size = (compression == Compression::None).then_some(opened.size);
+
+        Ok(ImageReader {
+            reader,
+            compression,
+            container: Some(opened.kind),
+            file_size: opened.total,
+            uncompressed_size,
+            consumed,
+        })
+    }
+
+    /// Fraction of the file on disk that has been read, from 0.0 to 1.0
+    ///
+    /// It is a handle rather than a number, so it can be watched from another thread once the reader
+    /// itself has moved to one
+    pub fn progress_meter(&self) -> impl Fn() -> f64 + Send + 'static {
+        let consumed = Arc::clone(&self.consumed);
+        let file_size = self.file_size;
+        move || if file_size == 0 { 1.0 } else { consumed.load(Ordering::Relaxed) as f64 / file_size as f64 }
+    }
+
+    /// Short description for the status line, e.g. "xz, 7.45 GiB uncompressed"
+    pub fn describe(&self) -> String {
+        let size = match (self.compression, self.container.is_some(), self.uncompressed_size) {
+            (Compression::None, false, _) => crate::units::human_size(self.file_size),
+            (Compression::None, true, Some(size)) => crate::units::human_size(size),
+            (c, _, Some(size)) => format!("{}, {} uncompressed", c, crate::units::human_size(size)),
+            (c, _, None) => format!("{}, uncompressed size unknown", c),
+        };
+
+        match &self.container {
+            Some(container) => format!("{}, {}", container, size),
+            None => size,
+        }
+    }
+}
+
+impl Read for ImageReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+/// Fills as much of `buf` as possible, only stopping early at end of file
+///
+/// A plain `read` may return fewer bytes than asked for (decompressors, pipes, some block devices),
+/// which would otherwise make chunks line up at different offsets
+pub fn read_full<R: Read>(r: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match r.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+fn invalid(msg: &str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+//
+// --- xz ---
+//
+
+/// Reads an xz multibyte integer (7 bits per byte, high bit means "more follows")
+fn xz_varint(buf: &[u8], pos: &mut usize) -> Result<u64> {
+    let mut value = 0u64;
+    for i in 0..9 {
+        let byte = *buf.get(*pos).ok_or_else(|| invalid("truncated xz index"))?;
+        *pos += 1;
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid("bad xz integer"))
+}
+
+/// Adds up the uncompressed sizes stored in the index of every xz stream, walking backwards from the end
+fn xz_uncompressed_size(file: &mut File, file_size: u64) -> Result<u64> {
+    let mut total = 0u64;
+    let mut end = file_size;
+
+    while end > 0 {
+        // Streams may be followed by padding made of null bytes, in multiples of four
+        let mut word = [0u8; 4];
+        loop {
+            if end < 4 {
+                return Err(invalid("bad xz stream padding"));
+            }
+            file.seek(SeekFrom::Start(end - 4))?;
+            file.read_exact(&mut word)?;
+            if word != [0; 4] {
+                break;
+            }
+            end -= 4;
+        }
+
+        // Stream footer: CRC32 (4), backward size (4), flags (2), "YZ"
+        if end < 12 + 12 {
+            return Err(invalid("xz file too small"));
+        }
+        let mut footer = [0u8; 12];
+        file.seek(SeekFrom::Start(end - 12))?;
+        file.read_exact(&mut footer)?;
+        if &footer[10..12] != b"YZ" {
+            return Err(invalid("missing xz stream footer"));
+        }
+
+        let index_size = (u64::from(u32::from_le_bytes(footer[4..8].try_into().unwrap())) + 1) * 4;
+        let index_start = (end - 12).checked_sub(index_size).ok_or_else(|| invalid("bad xz index size"))?;
+
+        let mut index = vec![0u8; index_size as usize];
+        file.seek(SeekFrom::Start(index_start))?;
+        file.read_exact(&mut index)?;
+        if index[0] != 0 {
+            return Err(invalid("missing xz index"));
+        }
+
+        let mut pos = 1;
+        let records = xz_varint(&index, &mut pos)?;
+        let mut blocks_size = 0u64;
+        // The sizes come from the file, a broken one must not overflow
+        let bad_sizes = || invalid("bad xz block sizes");
+        for _ in 0..records {
+            let unpadded = xz_varint(&index, &mut pos)?;
+            total = total.checked_add(xz_varint(&index, &mut pos)?).ok_or_else(bad_sizes)?;
+            blocks_size = unpadded
+                .checked_next_multiple_of(4)
+                .and_then(|padded| blocks_size.checked_add(padded))
+                .ok_or_else(bad_sizes)?;
+        }
+
+        // Step over this stream's blocks and its 12 byte header to reach the previous stream
+        end = blocks_size
+            .checked_add(12)
+            .and_then(|size| index_start.checked_sub(size))
+            .ok_or_else(bad_sizes)?;
+    }
+
+    Ok(total)
+}
+
+//
+// --- zstd ---
+//
+
+/// Adds up the content sizes of every zstd frame, or returns `None` if any frame leaves it out
+///
+/// Only the small frame and block headers are read, the compressed data itself is skipped over
+fn zstd_uncompressed_size(file: &mut File, file_size: u64) -> Result<Option<u64>> {
+    let mut reader = BufReader::new(&mut *file);
+    reader.seek(SeekFrom::Start(0))?;
+    let mut pos = 0u64;
+    let mut total = 0u64;
+
+    while pos < file_size {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        let magic = u32::from_le_bytes(magic);
+
+        // Skippable frames carry metadata only: magic, 4 byte length, data
+        if magic & 0xffff_fff0 == 0x184d_2a50 {
+            let mut len = [0u8; 4];
+            reader.read_exact(&mut len)?;
+            let len = u32::from_le_bytes(len);
+            reader.seek_relative(i64::from(len))?;
+            pos += 8 + u64::from(len);
+            continue;
+        }
+        if magic != 0xfd2f_b528 {
+            return Err(invalid("bad zstd frame magic"));
+        }
+
+        let mut fhd = [0u8; 1];
+        reader.read_exact(&mut fhd)?;
+        let fhd = fhd[0];
+        let fcs_flag = fhd >> 6;
+        let single_segment = fhd & 0x20 != 0;
+        let has_checksum = fhd & 0x04 != 0;
+        let dict_id_len = [0, 1, 2, 4][(fhd & 0x03) as usize];
+        let window_len = if single_segment { 0 } else { 1 };
+        let fcs_len = match fcs_flag {
+            0 if single_segment => 1,
+            0 => 0,
+            1 => 2,
+            2 => 4,
+            _ => 8,
+        };
+
+        if fcs_len == 0 {
+            return Ok(None);
+        }
+
+        reader.seek_relative(window_len + dict_id_len)?;
+        let mut fcs = [0u8; 8];
+        reader.read_exact(&mut fcs[..fcs_len])?;
+        let mut content_size = u64::from_le_bytes(fcs);
+        if fcs_len == 2 {
+            content_size += 256;
+        }
+        total = total.checked_add(content_size).ok_or_else(|| invalid("bad zstd frame sizes"))?;
+        pos += 5 + (window_len + dict_id_len) as u64 + fcs_len as u64;
+
+        // Each block starts with a 3 byte header: last-block bit, type and size
+        loop {
+            let mut header = [0u8; 3];
+            reader.read_exact(&mut header)?;
+            let header = u32::from(header[0]) | u32::from(header[1]) << 8 | u32::from(header[2]) << 16;
+            let last = header & 1 != 0;
+            let block_type = (header >> 1) & 0x03;
+            let block_size = header >> 3;
+            // RLE blocks store a single byte that is repeated `block_size` times
+            let stored = if block_type == 1 { 1 } else { block_size };
+            reader.seek_relative(i64::from(stored))?;
+            pos += 3 + u64::from(stored);
+            if last {
+                break;
+            }
+        }
+
+        if has_checksum {
+            reader.seek_relative(4)?;
+            pos += 4;
+        }
+    }
+
+    Ok(Some(total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    use crate::testutil::TempPath;
+
+    fn size_with(path: &TempPath, parse: impl Fn(&mut File, u64) -> Result<Option<u64>>) -> Result<Option<u64>> {
+        let mut file = File::open(path).unwrap();
+        let size = file.metadata().unwrap().len();
+        parse(&mut file, size)
+    }
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    fn xz(data: &[u8]) -> Vec<u8> {
+        let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 6);
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn xz_size_adds_up_every_stream() {
+        // Two streams with padding after each, as "cat a.xz b.xz" and some tools produce
+        let mut file = xz(&data(100_000));
+        file.extend([0; 8]);
+        file.extend(xz(&data(5)));
+        file.extend([0; 4]);
+        let file = TempPath::file("multi.xz", &file);
+        assert_eq!(size_with(&file, |f, size| xz_uncompressed_size(f, size).map(Some)).unwrap(), Some(100_005));
+    }
+
+    #[test]
+    fn broken_xz_is_an_error() {
+        let mut good = xz(&data(1000));
+        let len = good.len();
+        let file = TempPath::file("short.xz", &good[..len - 5]);
+        assert!(size_with(&file, |f, size| xz_uncompressed_size(f, size).map(Some)).is_err());
+
+        // An index whose one record claims a block far bigger than the file
+        let index_start = len - 12 - (u32::from_le_bytes(good[len - 8..len - 4].try_into().unwrap()) as usize + 1) * 4;
+        assert_eq!(good[index_start + 1], 1);
+        good[index_start + 2..index_start + 11].copy_from_slice(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f]);
+        good[index_start + 11] = 1;
+        let file = TempPath::file("huge.xz", &good);
+        assert!(size_with(&file, |f, size| xz_uncompressed_size(f, size).map(Some)).is_err());
+    }
+
+    #[test]
+    fn zstd_size_adds_up_every_frame() {
+        // bulk compression knows the size up front and stores it, for every size field width
+        let mut file = Vec::new();
+        for len in [10, 300, 70_000] {
+            file.extend(zstd::bulk::compress(&data(len), 3).unwrap());
+        }
+        // A skippable frame in between is stepped over
+        file.extend(0x184d_2a50u32.to_le_bytes());
+        file.extend(3u32.to_le_bytes());
+        file.extend([1, 2, 3]);
+        file.extend(zstd::bulk::compress(&data(1), 3).unwrap());
+        let file = TempPath::file("multi.zst", &file);
+        assert_eq!(size_with(&file, zstd_uncompressed_size).unwrap(), Some(70_311));
+    }
+
+    #[test]
+    fn zstd_without_sizes_is_unknown() {
+        // The streaming encoder does not know the size, so leaves it out
+        let file = TempPath::file("stream.zst", zstd::stream::encode_all(&data(1000)[..], 3).unwrap());
+        assert_eq!(size_with(&file, zstd_uncompressed_size).unwrap(), None);
+
+        let file = TempPath::file("bad.zst", b"not zstd at all");
+        assert!(size_with(&file, zstd_uncompressed_size).is_err());
+    }
+
+    #[test]
+    fn compressed_images_read_back_whole() {
+        let original = data(300_000);
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        gz.write_all(&original).unwrap();
+        let mut bz = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::fast());
+        bz.write_all(&original).unwrap();
+
+        for (name, bytes, compression, size) in [
+            ("raw.img", original.clone(), Compression::None, Some(300_000)),
+            ("img.gz", gz.finish().unwrap(), Compression::Gzip, None),
+            ("img.xz", xz(&original), Compression::Xz, Some(300_000)),
+            ("img.zst", zstd::bulk::compress(&original, 3).unwrap(), Compression::Zstd, Some(300_000)),
+            ("img.bz2", bz.finish().unwrap(), Compression::Bzip2, None),
+        ] {
+            let file = TempPath::file(name, &bytes);
+            let mut reader = ImageReader::open(&ImageSource::file(&*file)).unwrap();
+            assert_eq!((reader.compression, reader.uncompressed_size), (compression, size), "{}", name);
+            let mut back = vec![0u8; original.len() + 1];
+            assert_eq!(read_full(&mut reader, &mut back).unwrap(), original.len(), "{}", name);
+            assert_eq!(&back[..original.len()], &original[..], "{}", name);
+            assert_eq!(reader.progress_meter()(), 1.0, "{}", name);
+        }
+    }
+}
@@ -0,0 +1,289 @@
+//! iso.rs looks inside ISO9660 images (installer and live CDs) before they are flashed
+//!
+//! It reads the primary volume descriptor for the label, the El Torito boot catalog for how the
+//! image boots (BIOS, UEFI), and checks for the MBR/GPT that "hybrid" ISOs carry in their first sectors
+//!
+//! Only hybrid ISOs boot when written byte for byte to a USB drive, which is what tEtcher does
+//! Windows installer ISOs are not hybrid, so they get a warning before any drive is touched
+
+use std::io::{self, Read, Result};
+
+use crate::image::{ImageReader, ImageSource, read_full};
+use crate::parts::{self, TableKind};
+
+/// ISO9660 sectors are always 2048 bytes
+const SECTOR: u64 = 2048;
+/// Volume descriptors start at sector 16, after the "system area" that hybrid ISOs put an MBR in
+const DESCRIPTORS_START: u64 = 16;
+/// Give up on a descriptor set longer than this, real ones have a handful of entries
+const MAX_DESCRIPTORS: u64 = 32;
+/// Boot catalogs further into the image than this are not looked for, to keep compressed images quick
+const MAX_CATALOG_OFFSET: u64 = 64 * 1024 * 1024;
+
+/// What was found inside an ISO9660 image
+#[derive(Debug, Clone, Default)]
+pub struct IsoInfo {
+    /// The volume label, e.g. "Ubuntu 24.04 LTS amd64"
+    pub label: String,
+    /// Has an El Torito boot entry for BIOS (x86) machines
+    pub bios: bool,
+    /// Has an El Torito boot entry for UEFI machines
+    pub uefi: bool,
+    /// The partition table in the system area, if the ISO is hybrid
+    pub hybrid: Option<TableKind>,
+}
+
+impl IsoInfo {
+    /// A few lines describing the ISO, for the confirmation screen
+    pub fn summary(&self) -> Vec<String> {
+        let label = if self.label.is_empty() { "(none)" } else { &self.label };
+
+        let mut boot = Vec::new();
+        if self.bios {
+            boot.push("BIOS");
+        }
+        if self.uefi {
+            boot.push("UEFI");
+        }
+        let boot = if boot.is_empty() { "not bootable".to_string() } else { boot.join(" + ") };
+
+        let hybrid = match self.hybrid {
+            Some(table) => format!("yes ({}), boots when written to a USB drive", table),
+            None => "no".to_string(),
+        };
+
+        vec![
+            format!("ISO9660 volume: {}", label),
+            format!("Boot modes:     {}", boot),
+            format!("Hybrid:         {}", hybrid),
+        ]
+    }
+
+    /// The warning to show before flashing, if the image will not boot from a USB drive
+    pub fn warning(&self) -> Option<String> {
+        if self.hybrid.is_some() {
+            return None;
+        }
+        Some(
+            "This ISO is not hybrid, so it will most likely NOT boot once written to a USB drive.\n\
+             Windows installers are like this, they need a tool that copies the files onto a FAT32/NTFS stick instead."
+                .to_string(),
+        )
+    }
+}
+
+/// Reads forwards through the image, which may be compressed, so it can only skip ahead
+struct Forward {
+    reader: ImageReader,
+    pos: u64,
+}
+
+impl Forward {
+    /// Reads `buf.len()` bytes at `offset`, which must not be behind what was already read
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<bool> {
+        if offset < self.pos {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "ISO structures out of order"));
+        }
+        let skip = offset - self.pos;
+        if io::copy(&mut (&mut self.reader).take(skip), &mut io::sink())? < skip {
+            return Ok(false);
+        }
+        let n = read_full(&mut self.reader, buf)?;
+        self.pos = offset + n as u64;
+        Ok(n == buf.len())
+    }
+}
+
+/// Turns a space padded descriptor field into a string
+fn padded(field: &[u8]) -> String {
+    String::from_utf8_lossy(field).trim_end_matches([' ', '\0']).to_string()
+}
+
+/// Works out which platforms the El Torito boot catalog has bootable entries for
+///
+/// The catalog is a list of 32 byte entries: a validation entry (giving the first platform), the
+/// default entry, then any number of section headers, each with its own platform and entries
+fn boot_platforms(catalog: &[u8], info: &mut IsoInfo) {
+    // Validation entry: header id 1, and the key bytes 0x55 0xAA at the end
+    if catalog[0] != 1 || catalog[30..32] != [0x55, 0xAA] {
+        return;
+    }
+
+    let mut mark = |platform: u8| match platform {
+        0 => info.bios = true,
+        0xEF => info.uefi = true,
+        _ => {}
+    };
+
+    // Default entry, for the platform named in the validation entry
+    if catalog[32] == 0x88 {
+        mark(catalog[1]);
+    }
+
+    let mut offset = 64;
+    while offset + 32 <= catalog.len() {
+        let header = &catalog[offset..offset + 32];
+        if header[0] != 0x90 && header[0] != 0x91 {
+            break;
+        }
+        let platform = header[1];
+        let count = u16::from_le_bytes([header[2], header[3]]) as usize;
+        offset += 32;
+
+        for _ in 0..count {
+            if offset + 32 > catalog.len() {
+                return;
+            }
+            if catalog[offset] == 0x88 {
+                mark(platform);
+            }
+            offset += 32;
+        }
+
+        // 0x91 marks the last section header
+        if header[0] == 0x91 {
+            break;
+        }
+    }
+}
+
+/// Looks inside the image, returning `None` if it is not an ISO9660 image at all
+pub fn inspect(source: &ImageSource) -> Result<Option<IsoInfo>> {
+    let mut image = Forward { reader: ImageReader::open(source)?, pos: 0 };
+
+    let mut system_area = vec![0u8; (DESCRIPTORS_START * SECTOR) as usize];
+    if !image.read_at(0, &mut system_area)? {
+        return Ok(None);
+    }
+
+    let mut info = IsoInfo { hybrid: parts::parse(&system_area).map(|t| t.kind), ..Default::default() };
+    let mut found_pvd = false;
+    let mut catalog_lba = None;
+
+    for sector in DESCRIPTORS_START..DESCRIPTORS_START + MAX_DESCRIPTORS {
+        let mut desc = [0u8; SECTOR as usize];
+        if !image.read_at(sector * SECTOR, &mut desc)? || &desc[1..6] != b"CD001" {
+            break;
+        }
+
+        match desc[0] {
+            // Boot record, for El Torito it points at the boot catalog
+            0 if desc[7..30].starts_with(b"EL TORITO SPECIFICATION") => {
+                catalog_lba = Some(u32::from_le_bytes([desc[71], desc[72], desc[73], desc[74]]) as u64);
+            }
+            // Primary volume descriptor
+            1 => {
+                info.label = padded(&desc[40..72]);
+                found_pvd = true;
+            }
+            // Set terminator
+            255 => break,
+            _ => {}
+        }
+    }
+
+    if !found_pvd {
+        return Ok(None);
+    }
+
+    if let Some(lba) = catalog_lba {
+        let offset = lba * SECTOR;
+        let mut catalog = [0u8; SECTOR as usize];
+        if offset >= image.pos && offset <= MAX_CATALOG_OFFSET && image.read_at(offset, &mut catalog)? {
+            boot_platforms(&catalog, &mut info);
+        }
+    }
+
+    Ok(Some(info))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempPath;
+
+    /// A minimal ISO: PVD at 16, El Torito boot record at 17, terminator at 18, boot catalog at 20
+    fn iso(label: &str, hybrid: bool, catalog: &[u8]) -> Vec<u8> {
+        let mut image = vec![0u8; 21 * SECTOR as usize];
+        if hybrid {
+            // One bootable MBR entry covering the image, the way isohybrid does it
+            image[446] = 0x80;
+            image[446 + 4] = 0x17;
+            image[446 + 12..446 + 16].copy_from_slice(&84u32.to_le_bytes());
+            image[510..512].copy_from_slice(&[0x55, 0xAA]);
+        }
+
+        let sector = |n: u64| n as usize * SECTOR as usize;
+        let pvd = sector(16);
+        image[pvd] = 1;
+        image[pvd + 1..pvd + 6].copy_from_slice(b"CD001");
+        image[pvd + 40..pvd + 72].fill(b' ');
+        image[pvd + 40..pvd + 40 + label.len()].copy_from_slice(label.as_bytes());
+
+        let boot = sector(17);
+        image[boot + 1..boot + 6].copy_from_slice(b"CD001");
+        image[boot + 7..boot + 30].copy_from_slice(b"EL TORITO SPECIFICATION");
+        image[boot + 71..boot + 75].copy_from_slice(&20u32.to_le_bytes());
+
+        let end = sector(18);
+        image[end] = 255;
+        image[end + 1..end + 6].copy_from_slice(b"CD001");
+
+        image[sector(20)..sector(20) + catalog.len()].copy_from_slice(catalog);
+        image
+    }
+
+    /// Validation entry for BIOS and a bootable default entry, then a last section for UEFI
+    fn bios_and_uefi() -> Vec<u8> {
+        let mut catalog = vec![0u8; 128];
+        catalog[0] = 1;
+        catalog[30..32].copy_from_slice(&[0x55, 0xAA]);
+        catalog[32] = 0x88;
+        catalog[64] = 0x91;
+        catalog[65] = 0xEF;
+        catalog[66] = 1;
+        catalog[96] = 0x88;
+        catalog
+    }
+
+    fn inspect_bytes(name: &str, image: &[u8]) -> Option<IsoInfo> {
+        let path = TempPath::file(name, image);
+        inspect(&ImageSource::file(&*path)).unwrap()
+    }
+
+    #[test]
+    fn hybrid_iso_with_both_boot_modes() {
+        let info = inspect_bytes("hybrid.iso", &iso("Ubuntu 24.04 LTS amd64", true, &bios_and_uefi())).unwrap();
+        assert_eq!(info.label, "Ubuntu 24.04 LTS amd64");
+        assert!(info.bios && info.uefi);
+        assert_eq!(info.hybrid, Some(TableKind::Mbr));
+        assert!(info.warning().is_none());
+        assert_eq!(info.summary()[1], "Boot modes:     BIOS + UEFI");
+    }
+
+    #[test]
+    fn plain_iso_gets_a_warning() {
+        // A broken catalog, so no boot modes either
+        let info = inspect_bytes("plain.iso", &iso("", false, &[0u8; 64])).unwrap();
+        assert!(!info.bios && !info.uefi && info.hybrid.is_none());
+        assert!(info.warning().is_some());
+        assert_eq!(info.summary(), ["ISO9660 volume: (none)", "Boot modes:     not bootable", "Hybrid:         no"]);
+    }
+
+    #[test]
+    fn section_counts_past_the_catalog_are_ignored() {
+        let mut catalog = vec![0u8; SECTOR as usize];
+        catalog[..128].copy_from_slice(&bios_and_uefi());
+        // A header claiming far more entries than fit
+        catalog[66..68].copy_from_slice(&u16::MAX.to_le_bytes());
+        let mut info = IsoInfo::default();
+        boot_platforms(&catalog, &mut info);
+        assert!(info.bios && info.uefi);
+    }
+
+    #[test]
+    fn other_images_are_not_isos() {
+        assert!(inspect_bytes("zeros.img", &[0u8; 40 * 1024]).is_none());
+        assert!(inspect_bytes("short.img", b"CD001").is_none());
+    }
+}
@@ -10,27 +10,280 @@ use crossterm::{
 };
 use std::fs;
 use std::io::{stdout, Write};
+use std::path::{Path, PathBuf};
 
+use image::ImageSource;
+
+#[cfg(test)]
+mod testutil;
 mod targ;
 mod flash;
 mod verify;
+mod cli;
+mod archive;
+mod image;
+mod units;
+mod ui;
+mod backup;
+mod safety;
+mod iso;
+mod parts;
+mod probe;
+mod rescue;
+mod checksum;
+mod signature;
+mod sparse;
+mod bmap;
+mod cancel;
+mod devio;
+mod pool;
+mod progress;
+mod resume;
+#[cfg(all(target_os = "linux", feature = "uring"))]
+mod uring;
 
 /// To run this program, go to the README.md and follow the steps
+///
+/// It starts by asking whether to flash an image, back up a drive (see backup.rs), check a drive
+/// flashed earlier (see verify.rs) or undo a flash (see rescue.rs)
 /// 
-/// This program will list all directories and files, letting you move around your computer's files like a file explorer
+/// To flash, this program will list all directories and files, letting you move around your computer's files like a file explorer
 /// When a file is chosen, it will ask if '/folder/file' is the correct path, and then procede to targ.rs
+/// If the file is a .zip or .tar archive, it first lists the disk images inside it to choose from
 /// 
 /// Navigate using the arrow-keys to move up and down, and enter key to select
-
+///
+/// If any arguments are given, the menus are skipped and cli.rs takes over instead
 fn main() -> std::io::Result<()> {
-    
-    let mut selected = 0;
-    let mut current_dir = std::env::current_dir()?; // Track current directory
+    cancel::install();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        std::process::exit(cli::run(&args));
+    }
 
     enable_raw_mode()?;
     let mut stdout = stdout();
     execute!(stdout, cursor::Hide)?;
 
+    let mut current_dir = std::env::current_dir()?; // Track current directory
+
+    loop {
+        let mode = ui::choose(
+            &"What would you like to do?".with(Color::Blue).to_string(),
+            &[
+                "Flash an image to a drive",
+                "Back up a drive to an image file",
+                "Verify a drive that was flashed earlier",
+                "Undo a flash (restore a drive's partition table)",
+                "[Exit]",
+            ],
+        )?;
+
+        match mode {
+            Some(0) => flash_menu(&mut current_dir)?,
+            Some(1) => backup::menu(&mut current_dir)?,
+            Some(2) => verify::menu(&mut current_dir)?,
+            Some(3) => rescue::menu()?,
+            _ => break,
+        }
+    }
+
+    execute!(stdout, cursor::Show)?;
+    disable_raw_mode()?;
+    Ok(())
+}
+
+/// Picks the image with the file browser, confirms it and then moves on to targ.rs
+fn flash_menu(current_dir: &mut PathBuf) -> std::io::Result<()> {
+    loop {
+        let Some(source) = pick_image("Please navigate to the file you wish to flash", current_dir)? else {
+            return Ok(());
+        };
+
+        let mut stdout = std::io::stdout();
+        // File selected: confirmation
+        let confirm_options = ["Yes", "No"];
+        let mut confselected = 0;
+
+        // ISO images: show the label and how it boots, so a wrong download is noticed here
+        let iso_info = iso::inspect(&source).ok().flatten();
+        let info_lines: Vec<String> = iso_info
+            .as_ref()
+            .map(|i| i.summary())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|l| l.dark_grey().to_string())
+            .collect();
+
+        loop {
+            execute!(
+                stdout,
+                cursor::MoveTo(0, 0),
+                terminal::Clear(ClearType::FromCursorDown)
+            )?;
+            print!("Is '{}' the file you wish to flash?", source);
+            for (i, line) in info_lines.iter().enumerate() {
+                execute!(stdout, cursor::MoveTo(0, (i + 2) as u16))?;
+                print!("  {}", line);
+            }
+
+            let options_row = if info_lines.is_empty() { 2 } else { info_lines.len() + 3 };
+            for (i, item) in confirm_options.iter().enumerate() {
+                execute!(stdout, cursor::MoveTo(0, (i + options_row) as u16))?;
+                execute!(stdout, terminal::Clear(ClearType::CurrentLine))?;
+                if i == confselected {
+                    print!("  {}", item.on_white().black());
+                } else {
+                    print!("  {}", item);
+                }
+            }
+
+            stdout.flush()?;
+
+            if let Event::Key(ev) = event::read()? {
+                match ev.code {
+                    KeyCode::Up => confselected = confselected.saturating_sub(1),
+                    KeyCode::Down if confselected < confirm_options.len() - 1 => confselected += 1,
+                    KeyCode::Enter => {
+                        // The signature is checked on whichever checksum file was used, found or picked
+                        if confirm_options[confselected] == "Yes"
+                            && let Some(expected) = checksum_accepted(&source)?
+                            && signature_accepted(expected.and_then(|e| signature::check(&e.source)).as_ref())?
+                            && iso_warning_accepted(iso_info.as_ref())?
+                        {
+                            let _ = targ::menu(&source);
+                        }
+                        break;
+                    }
+                    KeyCode::Esc => break,
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+/// Picks an image with the file browser, and the image inside it if it is an archive
+///
+/// Returns `None` if the user picked "[Exit]"
+pub(crate) fn pick_image(title: &str, current_dir: &mut PathBuf) -> std::io::Result<Option<ImageSource>> {
+    loop {
+        let Some(path) = browse(title, current_dir, false)? else {
+            return Ok(None);
+        };
+
+        // Archives: choose which image inside it to use
+        return Ok(Some(match archive::detect(&path) {
+            Ok(Some(_)) => match pick_archive_entry(&path)? {
+                Some(entry) => ImageSource::archive_entry(&path, entry),
+                None => continue,
+            },
+            _ => ImageSource::file(&path),
+        }));
+    }
+}
+
+/// Says whether a trusted key signed the checksum file
+///
+/// A checksum file with a bad signature may have been tampered with, so make sure before going on
+fn signature_accepted(check: Option<&signature::SignatureCheck>) -> std::io::Result<bool> {
+    let Some(check) = check else {
+        return Ok(true);
+    };
+    let line = check.to_string();
+    let line = match check.status {
+        signature::Status::Bad(_) => {
+            return ui::confirm(&format!(
+                "{}\n{}\n\nFlash it anyway?",
+                line.red().bold(),
+                "The checksum file may have been tampered with, so the image cannot be trusted either.".red().bold()
+            ));
+        }
+        signature::Status::Trusted(_) => line.green().bold(),
+        _ => line.yellow(),
+    };
+    print!("\x1B[H\x1B[2J");
+    print!("{}", line);
+    ui::pause()?;
+    Ok(true)
+}
+
+/// Checks the image against a checksum file next to it, or one picked in the browser, before going on
+///
+/// A mismatch means a corrupted download, so it takes "Flash anyway" to carry on
+/// Returns `None` if the image should not be flashed, otherwise the checksum it was checked
+/// against (`Some(None)` when flashing without checking)
+fn checksum_accepted(source: &ImageSource) -> std::io::Result<Option<Option<checksum::Expected>>> {
+    let expected = match checksum::find(&source.path) {
+        Some(expected) => expected,
+        None => {
+            let choice = ui::choose(
+                &"No checksum file (SHA256SUMS, .sha256, ...) was found next to the image".blue().to_string(),
+                &["Flash without checking", "Choose a checksum file", "Go back"],
+            )?;
+            if choice != Some(1) {
+                return Ok((choice == Some(0)).then_some(None));
+            }
+
+            let mut dir = source.path.parent().map(Path::to_path_buf).unwrap_or_default();
+            let Some(file) = browse("Please navigate to the checksum file", &mut dir, false)? else {
+                return Ok(None);
+            };
+            match checksum::from_file(&file, &source.path) {
+                Ok(Some(expected)) => expected,
+                Ok(None) => {
+                    print!("\x1B[H\x1B[2J");
+                    print!("{}", format!("{} does not list {}", file.display(), source.path.display()).red());
+                    ui::pause()?;
+                    return Ok(None);
+                }
+                Err(e) => {
+                    print!("\x1B[H\x1B[2J");
+                    print!("{}", format!("Could not read {}: {}", file.display(), e).red());
+                    ui::pause()?;
+                    return Ok(None);
+                }
+            }
+        }
+    };
+
+    print!("\x1B[H\x1B[2J");
+    let problem = match checksum::check(&source.path, &expected) {
+        Ok(result) if result.is_ok() => {
+            print!("{}", format!("✅ {}", result.describe()).green().bold());
+            ui::pause()?;
+            return Ok(Some(Some(expected)));
+        }
+        Ok(result) => format!("❌ {}\nThe download is probably corrupted, download it again.", result.describe()),
+        Err(e) => format!("❌ Could not check the image: {}", e),
+    };
+
+    let problem: Vec<String> = problem.lines().map(|l| l.red().bold().to_string()).collect();
+    Ok((ui::choose(&problem.join("\n"), &["Go back", "Flash anyway"])? == Some(1)).then_some(Some(expected)))
+}
+
+/// Non-hybrid ISOs will not boot from a USB drive, so make sure that is really what is wanted
+///
+/// Anything that is not a non-hybrid ISO goes straight through
+fn iso_warning_accepted(info: Option<&iso::IsoInfo>) -> std::io::Result<bool> {
+    let Some(warning) = info.and_then(|i| i.warning()) else {
+        return Ok(true);
+    };
+    let warning: Vec<String> = warning.lines().map(|l| l.red().bold().to_string()).collect();
+    ui::confirm(&format!("{}\n\nFlash it anyway?", warning.join("\n")))
+}
+
+/// The file browser: lists every folder and file, starting in `current_dir`
+///
+/// Returns the chosen file, or with `pick_dir` the folder chosen with "[Save here]"
+/// `current_dir` is left wherever the user navigated to, so the next browse starts there
+///
+/// Returns `None` if the user picked "[Exit]"
+pub(crate) fn browse(title: &str, current_dir: &mut PathBuf, pick_dir: bool) -> std::io::Result<Option<PathBuf>> {
+    let mut selected = 0;
+    let mut stdout = stdout();
+
     loop {
         // Read all entries in the current directory
         let mut menu_items: Vec<String> = if let Ok(entries) = fs::read_dir(&current_dir) {
@@ -45,9 +298,14 @@ fn main() -> std::io::Result<()> {
         //Added "[Exit]" option for easy exit access
         menu_items.insert(0, "[Exit]".to_string());
 
+        // Add a "Save here" option when choosing a folder rather than a file
+        if pick_dir {
+            menu_items.insert(1, "[Save here]".to_string());
+        }
+
         // Add a "Back" option if not at root
         if current_dir.parent().is_some() {
-            menu_items.insert(1, "[Back]".to_string());
+            menu_items.insert(if pick_dir { 2 } else { 1 }, "[Back]".to_string());
         }
 
         // Adjust selection if needed
@@ -61,7 +319,7 @@ fn main() -> std::io::Result<()> {
             cursor::MoveTo(0, 0),
             terminal::Clear(ClearType::FromCursorDown)
         )?;
-        println!("{}", "Please navigate to the file you wish to flash".with(Color::Blue));
+        println!("{}", title.with(Color::Blue));
 
         for (i, item) in menu_items.iter().enumerate() {
             execute!(stdout, cursor::MoveTo(0, (i + 1) as u16))?;
@@ -70,7 +328,7 @@ fn main() -> std::io::Result<()> {
             /// Determine styling
             /// 
             /// To change the type of colour, edit the associated item edit
-            let display_item = if item == "[Back]" {
+            let display_item = if item == "[Back]" || item == "[Save here]" {
                 //Display item will be green and bold
                 item.clone().with(Color::Green).bold().to_string()
             } else if item == "[Exit]" {
@@ -103,29 +361,24 @@ fn main() -> std::io::Result<()> {
         if let Event::Key(event) = event::read()? {
             match event.code {
                 //Move selected item up when Up-arrow is pressed
-                KeyCode::Up => {
-                    if selected > 0 {
-                        selected -= 1;
-                    }
-                }
+                KeyCode::Up => selected = selected.saturating_sub(1),
                 //Move selected item down when down-arrow is pressed
-                KeyCode::Down => {
-                    if selected < menu_items.len().saturating_sub(1) { // .len() and .saturating_sub(1) checks that the selected item is not greater than menu items
-                        selected += 1;
-                    }
-                }
+                // .len() and .saturating_sub(1) checks that the selected item is not greater than menu items
+                KeyCode::Down if selected < menu_items.len().saturating_sub(1) => selected += 1,
                 KeyCode::Enter => {
                     let selected_item = &menu_items[selected];
                     // If the user selected [Exit]
                     if selected_item == "[Exit]" {
-                        execute!(stdout, cursor::Show)?;
-                        disable_raw_mode()?;
-                        break;
-                    } 
+                        return Ok(None);
+                    }
+
+                    else if selected_item == "[Save here]" {
+                        return Ok(Some(current_dir.clone()));
+                    }
 
                     else if selected_item == "[Back]" {
                         if let Some(parent) = current_dir.parent() {
-                            current_dir = parent.to_path_buf();
+                            *current_dir = parent.to_path_buf();
                             selected = 0;
                         }
                         continue;
@@ -133,66 +386,72 @@ fn main() -> std::io::Result<()> {
 
                     let path = current_dir.join(selected_item);
                     if path.is_dir() {
-                        current_dir = path;
+                        *current_dir = path;
                         selected = 0;
                         continue;
                     }
 
-                    let mut stdout = std::io::stdout();
-                    // File selected: confirmation
-                    let confirm_options = ["Yes", "No"];
-                    let mut confselected = 0;
-
-                    loop {
-                        execute!(
-                            stdout,
-                            cursor::MoveTo(0, 0),
-                            terminal::Clear(ClearType::FromCursorDown)
-                        )?;
-                        println!("Is '{}' the file you wish to flash?", path.display());
-
-                        for (i, item) in confirm_options.iter().enumerate() {
-                            execute!(stdout, cursor::MoveTo(0, (i + 2) as u16))?;
-                            execute!(stdout, terminal::Clear(ClearType::CurrentLine))?;
-                            if i == confselected {
-                                print!("  {}", item.on_white().black());
-                            } else {
-                                print!("  {}", item);
-                            }
-                        }
-
-                        stdout.flush()?;
-
-                        if let Event::Key(ev) = event::read()? {
-                            match ev.code {
-                                KeyCode::Up => {
-                                    if confselected > 0 {
-                                        confselected -= 1;
-                                    }
-                                }
-                                KeyCode::Down => {
-                                    if confselected < confirm_options.len() - 1 {
-                                        confselected += 1;
-                                    }
-                                }
-                                KeyCode::Enter => {
-                                    if confirm_options[confselected] == "Yes" {
-                                        let _ = targ::menu(&path);
-                                    }
-                                    break;
-                                }
-                                KeyCode::Esc => break,
-                                _ => {}
-                            }
-                        }
+                    // Files can only be picked when choosing a file
+                    if !pick_dir {
+                        return Ok(Some(path));
                     }
                 }
                 _ => {}
             }
         }
     }
+}
 
-    execute!(stdout, cursor::Show)?;
-    disable_raw_mode()?;
-    Ok(())
+/// Lists the disk images inside an archive and lets the user pick one
+///
+/// Returns `None` if the user backs out, or if the archive could not be read
+fn pick_archive_entry(path: &Path) -> std::io::Result<Option<String>> {
+    let mut stdout = stdout();
+    let entries = match archive::list_images(path) {
+        Ok(entries) => entries,
+        Err(e) => {
+            execute!(stdout, cursor::MoveTo(0, 0), terminal::Clear(ClearType::FromCursorDown))?;
+            print!("{}", format!("Could not read {}: {}", path.display(), e).red());
+            execute!(stdout, cursor::MoveTo(0, 2))?;
+            print!("Press any key to go back");
+            stdout.flush()?;
+            event::read()?;
+            return Ok(None);
+        }
+    };
+
+    let mut selected = 0;
+
+    loop {
+        execute!(stdout, cursor::MoveTo(0, 0), terminal::Clear(ClearType::FromCursorDown))?;
+        print!("{}", format!("Which image inside {} do you wish to flash?", path.display()).with(Color::Blue));
+
+        // Row 0 of the list is always "[Back]"
+        let back = "[Back]".with(Color::Green).bold().to_string();
+        let labels = std::iter::once(back).chain(
+            entries.iter().map(|e| format!("{}  ({})", e.name, units::human_size(e.size))),
+        );
+
+        for (i, label) in labels.enumerate() {
+            execute!(stdout, cursor::MoveTo(0, (i + 1) as u16))?;
+            if i == selected {
+                print!("  {}", label.on_white().black());
+            } else {
+                print!("  {}", label);
+            }
+        }
+
+        stdout.flush()?;
+
+        if let Event::Key(ev) = event::read()? {
+            match ev.code {
+                KeyCode::Up => selected = selected.saturating_sub(1),
+                KeyCode::Down if selected < entries.len() => selected += 1,
+                KeyCode::Enter if selected == 0 => return Ok(None),
+                KeyCode::Enter => return Ok(Some(entries[selected - 1].name.clone())),
+                KeyCode::Esc => return Ok(None),
+                _ => {}
+            }
+        }
+    }
 }
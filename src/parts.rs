@@ -0,0 +1,488 @@
+//! parts.rs reads MBR and GPT partition tables, from images and from drives
+//!
+//! It is used to preview what an image will create on the drive, next to what the drive holds now
+//!
+//! GPT headers and partition arrays carry CRC32s, they are checked and any mismatch is reported
+//! rather than hidden, since a damaged table is worth knowing about before flashing
+//! Logical partitions inside an MBR extended partition are not followed, the extended one is listed
+
+use std::fmt;
+use std::io::{Read, Result};
+
+use crate::image::{ImageReader, ImageSource, read_full};
+use crate::units;
+use crate::verify;
+
+/// How much of the start of a disk is read up front, enough for any usual GPT
+const HEAD: usize = 1024 * 1024;
+/// GPT partition arrays bigger than this are not read
+const MAX_ARRAY: u64 = 16 * 1024 * 1024;
+
+/// Type name for GPT type GUIDs not in `gpt_type_name`
+const UNKNOWN_GPT_TYPE: &str = "Unknown GPT type";
+
+/// Which kind of partition table it is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TableKind {
+    Mbr,
+    Gpt,
+}
+
+impl fmt::Display for TableKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TableKind::Mbr => "MBR",
+            TableKind::Gpt => "GPT",
+        })
+    }
+}
+
+/// One partition in the table
+#[derive(Debug, Clone)]
+pub struct Partition {
+    /// Partition number as the OS would show it, starting at 1
+    pub number: usize,
+    /// Offset from the start of the disk, in bytes
+    pub start: u64,
+    /// Size in bytes
+    pub size: u64,
+    /// GPT type GUID, e.g. "C12A7328-F81F-11D2-BA4B-00A0C93EC93B"
+    pub type_guid: Option<String>,
+    /// MBR partition type byte, e.g. 0x0C for FAT32
+    pub mbr_type: Option<u8>,
+    /// Readable type, e.g. "EFI System"
+    pub type_name: String,
+    /// GPT partition name, empty for MBR
+    pub name: String,
+}
+
+/// A partition table and whatever was wrong with it
+#[derive(Debug, Clone)]
+pub struct PartitionTable {
+    pub kind: TableKind,
+    /// Bytes per sector the table was found with, 512 or 4096
+    pub sector_size: u64,
+    pub partitions: Vec<Partition>,
+    /// Checksum mismatches and the like, empty for a healthy table
+    pub problems: Vec<String>,
+}
+
+impl PartitionTable {
+    /// A header line and one line per partition, for the confirmation screens
+    pub fn describe(&self) -> Vec<String> {
+        let sectors = if self.sector_size == 512 { String::new() } else { format!(", {} byte sectors", self.sector_size) };
+        let mut lines = vec![format!("{}, {} partition(s){}", self.kind, self.partitions.len(), sectors)];
+        for p in &self.partitions {
+            let name = if p.name.is_empty() { String::new() } else { format!(" '{}'", p.name) };
+            // Types without a name are shown by their GUID, so they can at least be looked up
+            let type_name = match &p.type_guid {
+                Some(guid) if p.type_name == UNKNOWN_GPT_TYPE => guid.clone(),
+                _ => p.type_name.clone(),
+            };
+            lines.push(format!(
+                "  {:>2}  {:<24} {:>11} at {}{}",
+                p.number,
+                type_name,
+                units::human_size(p.size),
+                units::human_size(p.start),
+                name
+            ));
+        }
+        for problem in &self.problems {
+            lines.push(format!("  ! {}", problem));
+        }
+        lines
+    }
+}
+
+/// Reads the partition table of an image, which may be compressed or inside an archive
+pub fn read_image(source: &ImageSource) -> Result<Option<PartitionTable>> {
+    read_table(ImageReader::open(source)?)
+}
+
+/// Reads the partition table currently on a drive
+pub fn read_device(path: &str) -> Result<Option<PartitionTable>> {
+    read_table(verify::open_device(path)?)
+}
+
+/// Reads the start of a disk from `reader` and parses the table, reading further if the GPT array needs it
+pub fn read_table<R: Read>(mut reader: R) -> Result<Option<PartitionTable>> {
+    let mut head = vec![0u8; HEAD];
+    let n = read_full(&mut reader, &mut head)?;
+    head.truncate(n);
+
+    // A GPT array past the first MiB is unusual, but allowed
+    if let Some(end) = gpt_array_end(&head)
+        && end > head.len() as u64
+        && end <= MAX_ARRAY {
+        let mut rest = vec![0u8; end as usize - head.len()];
+        let n = read_full(&mut reader, &mut rest)?;
+        head.extend_from_slice(&rest[..n]);
+    }
+
+    Ok(parse(&head))
+}
+
+/// Parses a table from the first bytes of a disk
+///
+/// A protective MBR (type 0xEE) means the real table is the GPT behind it
+pub fn parse(head: &[u8]) -> Option<PartitionTable> {
+    if let Some(sector_size) = gpt_sector_size(head) {
+        return Some(parse_gpt(head, sector_size));
+    }
+
+    let mut table = parse_mbr(head)?;
+    if table.partitions.iter().any(|p| p.mbr_type == Some(0xEE)) {
+        table.problems.push("protective MBR, but the GPT header is missing".to_string());
+    }
+    Some(table)
+}
+
+fn u16_at(b: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([b[at], b[at + 1]])
+}
+
+fn u32_at(b: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(b[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(b: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(b[at..at + 8].try_into().unwrap())
+}
+
+//
+// --- MBR ---
+//
+
+/// Names for the MBR partition types that turn up on USB drives and SD cards
+fn mbr_type_name(t: u8) -> String {
+    let name = match t {
+        0x00 => "Empty",
+        0x01 => "FAT12",
+        0x04 | 0x06 | 0x0E => "FAT16",
+        0x05 | 0x0F | 0x85 => "Extended",
+        0x07 => "NTFS/exFAT",
+        0x0B | 0x0C => "FAT32",
+        0x17 => "Hidden NTFS/IFS",
+        0x1C => "Hidden FAT32",
+        0x27 => "Windows recovery",
+        0x82 => "Linux swap",
+        0x83 => "Linux",
+        0x8E => "Linux LVM",
+        0xA5 => "FreeBSD",
+        0xA8 => "Apple UFS",
+        0xAF => "Apple HFS+",
+        0xDA => "Non-FS data",
+        0xEE => "GPT protective",
+        0xEF => "EFI System",
+        0xFD => "Linux RAID",
+        _ => return format!("Type 0x{:02X}", t),
+    };
+    name.to_string()
+}
+
+fn parse_mbr(head: &[u8]) -> Option<PartitionTable> {
+    if head.len() < 512 || head[510..512] != [0x55, 0xAA] {
+        return None;
+    }
+
+    // A FAT or NTFS boot sector also ends in 0x55AA, so only call it a table if the entries make sense
+    let mut partitions = Vec::new();
+    for i in 0..4 {
+        let entry = &head[446 + i * 16..446 + (i + 1) * 16];
+        if entry[0] != 0 && entry[0] != 0x80 {
+            return None;
+        }
+        let kind = entry[4];
+        let (start, sectors) = (u32_at(entry, 8), u32_at(entry, 12));
+        // Type 0 normally means unused, but hybrid ISOs (e.g. Debian's) cover themselves with a type 0 entry
+        if sectors == 0 {
+            continue;
+        }
+        partitions.push(Partition {
+            number: i + 1,
+            start: start as u64 * 512,
+            size: sectors as u64 * 512,
+            type_guid: None,
+            mbr_type: Some(kind),
+            type_name: mbr_type_name(kind),
+            name: String::new(),
+        });
+    }
+
+    if partitions.is_empty() {
+        return None;
+    }
+    Some(PartitionTable { kind: TableKind::Mbr, sector_size: 512, partitions, problems: Vec::new() })
+}
+
+//
+// --- GPT ---
+//
+
+/// Names for the common GPT partition type GUIDs
+fn gpt_type_name(guid: &str) -> String {
+    let name = match guid {
+        "C12A7328-F81F-11D2-BA4B-00A0C93EC93B" => "EFI System",
+        "21686148-6449-6E6F-744E-656564454649" => "BIOS boot",
+        "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7" => "Microsoft basic data",
+        "E3C9E316-0B5C-4DB8-817D-F92DF00215AE" => "Microsoft reserved",
+        "DE94BBA4-06D1-4D40-A16A-BFD50179D6AC" => "Windows recovery",
+        "0FC63DAF-8483-4772-8E79-3D69D8477DE4" => "Linux filesystem",
+        "4F68BCE3-E8CD-4DB1-96E7-FBCAF984B709" => "Linux root (x86-64)",
+        "B921B045-1DF0-41C3-AF44-4C6F280D3FAE" => "Linux root (ARM64)",
+        "BC13C2FF-59E6-4262-A352-B275FD6F7172" => "Linux extended boot",
+        "0657FD6D-A4AB-43C4-84E5-0933C84B4F4F" => "Linux swap",
+        "E6D6D379-F507-44C2-A23C-238F2A3DF928" => "Linux LVM",
+        "A19D880F-05FC-4D3B-A006-743F0F84911E" => "Linux RAID",
+        "48465300-0000-11AA-AA11-00306543ECAC" => "Apple HFS+",
+        "7C3457EF-0000-11AA-AA11-00306543ECAC" => "Apple APFS",
+        "FE3A2A5D-4F32-41A7-B725-ACCC3285A309" => "ChromeOS kernel",
+        "3CB8E202-3B7E-47DD-8A3C-7FF2A13CFCEC" => "ChromeOS root",
+        _ => UNKNOWN_GPT_TYPE,
+    };
+    name.to_string()
+}
+
+/// Formats a GUID, whose first three fields are stored little-endian
+fn guid(b: &[u8]) -> String {
+    format!(
+        "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}",
+        u32_at(b, 0),
+        u16_at(b, 4),
+        u16_at(b, 6),
+        b[8], b[9], b[10], b[11], b[12], b[13], b[14], b[15]
+    )
+}
+
+/// The header sits in LBA 1, so its offset depends on the sector size, try both usual ones
+fn gpt_sector_size(head: &[u8]) -> Option<u64> {
+    [512u64, 4096]
+        .into_iter()
+        .find(|&s| head.len() as u64 >= s + 92 && &head[s as usize..s as usize + 8] == b"EFI PART")
+}
+
+/// Where the partition array ends, so the caller knows how much to read
+fn gpt_array_end(head: &[u8]) -> Option<u64> {
+    let sector_size = gpt_sector_size(head)?;
+    let h = &head[sector_size as usize..];
+    let entries_lba = u64_at(h, 72);
+    let count = u32_at(h, 80) as u64;
+    let entry_size = u32_at(h, 84) as u64;
+    entries_lba.checked_mul(sector_size)?.checked_add(count.checked_mul(entry_size)?)
+}
+
+fn parse_gpt(head: &[u8], sector_size: u64) -> PartitionTable {
+    let mut table = PartitionTable { kind: TableKind::Gpt, sector_size, partitions: Vec::new(), problems: Vec::new() };
+    let h = &head[sector_size as usize..];
+
+    // Header CRC covers `header_size` bytes with the CRC field itself zeroed
+    let header_size = u32_at(h, 12) as usize;
+    if (92..=sector_size as usize).contains(&header_size) && h.len() >= header_size {
+        let mut header = h[..header_size].to_vec();
+        header[16..20].fill(0);
+        if crc32fast::hash(&header) != u32_at(h, 16) {
+            table.problems.push("GPT header checksum does not match".to_string());
+        }
+    } else {
+        table.problems.push(format!("GPT header has a bad size ({} bytes)", header_size));
+    }
+
+    let entries_at = u64_at(h, 72).saturating_mul(sector_size);
+    let count = u32_at(h, 80) as u64;
+    let entry_size = u32_at(h, 84) as u64;
+    if entry_size < 128 {
+        table.problems.push(format!("GPT entries have a bad size ({} bytes)", entry_size));
+        return table;
+    }
+
+    let end = entries_at.saturating_add(count.saturating_mul(entry_size));
+    if end > head.len() as u64 {
+        table.problems.push("GPT partition array could not be read".to_string());
+        return table;
+    }
+    let array = &head[entries_at as usize..end as usize];
+
+    if crc32fast::hash(array) != u32_at(h, 88) {
+        table.problems.push("GPT partition array checksum does not match".to_string());
+    }
+
+    for (i, entry) in array.chunks(entry_size as usize).enumerate() {
+        // An all-zero type GUID is an unused slot
+        if entry[..16].iter().all(|&b| b == 0) {
+            continue;
+        }
+        let (first, last) = (u64_at(entry, 32), u64_at(entry, 40));
+        let type_guid = guid(&entry[..16]);
+
+        // Both come straight off the disk, a damaged (or made up) entry can point anywhere
+        let range = last
+            .checked_add(1)
+            .filter(|&end| end > first)
+            .and_then(|end| Some((first.checked_mul(sector_size)?, (end - first).checked_mul(sector_size)?)));
+        let Some((start, size)) = range else {
+            table.problems.push(format!("GPT partition {} has an impossible range (LBA {} to {})", i + 1, first, last));
+            continue;
+        };
+
+        // Names are UTF-16LE, nul padded
+        let name: Vec<u16> = entry[56..128]
+            .chunks(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|&c| c != 0)
+            .collect();
+
+        table.partitions.push(Partition {
+            number: i + 1,
+            start,
+            size,
+            type_name: gpt_type_name(&type_guid),
+            type_guid: Some(type_guid),
+            mbr_type: None,
+            name: String::from_utf16_lossy(&name),
+        });
+    }
+
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// EFI System, as stored on disk
+    const ESP: [u8; 16] = [0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11, 0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B];
+
+    fn mbr_entry(head: &mut [u8], slot: usize, kind: u8, start: u32, sectors: u32) {
+        let entry = &mut head[446 + slot * 16..446 + (slot + 1) * 16];
+        entry[4] = kind;
+        entry[8..12].copy_from_slice(&start.to_le_bytes());
+        entry[12..16].copy_from_slice(&sectors.to_le_bytes());
+        head[510..512].copy_from_slice(&[0x55, 0xAA]);
+    }
+
+    /// A protective MBR, a GPT header in LBA 1 and a 128 entry array at `entries_lba`, with correct checksums
+    fn gpt(sector_size: usize, entries_lba: u64, entries: &[([u8; 16], u64, u64, &str)]) -> Vec<u8> {
+        let array_at = entries_lba as usize * sector_size;
+        let mut disk = vec![0u8; array_at + 128 * 128];
+        mbr_entry(&mut disk, 0, 0xEE, 1, u32::MAX);
+
+        for (i, (type_guid, first, last, name)) in entries.iter().enumerate() {
+            let entry = &mut disk[array_at + i * 128..array_at + (i + 1) * 128];
+            entry[..16].copy_from_slice(type_guid);
+            entry[32..40].copy_from_slice(&first.to_le_bytes());
+            entry[40..48].copy_from_slice(&last.to_le_bytes());
+            for (j, c) in name.encode_utf16().enumerate() {
+                entry[56 + j * 2..58 + j * 2].copy_from_slice(&c.to_le_bytes());
+            }
+        }
+        let array_crc = crc32fast::hash(&disk[array_at..]);
+
+        let h = &mut disk[sector_size..sector_size + 92];
+        h[..8].copy_from_slice(b"EFI PART");
+        h[12..16].copy_from_slice(&92u32.to_le_bytes());
+        h[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+        h[80..84].copy_from_slice(&128u32.to_le_bytes());
+        h[84..88].copy_from_slice(&128u32.to_le_bytes());
+        h[88..92].copy_from_slice(&array_crc.to_le_bytes());
+        let header_crc = crc32fast::hash(h);
+        h[16..20].copy_from_slice(&header_crc.to_le_bytes());
+        disk
+    }
+
+    #[test]
+    fn mbr_entries() {
+        let mut head = vec![0u8; 512];
+        mbr_entry(&mut head, 0, 0x0C, 2048, 2048);
+        mbr_entry(&mut head, 2, 0x83, 4096, 8192);
+        let table = parse(&head).unwrap();
+        assert_eq!(table.kind, TableKind::Mbr);
+        assert!(table.problems.is_empty());
+        let found: Vec<_> = table.partitions.iter().map(|p| (p.number, p.start, p.size, p.mbr_type)).collect();
+        assert_eq!(found, [(1, 1024 * 1024, 1024 * 1024, Some(0x0C)), (3, 2 * 1024 * 1024, 4 * 1024 * 1024, Some(0x83))]);
+    }
+
+    #[test]
+    fn boot_sectors_are_not_tables() {
+        let mut head = vec![0u8; 512];
+        assert!(parse(&head).is_none());
+        // A FAT boot sector has code where the entries would be
+        head[446..510].fill(0x31);
+        head[510..512].copy_from_slice(&[0x55, 0xAA]);
+        assert!(parse(&head).is_none());
+        assert!(parse(&head[..300]).is_none());
+    }
+
+    #[test]
+    fn gpt_entries() {
+        let disk = gpt(512, 2, &[(ESP, 2048, 4095, "EFI"), ([0x11; 16], 4096, 8191, "")]);
+        let table = parse(&disk).unwrap();
+        assert_eq!(table.kind, TableKind::Gpt);
+        assert!(table.problems.is_empty(), "{:?}", table.problems);
+        assert_eq!(table.partitions.len(), 2);
+        let esp = &table.partitions[0];
+        assert_eq!((esp.start, esp.size), (1024 * 1024, 1024 * 1024));
+        assert_eq!(esp.type_guid.as_deref(), Some("C12A7328-F81F-11D2-BA4B-00A0C93EC93B"));
+        assert_eq!((esp.type_name.as_str(), esp.name.as_str()), ("EFI System", "EFI"));
+
+        let lines = table.describe();
+        assert_eq!(lines[0], "GPT, 2 partition(s)");
+        // Unknown types are shown by their GUID
+        assert!(lines[2].contains("11111111-1111-1111-1111-111111111111"), "{}", lines[2]);
+    }
+
+    #[test]
+    fn gpt_with_4096_byte_sectors() {
+        let disk = gpt(4096, 2, &[(ESP, 256, 511, "")]);
+        let table = parse(&disk).unwrap();
+        assert!(table.problems.is_empty(), "{:?}", table.problems);
+        assert_eq!(table.sector_size, 4096);
+        assert_eq!((table.partitions[0].start, table.partitions[0].size), (1024 * 1024, 1024 * 1024));
+        assert_eq!(table.describe()[0], "GPT, 1 partition(s), 4096 byte sectors");
+    }
+
+    #[test]
+    fn gpt_checksums_are_checked() {
+        let mut disk = gpt(512, 2, &[(ESP, 2048, 4095, "EFI")]);
+        disk[512 + 40] ^= 1;
+        assert_eq!(parse(&disk).unwrap().problems, ["GPT header checksum does not match"]);
+
+        let mut disk = gpt(512, 2, &[(ESP, 2048, 4095, "EFI")]);
+        disk[1024 + 60] ^= 1;
+        let table = parse(&disk).unwrap();
+        assert_eq!(table.problems, ["GPT partition array checksum does not match"]);
+        // The entries are still listed, the problem is shown next to them
+        assert_eq!(table.partitions.len(), 1);
+    }
+
+    #[test]
+    fn impossible_gpt_ranges() {
+        let disk = gpt(512, 2, &[(ESP, 4096, 2047, ""), (ESP, 0, u64::MAX, ""), (ESP, u64::MAX / 2, u64::MAX - 1, "")]);
+        let table = parse(&disk).unwrap();
+        assert!(table.partitions.is_empty());
+        assert_eq!(table.problems.len(), 3);
+        assert_eq!(table.problems[0], "GPT partition 1 has an impossible range (LBA 4096 to 2047)");
+    }
+
+    #[test]
+    fn protective_mbr_without_gpt() {
+        let mut head = vec![0u8; 1024];
+        mbr_entry(&mut head, 0, 0xEE, 1, u32::MAX);
+        let table = parse(&head).unwrap();
+        assert_eq!(table.kind, TableKind::Mbr);
+        assert_eq!(table.problems, ["protective MBR, but the GPT header is missing"]);
+    }
+
+    #[test]
+    fn arrays_past_the_first_mib_are_read() {
+        let disk = gpt(512, 4096, &[(ESP, 8192, 10239, "late")]);
+        assert!(disk.len() > HEAD);
+        let table = read_table(&disk[..]).unwrap().unwrap();
+        assert!(table.problems.is_empty(), "{:?}", table.problems);
+        assert_eq!(table.partitions[0].name, "late");
+
+        // Cut short, the array cannot be read
+        let table = read_table(&disk[..HEAD]).unwrap().unwrap();
+        assert_eq!(table.problems, ["GPT partition array could not be read"]);
+    }
+}
@@ -0,0 +1,199 @@
+//! pool.rs is a fixed set of block buffers that reader threads fill and writer threads drain
+//!
+//! A buffer goes back into the pool as soon as the last user drops it, and a reader that runs out
+//! of buffers waits for one to come back. That keeps the reader a few blocks ahead of the slowest
+//! writer without allocating a fresh 4 MB block every time, and keeps memory use fixed
+//!
+//! The buffers are page aligned (see devio.rs), so direct I/O can write straight out of them
+
+use std::io::{self, Read, Result};
+use std::ops::{Deref, DerefMut};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{Scope, ScopedJoinHandle};
+
+use crate::devio::AlignedBuf;
+use crate::image::read_full;
+
+/// Size of every block, 4 MB
+pub const BLOCK: usize = 4 * 1024 * 1024;
+/// How many blocks a pool holds, i.e. how far a reader can get ahead
+pub const BLOCKS: usize = 8;
+
+struct Shared {
+    free: Mutex<Vec<AlignedBuf>>,
+    returned: Condvar,
+}
+
+/// A fixed set of `BLOCK` sized buffers
+#[derive(Clone)]
+pub struct BufferPool {
+    shared: Arc<Shared>,
+}
+
+impl BufferPool {
+    pub fn new() -> BufferPool {
+        let free = (0..BLOCKS).map(|_| AlignedBuf::new(BLOCK)).collect();
+        BufferPool { shared: Arc::new(Shared { free: Mutex::new(free), returned: Condvar::new() }) }
+    }
+
+    /// Takes a buffer, waiting for one to be handed back if they are all in use
+    pub fn get(&self) -> PooledBuf {
+        let mut free = self.shared.free.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            if let Some(buf) = free.pop() {
+                return PooledBuf { buf: Some(buf), len: BLOCK, shared: Arc::clone(&self.shared) };
+            }
+            free = self.shared.returned.wait(free).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+/// A buffer on loan from a `BufferPool`, it goes back when dropped
+///
+/// It derefs to the filled part only, see `truncate`
+pub struct PooledBuf {
+    buf: Option<AlignedBuf>,
+    len: usize,
+    shared: Arc<Shared>,
+}
+
+impl PooledBuf {
+    /// Keeps only the first `len` bytes, e.g. after a short read at the end of the image
+    pub fn truncate(&mut self, len: usize) {
+        self.len = self.len.min(len);
+    }
+}
+
+impl Deref for PooledBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.buf.as_deref().map_or(&[], |b| &b[..self.len])
+    }
+}
+
+impl DerefMut for PooledBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        let len = self.len;
+        self.buf.as_deref_mut().map_or(&mut [], |b| &mut b[..len])
+    }
+}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        if let Some(buf) = self.buf.take() {
+            self.shared.free.lock().unwrap_or_else(|e| e.into_inner()).push(buf);
+            self.shared.returned.notify_one();
+        }
+    }
+}
+
+/// Reads `reader` block by block on its own thread, so whoever takes the blocks never waits for
+/// the disk unless it really is the slow one
+///
+/// The channel ends after the last (possibly short) block. A read error is sent as the last item.
+/// Dropping the receiver stops the thread
+pub fn read_ahead<'scope, R: Read + Send + 'scope>(
+    scope: &'scope Scope<'scope, '_>,
+    mut reader: R,
+) -> (Receiver<Result<PooledBuf>>, ScopedJoinHandle<'scope, ()>) {
+    let pool = BufferPool::new();
+    let (tx, rx) = mpsc::sync_channel(BLOCKS);
+
+    let handle = scope.spawn(move || {
+        loop {
+            let mut buf = pool.get();
+            let block = match read_full(&mut reader, &mut buf) {
+                Ok(0) => return,
+                Ok(n) => {
+                    buf.truncate(n);
+                    Ok(buf)
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => Err(e),
+            };
+            let failed = block.is_err();
+            if tx.send(block).is_err() || failed {
+                return;
+            }
+        }
+    });
+    (rx, handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    /// Hands out `good` bytes, then fails
+    struct Failing {
+        good: usize,
+    }
+
+    impl Read for Failing {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            if self.good == 0 {
+                return Err(io::Error::other("bad sector"));
+            }
+            let n = buf.len().min(self.good);
+            buf[..n].fill(1);
+            self.good -= n;
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn blocks_come_back_in_order_with_a_short_last_one() {
+        let data: Vec<u8> = (0..2 * BLOCK + 100).map(|i| (i % 251) as u8).collect();
+        let read: Vec<u8> = thread::scope(|scope| {
+            let (blocks, _) = read_ahead(scope, &data[..]);
+            let blocks: Vec<PooledBuf> = blocks.into_iter().map(|b| b.unwrap()).collect();
+            assert_eq!(blocks.iter().map(|b| b.len()).collect::<Vec<_>>(), [BLOCK, BLOCK, 100]);
+            blocks.iter().flat_map(|b| b.iter().copied()).collect()
+        });
+        assert!(read == data);
+    }
+
+    #[test]
+    fn a_read_error_is_the_last_item() {
+        thread::scope(|scope| {
+            let (blocks, handle) = read_ahead(scope, Failing { good: BLOCK + 10 });
+            let blocks: Vec<Result<PooledBuf>> = blocks.into_iter().collect();
+            // The bytes read before the error in the same block are not handed on
+            assert_eq!(blocks.len(), 2);
+            assert_eq!(blocks[0].as_ref().unwrap().len(), BLOCK);
+            assert_eq!(blocks[1].as_ref().err().unwrap().to_string(), "bad sector");
+            handle.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn buffers_go_back_to_the_pool() {
+        let pool = BufferPool::new();
+        let mut taken: Vec<PooledBuf> = (0..BLOCKS).map(|_| pool.get()).collect();
+        taken[0].truncate(10);
+        assert_eq!(taken[0].len(), 10);
+
+        // With every buffer out, `get` waits until one is dropped
+        let waiting = {
+            let pool = pool.clone();
+            thread::spawn(move || pool.get().len())
+        };
+        drop(taken.pop());
+        // A buffer coming back is whole again
+        assert_eq!(waiting.join().unwrap(), BLOCK);
+    }
+
+    #[test]
+    fn dropping_the_receiver_stops_the_reader() {
+        let data = vec![0u8; (BLOCKS + 4) * BLOCK];
+        thread::scope(|scope| {
+            let (blocks, handle) = read_ahead(scope, &data[..]);
+            drop(blocks.recv().unwrap());
+            drop(blocks);
+            handle.join().unwrap();
+        });
+    }
+}
@@ -0,0 +1,535 @@
+//! probe.rs works out what is on a drive right now, blkid-style, so the final warning can say
+//! what is about to be destroyed rather than just "all disk contents"
+//!
+//! Each partition (or the whole drive, if it has no partition table) is checked for the filesystems
+//! that turn up on USB sticks and SD cards: FAT12/16/32, exFAT, NTFS, ext2/3/4, ISO9660 and swap
+//! The label and, where the filesystem keeps count, the space in use are read from its superblock
+//!
+//! Reads are always whole 4 KiB blocks at 4 KiB offsets, which raw disks on Windows insist on
+//!
+//! Everything read off the drive is treated as untrusted: the user may have picked a drive holding
+//! anything at all, so sizes and offsets are checked before they are used and never trusted to fit
+
+use std::fs::File;
+use std::io::{ErrorKind, Result, Seek, SeekFrom};
+
+use crate::image::read_full;
+use crate::parts;
+use crate::units;
+
+/// Raw disk reads are done in whole blocks of this size
+const BLOCK: u64 = 4096;
+/// The most that is read of a FAT16 table, the largest one there can be (65525 clusters, 2 bytes each)
+const MAX_FAT16: u64 = 128 * 1024;
+
+/// A filesystem found on the drive
+#[derive(Debug, Clone)]
+pub struct Filesystem {
+    /// e.g. "FAT32", "ext4"
+    pub kind: &'static str,
+    pub label: Option<String>,
+    /// Size of the filesystem in bytes
+    pub size: Option<u64>,
+    /// Bytes in use, if the filesystem keeps count
+    pub used: Option<u64>,
+}
+
+/// One area of the drive that was probed: a partition, or the whole drive
+#[derive(Debug, Clone)]
+pub struct Area {
+    /// Partition number, `None` for the whole drive
+    pub partition: Option<usize>,
+    /// Size of the partition (or drive) in bytes, if known
+    pub size: Option<u64>,
+    /// The filesystem on it, `None` if nothing was recognised
+    pub filesystem: Option<Filesystem>,
+}
+
+impl Area {
+    /// e.g. "FAT32 'BACKUPS' 28.00 GiB (3.10 GiB used)"
+    pub fn describe(&self) -> String {
+        let Some(fs) = &self.filesystem else {
+            return match self.size {
+                Some(size) => format!("unknown contents {}", units::human_size(size)),
+                None => "unknown contents".to_string(),
+            };
+        };
+
+        let mut text = fs.kind.to_string();
+        if let Some(label) = &fs.label {
+            text.push_str(&format!(" '{}'", label));
+        }
+        if let Some(size) = fs.size.or(self.size) {
+            text.push_str(&format!(" {}", units::human_size(size)));
+        }
+        if let Some(used) = fs.used {
+            text.push_str(&format!(" ({} used)", units::human_size(used)));
+        }
+        text
+    }
+}
+
+/// One line summary of what was found, e.g. "2 partitions: FAT32 'BACKUPS' 28.00 GiB, ext4 'rootfs' 4.00 GiB"
+pub fn summary(areas: &[Area]) -> String {
+    let described: Vec<String> = areas.iter().map(Area::describe).collect();
+    match areas {
+        [] => "nothing recognisable, the drive looks empty".to_string(),
+        [area] if area.partition.is_none() => described[0].clone(),
+        [_] => format!("1 partition: {}", described[0]),
+        _ => format!("{} partitions: {}", areas.len(), described.join(", ")),
+    }
+}
+
+/// Reads a drive in whole, aligned blocks
+struct Disk {
+    file: File,
+}
+
+impl Disk {
+    /// Reads `len` bytes at `offset`, going through whole blocks, short at the end of the drive
+    ///
+    /// Nothing comes back for an offset past anything a drive could hold
+    fn read(&mut self, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let Some(end) = offset.checked_add(len as u64).and_then(|end| end.checked_next_multiple_of(BLOCK)) else {
+            return Ok(Vec::new());
+        };
+        let start = offset / BLOCK * BLOCK;
+        let mut buf = vec![0u8; (end - start) as usize];
+
+        match self.file.seek(SeekFrom::Start(start)) {
+            // Drives refuse to seek past their end, as do filesystems past their largest file
+            Err(e) if e.kind() == ErrorKind::InvalidInput => return Ok(Vec::new()),
+            seeked => seeked?,
+        };
+        let n = read_full(&mut self.file, &mut buf)?;
+        buf.truncate(n);
+
+        let skip = (offset - start) as usize;
+        if skip >= buf.len() {
+            return Ok(Vec::new());
+        }
+        let mut wanted = buf.split_off(skip);
+        wanted.truncate(len);
+        Ok(wanted)
+    }
+}
+
+fn u16_at(b: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([b[at], b[at + 1]])
+}
+
+fn u32_at(b: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(b[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(b: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(b[at..at + 8].try_into().unwrap())
+}
+
+/// A space or nul padded label, `None` if it is blank
+fn label(field: &[u8]) -> Option<String> {
+    let text = String::from_utf8_lossy(field).trim_end_matches([' ', '\0']).to_string();
+    (!text.is_empty()).then_some(text)
+}
+
+/// A UTF-16LE label, `None` if it is blank
+fn label_utf16(field: &[u8]) -> Option<String> {
+    let chars: Vec<u16> = field.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).take_while(|&c| c != 0).collect();
+    label(String::from_utf16_lossy(&chars).as_bytes())
+}
+
+/// Probes every partition on the drive, or the whole drive if it has no partition table
+pub fn probe_device(path: &str) -> Result<Vec<Area>> {
+    let mut disk = Disk { file: File::open(path)? };
+
+    // A drive an ISO was flashed to has a filesystem over the whole drive, even if it has a table too
+    if let Some(fs) = probe_iso9660(&mut disk, 0)? {
+        return Ok(vec![Area { partition: None, size: fs.size, filesystem: Some(fs) }]);
+    }
+
+    let head = disk.read(0, 1024 * 1024)?;
+    let Some(table) = parts::read_table(&head[..])? else {
+        // No table: the drive may be formatted as a whole ("superfloppy"), or be blank
+        return Ok(match probe(&mut disk, 0)? {
+            Some(fs) => vec![Area { partition: None, size: fs.size, filesystem: Some(fs) }],
+            None => Vec::new(),
+        });
+    };
+
+    let mut areas = Vec::new();
+    for part in &table.partitions {
+        // Containers for other partitions, nothing to find in them directly
+        if matches!(part.mbr_type, Some(0x05 | 0x0F | 0x85 | 0xEE)) {
+            continue;
+        }
+        areas.push(Area { partition: Some(part.number), size: Some(part.size), filesystem: probe(&mut disk, part.start)? });
+    }
+    Ok(areas)
+}
+
+/// Checks the start of a partition for every filesystem this knows about
+fn probe(disk: &mut Disk, start: u64) -> Result<Option<Filesystem>> {
+    let boot = disk.read(start, 4096)?;
+    if boot.len() < 4096 {
+        return Ok(None);
+    }
+
+    if &boot[3..11] == b"EXFAT   " {
+        return probe_exfat(disk, start, &boot).map(Some);
+    }
+    if &boot[3..11] == b"NTFS    " {
+        return probe_ntfs(disk, start, &boot).map(Some);
+    }
+    // FAT boot sectors start with a jump over the parameter block
+    if boot[510..512] == [0x55, 0xAA] && matches!(boot[0], 0xEB | 0xE9) && let Some(fs) = probe_fat(disk, start, &boot)? {
+        return Ok(Some(fs));
+    }
+    // ext2/3/4 superblock sits 1024 bytes in, its magic 0x38 bytes into that
+    if u16_at(&boot, 1024 + 0x38) == 0xEF53 {
+        return Ok(Some(probe_ext(&boot[1024..2048])));
+    }
+    if &boot[4086..4096] == b"SWAPSPACE2" {
+        let size = (u32_at(&boot, 1024 + 4) as u64 + 1) * 4096;
+        return Ok(Some(Filesystem { kind: "swap", label: label(&boot[1024 + 28..1024 + 44]), size: Some(size), used: None }));
+    }
+    probe_iso9660(disk, start)
+}
+
+/// ISO9660: the primary volume descriptor is in sector 16
+fn probe_iso9660(disk: &mut Disk, start: u64) -> Result<Option<Filesystem>> {
+    let pvd = disk.read(start.saturating_add(16 * 2048), 2048)?;
+    if pvd.len() < 2048 || pvd[0] != 1 || &pvd[1..6] != b"CD001" {
+        return Ok(None);
+    }
+    let size = u32_at(&pvd, 80) as u64 * u16_at(&pvd, 128) as u64;
+    Ok(Some(Filesystem { kind: "ISO9660", label: label(&pvd[40..72]), size: (size > 0).then_some(size), used: None }))
+}
+
+/// ext2/3/4, told apart by their feature flags
+fn probe_ext(sb: &[u8]) -> Filesystem {
+    let (compat, incompat) = (u32_at(sb, 0x5C), u32_at(sb, 0x60));
+    let kind = if incompat & (0x40 | 0x80 | 0x200) != 0 {
+        // extents, 64bit or flex_bg
+        "ext4"
+    } else if compat & 0x4 != 0 {
+        // has_journal
+        "ext3"
+    } else {
+        "ext2"
+    };
+
+    // ext4 blocks go up to 64 KiB, anything bigger is a damaged superblock
+    let block_size = 1024u64 << u32_at(sb, 0x18).min(6);
+    let is_64bit = incompat & 0x80 != 0;
+    let blocks = u32_at(sb, 0x04) as u64 | if is_64bit { (u32_at(sb, 0x150) as u64) << 32 } else { 0 };
+    let free = u32_at(sb, 0x0C) as u64 | if is_64bit { (u32_at(sb, 0x158) as u64) << 32 } else { 0 };
+
+    Filesystem {
+        kind,
+        label: label(&sb[0x78..0x88]),
+        size: blocks.checked_mul(block_size),
+        used: blocks.saturating_sub(free).checked_mul(block_size),
+    }
+}
+
+/// FAT12/16/32, going by the BIOS parameter block
+fn probe_fat(disk: &mut Disk, start: u64, boot: &[u8]) -> Result<Option<Filesystem>> {
+    let bytes_per_sector = u16_at(boot, 11) as u64;
+    let sectors_per_cluster = boot[13] as u64;
+    if ![512, 1024, 2048, 4096].contains(&bytes_per_sector) || !sectors_per_cluster.is_power_of_two() {
+        return Ok(None);
+    }
+
+    let reserved = u16_at(boot, 14) as u64;
+    let fats = boot[16] as u64;
+    let root_entries = u16_at(boot, 17) as u64;
+    let total = match u16_at(boot, 19) {
+        0 => u32_at(boot, 32) as u64,
+        n => n as u64,
+    };
+    let fat_size = match u16_at(boot, 22) {
+        0 => u32_at(boot, 36) as u64,
+        n => n as u64,
+    };
+    if fats == 0 || fat_size == 0 || total == 0 {
+        return Ok(None);
+    }
+
+    // FAT32 is the one with no 16-bit FAT size, between FAT12 and FAT16 the cluster count decides,
+    // whatever the "FAT16" text in the boot sector says
+    let root_sectors = (root_entries * 32).div_ceil(bytes_per_sector);
+    let data_start = reserved + fats * fat_size + root_sectors;
+    let clusters = total.saturating_sub(data_start) / sectors_per_cluster;
+    let cluster_size = sectors_per_cluster * bytes_per_sector;
+
+    let (kind, label_at) = if u16_at(boot, 22) == 0 {
+        ("FAT32", 71)
+    } else if clusters < 4085 {
+        ("FAT12", 43)
+    } else {
+        ("FAT16", 43)
+    };
+    // Formatters write "NO NAME" when there is no label
+    let name = label(&boot[label_at..label_at + 11]).filter(|l| l != "NO NAME");
+
+    let free = match kind {
+        // FAT32 keeps a free cluster count in the FSInfo sector, 0xFFFFFFFF means unknown
+        "FAT32" => {
+            let info = disk.read(start.saturating_add(u16_at(boot, 48) as u64 * bytes_per_sector), 512)?;
+            let valid = info.len() == 512 && &info[0..4] == b"RRaA" && &info[484..488] == b"rrAa";
+            let free = if valid { u32_at(&info, 488) } else { u32::MAX };
+            (free != u32::MAX && free as u64 <= clusters).then_some(free as u64)
+        }
+        // FAT16 tables are at most 128 KiB, so just count the free entries. More clusters than
+        // FAT16 can have means the boot sector is not to be trusted, so the count is left out
+        "FAT16" if (clusters + 2) * 2 <= MAX_FAT16 => {
+            let fat = disk.read(start.saturating_add(reserved * bytes_per_sector), ((clusters + 2) * 2) as usize)?;
+            Some(fat.chunks_exact(2).skip(2).filter(|e| e == &[0, 0]).count() as u64)
+        }
+        _ => None,
+    };
+
+    Ok(Some(Filesystem {
+        kind,
+        label: name,
+        size: Some(total * bytes_per_sector),
+        used: free.map(|free| clusters.saturating_sub(free) * cluster_size),
+    }))
+}
+
+/// exFAT keeps its label in the root directory, rather than the boot sector
+fn probe_exfat(disk: &mut Disk, start: u64, boot: &[u8]) -> Result<Filesystem> {
+    let bytes_per_sector = 1u64 << boot[108].min(12);
+    let sectors_per_cluster = 1u64 << boot[109].min(25 - boot[108].min(12));
+    let cluster_size = bytes_per_sector * sectors_per_cluster;
+    let size = u64_at(boot, 72).checked_mul(bytes_per_sector);
+
+    // Percent in use, 0xFF means unknown
+    let used = size.filter(|_| boot[112] <= 100).map(|size| size / 100 * boot[112] as u64);
+
+    let heap = u32_at(boot, 88) as u64 * bytes_per_sector;
+    let root = u32_at(boot, 96) as u64;
+    let dir_at = start.saturating_add(heap).saturating_add(root.saturating_sub(2) * cluster_size);
+    let dir = disk.read(dir_at, cluster_size.min(64 * 1024) as usize)?;
+
+    let mut name = None;
+    for entry in dir.chunks_exact(32) {
+        match entry[0] {
+            // End of directory
+            0x00 => break,
+            // Volume label: character count, then up to 11 UTF-16 characters
+            0x83 => {
+                let count = (entry[1] as usize).min(11);
+                name = label_utf16(&entry[2..2 + count * 2]);
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    Ok(Filesystem { kind: "exFAT", label: name, size, used })
+}
+
+/// NTFS keeps its label as an attribute of $Volume, which is MFT record 3
+fn probe_ntfs(disk: &mut Disk, start: u64, boot: &[u8]) -> Result<Filesystem> {
+    let bytes_per_sector = u16_at(boot, 11) as u64;
+    // Big clusters are given as a negative power of two
+    let sectors_per_cluster = match boot[13] {
+        n if n > 0x80 => 1u64 << (256 - n as u64).min(31),
+        n => n as u64,
+    };
+    let cluster_size = bytes_per_sector * sectors_per_cluster;
+    let size = u64_at(boot, 40).checked_mul(bytes_per_sector);
+
+    let record_size = match boot[64] as i8 {
+        n if n < 0 => 1u64 << (-(n as i64)).min(20),
+        n => n as u64 * cluster_size,
+    };
+
+    let filesystem = Filesystem { kind: "NTFS", label: None, size, used: None };
+    // A record holds at least one whole sector, which the update sequence below relies on
+    let sane = [512, 1024, 2048, 4096].contains(&bytes_per_sector) && (bytes_per_sector..=64 * 1024).contains(&record_size);
+    let record_at = u64_at(boot, 48)
+        .checked_mul(cluster_size)
+        .and_then(|mft| mft.checked_add(3 * record_size))
+        .and_then(|at| at.checked_add(start));
+    let Some(record_at) = record_at.filter(|_| sane) else {
+        return Ok(filesystem);
+    };
+
+    let mut record = disk.read(record_at, record_size as usize)?;
+    if record.len() < record_size as usize || &record[0..4] != b"FILE" {
+        return Ok(filesystem);
+    }
+
+    // Undo the "update sequence": the last two bytes of each sector were swapped out on write
+    let (usa, usa_count) = (u16_at(&record, 4) as usize, u16_at(&record, 6) as usize);
+    for i in 1..usa_count {
+        let at = i * bytes_per_sector as usize - 2;
+        if usa + i * 2 + 2 > record.len() || at + 2 > record.len() {
+            break;
+        }
+        let fixed = [record[usa + i * 2], record[usa + i * 2 + 1]];
+        record[at..at + 2].copy_from_slice(&fixed);
+    }
+
+    // Walk the attributes for $VOLUME_NAME (0x60), which is always resident
+    let mut at = u16_at(&record, 20) as usize;
+    while at + 24 <= record.len() {
+        let (kind, length) = (u32_at(&record, at), u32_at(&record, at + 4) as usize);
+        if kind == 0xFFFF_FFFF || length == 0 {
+            break;
+        }
+        if kind == 0x60 {
+            let len = u32_at(&record, at + 16) as usize;
+            let offset = at + u16_at(&record, at + 20) as usize;
+            if offset + len <= record.len() {
+                return Ok(Filesystem { label: label_utf16(&record[offset..offset + len]), ..filesystem });
+            }
+            break;
+        }
+        at += length;
+    }
+    Ok(filesystem)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempPath;
+
+    fn probe_bytes(name: &str, disk: &[u8]) -> Vec<Area> {
+        let path = TempPath::file(name, disk);
+        probe_device(path.as_str()).unwrap()
+    }
+
+    /// A FAT32 boot sector with an FSInfo sector saying `free` clusters are free
+    fn fat32(label: &[u8; 11], free: u32) -> Vec<u8> {
+        let mut disk = vec![0u8; 64 * 1024];
+        disk[0] = 0xEB;
+        disk[11..13].copy_from_slice(&512u16.to_le_bytes());
+        disk[13] = 8;
+        disk[14..16].copy_from_slice(&32u16.to_le_bytes());
+        disk[16] = 2;
+        disk[32..36].copy_from_slice(&(1024 * 1024u32).to_le_bytes());
+        disk[36..40].copy_from_slice(&1024u32.to_le_bytes());
+        disk[48..50].copy_from_slice(&1u16.to_le_bytes());
+        disk[71..82].copy_from_slice(label);
+        disk[510..512].copy_from_slice(&[0x55, 0xAA]);
+
+        let info = &mut disk[512..1024];
+        info[0..4].copy_from_slice(b"RRaA");
+        info[484..488].copy_from_slice(b"rrAa");
+        info[488..492].copy_from_slice(&free.to_le_bytes());
+        disk
+    }
+
+    /// An ext4 superblock, 1 KiB blocks
+    fn ext4(label: &str, blocks: u32, free: u32) -> Vec<u8> {
+        let mut fs = vec![0u8; 8192];
+        let sb = &mut fs[1024..2048];
+        sb[0x04..0x08].copy_from_slice(&blocks.to_le_bytes());
+        sb[0x0C..0x10].copy_from_slice(&free.to_le_bytes());
+        sb[0x38..0x3A].copy_from_slice(&0xEF53u16.to_le_bytes());
+        sb[0x60..0x64].copy_from_slice(&0x40u32.to_le_bytes());
+        sb[0x78..0x78 + label.len()].copy_from_slice(label.as_bytes());
+        fs
+    }
+
+    #[test]
+    fn whole_drive_fat32() {
+        let areas = probe_bytes("fat32.img", &fat32(b"BACKUPS    ", 100_000));
+        assert_eq!(areas.len(), 1);
+        let fs = areas[0].filesystem.as_ref().unwrap();
+        assert_eq!((fs.kind, fs.label.as_deref()), ("FAT32", Some("BACKUPS")));
+        assert_eq!(fs.size, Some(512 * 1024 * 1024));
+        // Clusters from the end of the FATs, less the free ones, 4 KiB each
+        let clusters = (1024 * 1024 - 32 - 2 * 1024) / 8;
+        assert_eq!(fs.used, Some((clusters - 100_000) * 4096));
+        assert_eq!(summary(&areas), format!("FAT32 'BACKUPS' 512.00 MiB ({} used)", units::human_size(fs.used.unwrap())));
+
+        // "NO NAME" is no label, and an unknown free count no usage
+        let areas = probe_bytes("noname.img", &fat32(b"NO NAME    ", u32::MAX));
+        let fs = areas[0].filesystem.as_ref().unwrap();
+        assert_eq!((fs.label.as_deref(), fs.used), (None, None));
+    }
+
+    #[test]
+    fn partitions_are_probed_one_by_one() {
+        let mut disk = vec![0u8; 2048 * 512 + 8192];
+        disk[446 + 4] = 0x83;
+        disk[446 + 8..446 + 12].copy_from_slice(&2048u32.to_le_bytes());
+        disk[446 + 12..446 + 16].copy_from_slice(&16u32.to_le_bytes());
+        // An extended partition has nothing to show for itself
+        disk[462 + 4] = 0x05;
+        disk[462 + 12..462 + 16].copy_from_slice(&1u32.to_le_bytes());
+        disk[510..512].copy_from_slice(&[0x55, 0xAA]);
+        disk[2048 * 512..].copy_from_slice(&ext4("rootfs", 4096, 1024));
+
+        let areas = probe_bytes("mbr.img", &disk);
+        assert_eq!(areas.len(), 1);
+        assert_eq!(areas[0].partition, Some(1));
+        let fs = areas[0].filesystem.as_ref().unwrap();
+        assert_eq!((fs.kind, fs.label.as_deref()), ("ext4", Some("rootfs")));
+        assert_eq!((fs.size, fs.used), (Some(4 * 1024 * 1024), Some(3 * 1024 * 1024)));
+        assert_eq!(summary(&areas), "1 partition: ext4 'rootfs' 4.00 MiB (3.00 MiB used)");
+    }
+
+    #[test]
+    fn blank_drives_have_nothing() {
+        assert!(probe_bytes("blank.img", &[0u8; 64 * 1024]).is_empty());
+        assert!(probe_bytes("tiny.img", &[0xEB; 100]).is_empty());
+        assert_eq!(summary(&[]), "nothing recognisable, the drive looks empty");
+    }
+
+    #[test]
+    fn reads_past_any_drive_come_back_empty() {
+        let path = TempPath::file("read.img", [7u8; 5000]);
+        let mut disk = Disk { file: File::open(&path).unwrap() };
+        assert_eq!(disk.read(4990, 20).unwrap(), [7u8; 10]);
+        assert!(disk.read(u64::MAX - 10, 4096).unwrap().is_empty());
+        assert!(disk.read(1 << 40, 4096).unwrap().is_empty());
+        assert!(disk.read(i64::MAX as u64 + 1, 512).unwrap().is_empty());
+    }
+
+    #[test]
+    fn hostile_boot_sectors_do_not_panic() {
+        // Random parameter blocks behind each signature, with the fields that steer reads set to extremes
+        let mut seed = 0x2545_F491_4F6C_DD1Du64;
+        let mut next = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+        for round in 0..200 {
+            let mut disk: Vec<u8> = (0..16 * 1024).map(|_| next() as u8).collect();
+            disk[510..512].copy_from_slice(&[0x55, 0xAA]);
+            match round % 4 {
+                0 => disk[3..11].copy_from_slice(b"EXFAT   "),
+                1 => {
+                    disk[3..11].copy_from_slice(b"NTFS    ");
+                    disk[11..13].copy_from_slice(&512u16.to_le_bytes());
+                    disk[13] = 1;
+                    disk[48..56].copy_from_slice(&(next() % 24).to_le_bytes());
+                    disk[64] = 0xF6;
+                    // An MFT record full of junk, with the signature it needs
+                    let at = u64_at(&disk, 48) as usize * 512 + 3 * 1024;
+                    if at + 4 <= disk.len() {
+                        disk[at..at + 4].copy_from_slice(b"FILE");
+                    }
+                }
+                2 => {
+                    disk[0] = 0xEB;
+                    disk[11..13].copy_from_slice(&512u16.to_le_bytes());
+                    disk[13] = 1 << (next() % 8);
+                }
+                _ => disk[1024 + 0x38..1024 + 0x3A].copy_from_slice(&0xEF53u16.to_le_bytes()),
+            }
+            let areas = probe_bytes("hostile.img", &disk);
+            for area in &areas {
+                area.describe();
+            }
+        }
+    }
+}
@@ -0,0 +1,228 @@
+//! progress.rs is the progress line shown while flashing and verifying, e.g.
+//!
+//! ```text
+//! [██████████░░░░░░░░░░]    1.20 GiB / 2.40 GiB     38.2 MiB/s  avg    35.1 MiB/s  ETA  0:35
+//! ```
+//!
+//! The current speed is taken over the last few seconds, the average over the whole run. Both phases
+//! use the same widget so they look and behave the same
+
+use std::collections::VecDeque;
+use std::io::{IsTerminal, Result, Write, stdout};
+use std::time::{Duration, Instant};
+
+use crate::units;
+
+/// How far back the current speed looks
+const WINDOW: Duration = Duration::from_secs(3);
+/// `print` redraws at most this often
+const REDRAW: Duration = Duration::from_millis(100);
+/// When the output is not a terminal (e.g. a CI log) a plain line is printed this often instead
+pub const LOG_EVERY: Duration = Duration::from_secs(5);
+/// The bar is never wider than this, and left out if there is less room than `MIN_BAR`
+const MAX_BAR: usize = 30;
+const MIN_BAR: usize = 10;
+/// Room kept for the numbers after the bar, the usual length of the line without it
+const STATS: usize = 64;
+
+pub struct Progress {
+    total: Option<u64>,
+    /// How far along it is when `total` is not known, e.g. from how much of a compressed file was read
+    estimate: Option<Box<dyn Fn() -> f64 + Send>>,
+    start: Instant,
+    /// Bytes that were done before this run started (carrying on an earlier flash), left out of the speed
+    resumed: u64,
+    /// Recent (time, bytes done this run) readings, for the current speed
+    samples: VecDeque<(Instant, u64)>,
+    last_drawn: Option<Instant>,
+}
+
+impl Progress {
+    /// A fresh progress line, the clock starts now
+    pub fn new(total: Option<u64>) -> Progress {
+        Progress { total, estimate: None, start: Instant::now(), resumed: 0, samples: VecDeque::new(), last_drawn: None }
+    }
+
+    /// Carrying on from `done` bytes of an earlier run: they show on the bar, but the speed and the
+    /// ETA only go by what this run does
+    pub fn resumed_from(mut self, done: u64) -> Progress {
+        self.resumed = done;
+        self
+    }
+
+    /// Where the total is not known up front (a compressed image with no size in its header),
+    /// `fraction` says how far along it is, from 0.0 to 1.0, for the bar and the ETA
+    pub fn estimated_by(mut self, fraction: impl Fn() -> f64 + Send + 'static) -> Progress {
+        self.estimate = Some(Box::new(fraction));
+        self
+    }
+
+    /// The line for `done` bytes, fitted into `width` columns
+    pub fn line(&mut self, done: u64, width: usize) -> String {
+        let now = Instant::now();
+        let moved = done.saturating_sub(self.resumed);
+        self.samples.push_back((now, moved));
+        // Keep one reading from before the window, as the starting point
+        while self.samples.len() > 2 && self.samples[1].0 + WINDOW <= now {
+            self.samples.pop_front();
+        }
+
+        let fraction = match (self.total, &self.estimate) {
+            (Some(total), _) if total > 0 => Some((done as f64 / total as f64).min(1.0)),
+            (Some(_), _) => Some(1.0),
+            (None, Some(estimate)) => Some(estimate().clamp(0.0, 1.0)),
+            (None, None) => None,
+        };
+        let finished = fraction.is_some_and(|f| f >= 1.0);
+
+        let elapsed = now.duration_since(self.start).as_secs_f64();
+        let average = if elapsed > 0.0 { moved as f64 / elapsed } else { 0.0 };
+        let (since, from) = self.samples[0];
+        let window = now.duration_since(since).as_secs_f64();
+        let current = if window > 0.0 { moved.saturating_sub(from) as f64 / window } else { average };
+
+        // Fixed widths, so the bar does not jump about as the numbers change
+        let mut stats = match self.total {
+            Some(total) => format!("{:>10} / {}", units::human_size(done), units::human_size(total)),
+            None => format!("{:>10}", units::human_size(done)),
+        };
+        if finished {
+            stats += &format!("  avg {}", rate(average));
+        } else {
+            stats += &format!("  {:>11}  avg {:>11}", rate(current), rate(average));
+        }
+
+        // Going by the current speed, or the average while there is no current one yet
+        let speed = if current > 0.0 { current } else { average };
+        let left = match (self.total, fraction) {
+            _ if finished => None,
+            (Some(total), _) if speed > 0.0 => Some(total.saturating_sub(done) as f64 / speed),
+            // With only an estimate, the time left goes by how long it took to get this far
+            (None, Some(fraction)) if fraction > 0.0 => Some(elapsed / fraction - elapsed),
+            _ => None,
+        };
+        if let Some(left) = left {
+            stats += &format!("  ETA {:>5}", eta(left));
+        }
+
+        let room = width.saturating_sub(stats.chars().count().max(STATS) + 4).min(MAX_BAR);
+        match fraction {
+            Some(fraction) if room >= MIN_BAR => {
+                let filled = ((fraction * room as f64) as usize).min(room);
+                format!("[{}{}]  {}", "█".repeat(filled), "░".repeat(room - filled), stats)
+            }
+            _ => stats,
+        }
+    }
+
+    /// Redraws the line in place on the terminal, at most every `REDRAW`
+    ///
+    /// If the output is not a terminal, prints it as a line of its own every `LOG_EVERY` instead
+    pub fn print(&mut self, done: u64) -> Result<()> {
+        let now = Instant::now();
+        let every = if stdout().is_terminal() { REDRAW } else { LOG_EVERY };
+        if self.last_drawn.is_some_and(|last| now.duration_since(last) < every) {
+            return Ok(());
+        }
+        self.last_drawn = Some(now);
+        self.draw(done)
+    }
+
+    /// Draws the line a last time, so it shows where things ended, and moves on to the next line
+    pub fn finish(&mut self, done: u64) -> Result<()> {
+        self.draw(done)?;
+        if stdout().is_terminal() {
+            // "\r" too, the menus keep the terminal in raw mode
+            print!("\r\n");
+        }
+        stdout().flush()
+    }
+
+    fn draw(&mut self, done: u64) -> Result<()> {
+        let line = self.line(done, terminal_width());
+        if !stdout().is_terminal() {
+            println!("{}", line);
+            return Ok(());
+        }
+        print!("\r{}\x1B[K", line);
+        stdout().flush()
+    }
+}
+
+/// Columns the terminal has, 80 if that cannot be found out (e.g. output to a file)
+pub fn terminal_width() -> usize {
+    crossterm::terminal::size().map_or(80, |(columns, _)| columns as usize)
+}
+
+/// Bytes per second as e.g. "38.2 MiB/s"
+fn rate(bytes_per_second: f64) -> String {
+    let size = units::human_size(bytes_per_second as u64);
+    // One decimal is plenty for a speed that changes all the time
+    match size.split_once(' ') {
+        Some((value, unit)) if value.contains('.') => format!("{} {}/s", &value[..value.len() - 1], unit),
+        _ => format!("{}/s", size),
+    }
+}
+
+/// Seconds as "1:23" or "1:02:03"
+fn eta(seconds: f64) -> String {
+    let seconds = seconds.max(0.0).round() as u64;
+    match seconds / 3600 {
+        0 => format!("{}:{:02}", seconds / 60, seconds % 60),
+        hours => format!("{}:{:02}:{:02}", hours, seconds / 60 % 60, seconds % 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eta_is_minutes_or_hours() {
+        assert_eq!(eta(0.0), "0:00");
+        assert_eq!(eta(-5.0), "0:00");
+        assert_eq!(eta(59.6), "1:00");
+        assert_eq!(eta(83.0), "1:23");
+        assert_eq!(eta(3723.0), "1:02:03");
+    }
+
+    #[test]
+    fn rate_keeps_one_decimal() {
+        assert_eq!(rate(0.0), "0 B/s");
+        assert_eq!(rate(38.25 * 1024.0 * 1024.0), "38.2 MiB/s");
+    }
+
+    #[test]
+    fn line_fits_the_width() {
+        let mut progress = Progress::new(Some(100 << 20));
+        let wide = progress.line(50 << 20, 120);
+        assert!(wide.starts_with('[') && wide.contains("50.00 MiB / 100.00 MiB"), "{}", wide);
+        // No room for a bar, just the numbers
+        let narrow = progress.line(50 << 20, 40);
+        assert!(narrow.starts_with(' ') && !narrow.contains('█'), "{}", narrow);
+    }
+
+    #[test]
+    fn finished_line_has_no_eta() {
+        let mut progress = Progress::new(Some(4096));
+        let line = progress.line(4096, 120);
+        assert!(line.contains("avg") && !line.contains("ETA") && !line.contains('░'), "{}", line);
+    }
+
+    #[test]
+    fn resumed_bytes_do_not_count_towards_the_speed() {
+        let mut progress = Progress::new(Some(100 << 20)).resumed_from(90 << 20);
+        let line = progress.line(90 << 20, 120);
+        assert!(line.contains("90.00 MiB / 100.00 MiB") && line.ends_with("avg       0 B/s"), "{}", line);
+        // No speed yet, so no ETA either
+        assert!(!line.contains("ETA"), "{}", line);
+    }
+
+    #[test]
+    fn estimate_fills_the_bar_without_a_total() {
+        let mut progress = Progress::new(None).estimated_by(|| 0.5);
+        let line = progress.line(1 << 20, 120);
+        assert_eq!(line.matches('█').count(), MAX_BAR / 2, "{}", line);
+        assert!(!line.contains(" / "), "{}", line);
+    }
+}
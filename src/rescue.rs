@@ -0,0 +1,470 @@
+//! rescue.rs saves the first and last few MiB of a drive before it is flashed, so a flash to the
+//! wrong drive can be undone well enough for data-recovery tools to find the old partitions again
+//!
+//! The start holds the MBR or primary GPT and most filesystems' superblocks, the end holds the
+//! backup GPT. Everything in between is lost for good once the image is written over it
+//!
+//! The rescue files go in the user's data directory, named after the drive's serial number and
+//! the time, e.g. ~/.local/share/tetcher/rescue/4C530001230101-20260317-142501.rescue, with a -2,
+//! -3... on the end for more saves of the same drive within one second
+//! Only the newest `KEEP` of each drive are kept, older ones are deleted as new ones are saved
+//!
+//! A drive whose rescue file cannot be saved is not flashed, unless the user says to go ahead
+//! without one (--no-rescue, or the question the menus ask)
+//!
+//! A rescue file is a short text header followed by the raw bytes:
+//!
+//! ```text
+//! TETCHER-RESCUE 1
+//! device: /dev/sdb
+//! ...
+//! <blank line>
+//! <head bytes><tail bytes>
+//! ```
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Result, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crossterm::style::Stylize;
+
+use crate::image::read_full;
+use crate::safety;
+use crate::targ::{self, DriveInfo};
+use crate::ui;
+use crate::units;
+
+/// How much of each end of the drive is saved
+const SAVE: u64 = 4 * 1024 * 1024;
+/// First line of every rescue file
+const MAGIC: &str = "TETCHER-RESCUE 1";
+/// How many rescue files are kept per drive
+const KEEP: usize = 5;
+
+/// What a rescue file says about itself
+#[derive(Debug, Clone)]
+pub struct RescueFile {
+    pub path: PathBuf,
+    /// The device it was saved from, e.g. /dev/sdb
+    pub device: String,
+    /// The drive's serial number, or the device name if it has none
+    pub serial: String,
+    /// Size of the drive when it was saved
+    pub size: u64,
+    /// When it was saved, "2026-03-17 14:25:01 UTC"
+    pub created: String,
+    head_len: u64,
+    tail_offset: u64,
+    tail_len: u64,
+    /// Where the raw bytes start in the file
+    data_start: u64,
+}
+
+impl RescueFile {
+    /// e.g. "/dev/sdb (serial 4C53...), 57.30 GiB, saved 2026-03-17 14:25:01 UTC"
+    pub fn label(&self) -> String {
+        format!("{} (serial {}), {}, saved {}", self.device, self.serial, units::human_size(self.size), self.created)
+    }
+}
+
+/// tEtcher's per-user data folder, following each OS's habit for application data
+///
+/// Tests never get the real one, see `testutil::DataDir`
+pub fn data_dir() -> Option<PathBuf> {
+    #[cfg(test)]
+    let base = crate::testutil::data_dir();
+    #[cfg(not(test))]
+    let base = user_data_dir();
+    base
+}
+
+#[cfg(not(test))]
+fn user_data_dir() -> Option<PathBuf> {
+    let var = |name: &str| std::env::var_os(name).filter(|v| !v.is_empty()).map(PathBuf::from);
+
+    #[cfg(windows)]
+    let base = var("APPDATA").map(|d| d.join("tEtcher"));
+    #[cfg(target_os = "macos")]
+    let base = var("HOME").map(|h| h.join("Library/Application Support/tEtcher"));
+    #[cfg(not(any(windows, target_os = "macos")))]
+    let base = var("XDG_DATA_HOME")
+        .or_else(|| var("HOME").map(|h| h.join(".local/share")))
+        .map(|d| d.join("tetcher"));
+
+    base
+}
+
+/// Where rescue files are kept
+pub fn rescue_dir() -> Option<PathBuf> {
+    data_dir().map(|d| d.join("rescue"))
+}
+
+/// "YYYYmmdd-HHMMSS" and "YYYY-mm-dd HH:MM:SS UTC" for the current time
+fn timestamps() -> (String, String) {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, rem) = (secs / 86400, secs % 86400);
+    let (h, m, s) = (rem / 3600, rem % 3600 / 60, rem % 60);
+
+    // Days since 1970-01-01 to a civil date (Howard Hinnant's algorithm)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let mo = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + i64::from(mo <= 2);
+
+    (
+        format!("{:04}{:02}{:02}-{:02}{:02}{:02}", y, mo, d, h, m, s),
+        format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", y, mo, d, h, m, s),
+    )
+}
+
+/// Size of the drive (or plain file) at `device`
+fn size_of(device: &str) -> Result<u64> {
+    match targ::device_size(device)? {
+        Some(size) => Ok(size),
+        None => File::open(device)?.seek(SeekFrom::End(0)),
+    }
+}
+
+/// The serial number to name the rescue file after, the device name if the drive has none
+///
+/// resume.rs names its journals the same way
+pub fn serial_of(device: &str) -> String {
+    let serial = targ::list_drives()
+        .ok()
+        .and_then(|drives| drives.into_iter().find(|d| d.path == device))
+        .and_then(|d| d.serial);
+    let name = serial.unwrap_or_else(|| {
+        Path::new(device).file_name().map_or("drive".to_string(), |n| n.to_string_lossy().into_owned())
+    });
+    file_safe(&name)
+}
+
+/// Keeps a serial number usable as a file name
+fn file_safe(name: &str) -> String {
+    name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' }).collect()
+}
+
+/// Saves the start and end of `device` to a new rescue file, returning where it went
+///
+/// Returns `None` for an empty device, there is nothing to save
+pub fn save(device: &str) -> Result<Option<PathBuf>> {
+    let size = size_of(device)?;
+    if size == 0 {
+        return Ok(None);
+    }
+
+    let dir = rescue_dir().ok_or_else(|| io::Error::other("no home directory to keep rescue files in"))?;
+    fs::create_dir_all(&dir)?;
+
+    // Small drives are saved whole, as the head
+    let head_len = size.min(SAVE);
+    let tail_len = SAVE.min(size - head_len);
+    let tail_offset = size - tail_len;
+
+    let mut drive = File::open(device)?;
+    let mut head = vec![0u8; head_len as usize];
+    let n = read_full(&mut drive, &mut head)?;
+    head.truncate(n);
+    let mut tail = vec![0u8; tail_len as usize];
+    drive.seek(SeekFrom::Start(tail_offset))?;
+    let n = read_full(&mut drive, &mut tail)?;
+    tail.truncate(n);
+
+    let serial = serial_of(device);
+    let (stamp, created) = timestamps();
+    let (mut out, path) = create_new(&dir, &format!("{}-{}", serial, stamp))?;
+    write!(
+        out,
+        "{}\ndevice: {}\nserial: {}\nsize: {}\ncreated: {}\nhead: {}\ntail-offset: {}\ntail: {}\n\n",
+        MAGIC,
+        device,
+        serial,
+        size,
+        created,
+        head.len(),
+        tail_offset,
+        tail.len()
+    )?;
+    out.write_all(&head)?;
+    out.write_all(&tail)?;
+    out.sync_all()?;
+
+    prune(&dir, &serial);
+    Ok(Some(path))
+}
+
+/// Creates `<name>.rescue` in `dir`, or `<name>-2.rescue` and so on if an earlier save got that name
+fn create_new(dir: &Path, name: &str) -> Result<(File, PathBuf)> {
+    let mut n = 1;
+    loop {
+        let path = match n {
+            1 => dir.join(format!("{}.rescue", name)),
+            n => dir.join(format!("{}-{}.rescue", name, n)),
+        };
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((file, path)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => n += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Saves the start and end of `device` before it gets flashed, saying where they went
+///
+/// The error says the flash was refused because of it, ready to be shown as the drive's result
+pub fn save_before_flash(device: &str) -> Result<()> {
+    match save(device) {
+        Ok(Some(path)) => {
+            println!("Saved the start and end of {} to {}\r", device, path.display());
+            Ok(())
+        }
+        Ok(None) => Ok(()),
+        Err(e) => Err(io::Error::new(
+            e.kind(),
+            format!("could not save the start and end of the drive first ({}), so the flash could not be undone. Nothing was written", e),
+        )),
+    }
+}
+
+/// Where a rescue file of the drive named `prefix` ("<serial>-") comes among that drive's saves
+///
+/// The name is "<serial>-YYYYmmdd-HHMMSS" with maybe a "-N" on the end, see `create_new`. Anything
+/// else is `None`, so one serial that starts another is not mixed up with it
+fn save_order<'a>(stem: &'a str, prefix: &str) -> Option<(&'a str, u32)> {
+    let rest = stem.strip_prefix(prefix)?;
+    let (stamp, n) = match rest.get(15..)? {
+        "" => (rest, 1),
+        suffix => (&rest[..15], suffix.strip_prefix('-')?.parse().ok()?),
+    };
+    stamp.chars().all(|c| c.is_ascii_digit() || c == '-').then_some((stamp, n))
+}
+
+/// Deletes all but the newest `KEEP` rescue files of one drive
+///
+/// Their names end in the time they were saved, so they sort oldest first
+fn prune(dir: &Path, serial: &str) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    let prefix = format!("{}-", serial);
+    let mut files: Vec<((String, u32), PathBuf)> = entries
+        .flatten()
+        .map(|e| e.path())
+        .filter(|p| p.extension().is_some_and(|x| x == "rescue"))
+        .filter_map(|p| {
+            let (stamp, n) = save_order(p.file_stem()?.to_str()?, &prefix)?;
+            Some(((stamp.to_string(), n), p))
+        })
+        .collect();
+    files.sort();
+    let old = files.len().saturating_sub(KEEP);
+    for (_, path) in &files[..old] {
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// Reads the header of a rescue file
+pub fn read(path: &Path) -> Result<RescueFile> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("{} is not a tEtcher rescue file", path.display()));
+    let mut reader = BufReader::new(File::open(path)?);
+
+    let mut line = String::new();
+    let mut data_start = reader.read_line(&mut line)? as u64;
+    if line.trim_end() != MAGIC {
+        return Err(invalid());
+    }
+
+    let mut fields = std::collections::HashMap::new();
+    loop {
+        line.clear();
+        let n = reader.read_line(&mut line)?;
+        data_start += n as u64;
+        if n == 0 {
+            return Err(invalid());
+        }
+        if line.trim_end().is_empty() {
+            break;
+        }
+        if let Some((key, value)) = line.trim_end().split_once(": ") {
+            fields.insert(key.to_string(), value.to_string());
+        }
+    }
+
+    let text = |key: &str| fields.get(key).cloned().ok_or_else(invalid);
+    let number = |key: &str| text(key)?.parse::<u64>().map_err(|_| invalid());
+
+    Ok(RescueFile {
+        path: path.to_path_buf(),
+        device: text("device")?,
+        serial: text("serial")?,
+        size: number("size")?,
+        created: text("created")?,
+        head_len: number("head")?,
+        tail_offset: number("tail-offset")?,
+        tail_len: number("tail")?,
+        data_start,
+    })
+}
+
+/// Every rescue file in the rescue directory, newest first
+pub fn list() -> Result<Vec<RescueFile>> {
+    let Some(dir) = rescue_dir() else {
+        return Ok(Vec::new());
+    };
+    let Ok(entries) = fs::read_dir(&dir) else {
+        return Ok(Vec::new());
+    };
+
+    let mut files: Vec<RescueFile> = entries
+        .flatten()
+        .filter(|e| e.path().extension().is_some_and(|x| x == "rescue"))
+        .filter_map(|e| read(&e.path()).ok())
+        .collect();
+    // "2026-03-17 14:25:01 UTC" sorts the same as text
+    files.sort_by(|a, b| b.created.cmp(&a.created));
+    Ok(files)
+}
+
+/// Writes the saved start and end back onto `device`
+///
+/// The device has to be the same size as the one that was saved, otherwise the end would land
+/// in the wrong place
+pub fn restore(rescue: &RescueFile, device: &str) -> Result<()> {
+    safety::ensure_safe(device)?;
+
+    let size = size_of(device)?;
+    if size != rescue.size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} is {} but the rescue file is from a {} drive, refusing to restore onto a different drive",
+                device,
+                units::human_size(size),
+                units::human_size(rescue.size)
+            ),
+        ));
+    }
+
+    let mut file = File::open(&rescue.path)?;
+    file.seek(SeekFrom::Start(rescue.data_start))?;
+    let mut head = vec![0u8; rescue.head_len as usize];
+    file.read_exact(&mut head)?;
+    let mut tail = vec![0u8; rescue.tail_len as usize];
+    file.read_exact(&mut tail)?;
+
+    let mut drive = OpenOptions::new().write(true).open(device)?;
+    drive.write_all(&head)?;
+    drive.seek(SeekFrom::Start(rescue.tail_offset))?;
+    drive.write_all(&tail)?;
+    drive.sync_all()
+}
+
+/// Menu flow for undoing a flash: pick the rescue file, then the drive to put it back on
+pub fn menu() -> Result<()> {
+    let files = list()?;
+    if files.is_empty() {
+        print!("\x1B[H\x1B[2J");
+        print!("There are no rescue files yet.\r\nThey are saved automatically every time a drive is flashed.");
+        ui::pause()?;
+        return Ok(());
+    }
+
+    let labels: Vec<String> = files.iter().map(RescueFile::label).collect();
+    let labels: Vec<&str> = labels.iter().map(String::as_str).collect();
+    let Some(choice) = ui::choose(&"Which flash do you want to undo?".blue().bold().to_string(), &labels)? else {
+        return Ok(());
+    };
+    let rescue = &files[choice];
+
+    let Some(drive) = targ::pick_drive("Which drive should it be restored onto?")? else {
+        return Ok(());
+    };
+    let DriveInfo { path, serial, .. } = &drive;
+
+    // Restoring onto a different stick would wreck that one too, so say so if the serial does not match
+    let mut question = format!("Restore the start and end of {} onto {}?", rescue.device, drive.label());
+    if serial.as_deref().is_some_and(|s| file_safe(s) != rescue.serial) {
+        question = format!(
+            "{}\n{}",
+            question,
+            format!("The serial number does not match the one saved ({})!", rescue.serial).red().bold()
+        );
+    }
+    if !ui::confirm(&question)? {
+        return Ok(());
+    }
+
+    print!("\x1B[H\x1B[2J");
+    match restore(rescue, path) {
+        Ok(()) => print!(
+            "{}\r\nRun a recovery tool such as testdisk to get at the files that were not overwritten",
+            format!("✅ Restored {} from {}", path, rescue.path.display()).green().bold()
+        ),
+        Err(e) => print!("{}", format!("❌ Restore failed: {}", e).red().bold()),
+    }
+    ui::pause()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{DataDir, TempPath};
+
+    #[test]
+    fn prune_keeps_the_newest_of_each_drive() {
+        let dir = TempPath::dir("prune");
+        // Oldest first, more saves within one second get -2, -3... and -10 comes after -2
+        let mut names = vec!["ABC-20260317-142500.rescue".to_string(), "ABC-20260317-142500-2.rescue".to_string()];
+        names.push("ABC-20260317-142500-10.rescue".to_string());
+        names.extend((1..KEEP).map(|i| format!("ABC-20260317-1425{:02}.rescue", i)));
+        for name in names.iter().chain(["ABCD-20260101-000000.rescue".to_string(), "ABC-notes.rescue".to_string()].iter()) {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+
+        prune(&dir, "ABC");
+
+        let mut left: Vec<String> = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name().into_string().unwrap()).collect();
+        left.sort();
+
+        let mut expected: Vec<String> = names[2..].to_vec();
+        expected.extend(["ABC-notes.rescue".to_string(), "ABCD-20260101-000000.rescue".to_string()]);
+        expected.sort();
+        assert_eq!(left, expected);
+    }
+
+    #[test]
+    fn saved_drives_can_be_restored_onto_one_the_same_size() {
+        let _data_dir = DataDir::new();
+        let drive: Vec<u8> = (0..3 * SAVE).map(|i| (i % 251) as u8).collect();
+        let saved_from = TempPath::file("drive", &drive);
+
+        let path = save(saved_from.as_str()).unwrap().unwrap();
+        // Saved again, most likely within the same second, the first one is still there
+        let again = save(saved_from.as_str()).unwrap().unwrap();
+        assert_ne!(path, again);
+        assert_eq!(list().unwrap().len(), 2);
+
+        let rescue = read(&path).unwrap();
+        assert_eq!((rescue.device.as_str(), rescue.size), (saved_from.as_str(), 3 * SAVE));
+        assert_eq!((rescue.head_len, rescue.tail_offset, rescue.tail_len), (SAVE, 2 * SAVE, SAVE));
+
+        // Flashed over: the start and end come back, the middle is lost for good
+        let flashed = TempPath::file("flashed", vec![0u8; drive.len()]);
+        restore(&rescue, flashed.as_str()).unwrap();
+        let back = fs::read(&flashed).unwrap();
+        let save = SAVE as usize;
+        assert!(back[..save] == drive[..save] && back[2 * save..] == drive[2 * save..]);
+        assert!(back[save..2 * save].iter().all(|&b| b == 0));
+
+        let other = TempPath::file("other", vec![0u8; drive.len() - 512]);
+        let e = restore(&rescue, other.as_str()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(fs::read(&other).unwrap().iter().all(|&b| b == 0));
+    }
+}
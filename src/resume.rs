@@ -0,0 +1,355 @@
+//! resume.rs keeps a small journal while flashing, so a flash that died part way (a loose cable, a
+//! crash, Esc) can carry on from where it got to instead of starting again from zero
+//!
+//! There is one journal per drive, named after its serial number, e.g.
+//! ~/.local/share/tetcher/resume/4C530001230101.journal, in the same style as the rescue files:
+//!
+//! ```text
+//! TETCHER-RESUME 1
+//! image: /home/me/Downloads/raspios.img.xz
+//! image-size: 1204928512
+//! image-mtime: 1773757501
+//! image-hash: 9f86d081884c7d65...
+//! serial: 4C530001230101
+//! device: /dev/sdb
+//! mode: plain
+//! total: 5368709120
+//! synced: 4294967296
+//! ```
+//!
+//! `synced` is how many bytes of the image are known to be on the drive. The image is recognised by
+//! its path, size, modification time and a hash of its first MiB, so a new download with the same
+//! name does not get mixed up with the old one. Before carrying on, the last `TAIL` bytes written are
+//! read back and checked, in case something else wrote to the drive in between
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Result, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use sha2::{Digest, Sha256};
+
+use crate::bmap::Bmap;
+use crate::flash::FlashOptions;
+use crate::image::{ImageSource, read_full};
+use crate::rescue;
+use crate::units;
+use crate::verify;
+
+/// First line of every journal
+const MAGIC: &str = "TETCHER-RESUME 1";
+/// How much of the start of the image file goes into its hash
+const HASHED: usize = 1024 * 1024;
+/// How much of what was written before is read back and checked before carrying on
+pub const TAIL: u64 = 64 * 1024 * 1024;
+
+/// What the journal says about the image, to tell whether it is still the same one
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Identity {
+    image: String,
+    entry: Option<String>,
+    size: u64,
+    mtime: u64,
+    hash: String,
+}
+
+impl Identity {
+    fn of(image: &ImageSource) -> Result<Identity> {
+        let path = fs::canonicalize(&image.path)?;
+        let metadata = fs::metadata(&path)?;
+        let mtime = metadata.modified()?.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+
+        let mut start = vec![0u8; HASHED];
+        let n = read_full(&mut File::open(&path)?, &mut start)?;
+
+        Ok(Identity {
+            image: path.display().to_string(),
+            entry: image.entry.clone(),
+            size: metadata.len(),
+            mtime,
+            hash: format!("{:x}", Sha256::digest(&start[..n])),
+        })
+    }
+}
+
+/// The resume journal of one drive
+#[derive(Debug, Clone)]
+pub struct Journal {
+    /// Where the journal is kept
+    path: PathBuf,
+    identity: Identity,
+    pub device: String,
+    serial: String,
+    /// How the flash was done, see `mode`. Carrying on has to be done the same way
+    mode: String,
+    /// Size of the image once decompressed, if it was known
+    pub total: Option<u64>,
+    /// Bytes of the image known to be on the drive
+    pub synced: u64,
+}
+
+/// How a flash is done, only a flash done the same way can be carried on
+///
+/// e.g. an earlier flash with a bmap left the unmapped parts alone, a plain one carrying on would not
+pub fn mode(bmap: Option<&Bmap>, skip_zeros: bool) -> String {
+    match bmap {
+        Some(bmap) => format!("bmap {}", bmap.path.display()),
+        None if skip_zeros => "skip-zeros".to_string(),
+        None => "plain".to_string(),
+    }
+}
+
+/// Where the journals are kept
+fn journal_dir() -> Option<PathBuf> {
+    rescue::data_dir().map(|d| d.join("resume"))
+}
+
+fn invalid(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{} is not a tEtcher resume journal", path.display()))
+}
+
+impl Journal {
+    /// A fresh journal for flashing `image` to `device`, nothing is saved until `save`
+    pub fn new(image: &ImageSource, device: &str, mode: String, total: Option<u64>) -> Result<Journal> {
+        let dir = journal_dir().ok_or_else(|| io::Error::other("no home directory to keep the resume journal in"))?;
+        let serial = rescue::serial_of(device);
+        Ok(Journal {
+            path: dir.join(format!("{}.journal", serial)),
+            identity: Identity::of(image)?,
+            device: device.to_string(),
+            serial,
+            mode,
+            total,
+            synced: 0,
+        })
+    }
+
+    /// The journal left by an earlier flash of this same image to this same drive, done the same way
+    ///
+    /// `None` if there is none, or it is for something else, or there is nothing to carry on with
+    pub fn find(image: &ImageSource, device: &str, options: &FlashOptions) -> Option<Journal> {
+        // A broken bmap stops the flash anyway, there is no point offering to carry on
+        let bmap = options.bmap.load(image).ok()?;
+        let wanted = Journal::new(image, device, mode(bmap.as_ref(), options.skip_zeros), None).ok()?;
+        let found = Journal::read(&wanted.path).ok()?;
+
+        let same = found.identity == wanted.identity && found.serial == wanted.serial && found.mode == wanted.mode;
+        let unfinished = found.synced > 0 && found.total.is_none_or(|total| found.synced < total);
+        (same && unfinished).then_some(Journal { device: device.to_string(), ..found })
+    }
+
+    fn read(path: &Path) -> Result<Journal> {
+        let text = fs::read_to_string(path)?;
+        let mut lines = text.lines();
+        if lines.next() != Some(MAGIC) {
+            return Err(invalid(path));
+        }
+
+        let fields: std::collections::HashMap<&str, &str> = lines.filter_map(|l| l.split_once(": ")).collect();
+        let text = |key: &str| fields.get(key).map(|v| v.to_string()).ok_or_else(|| invalid(path));
+        let number = |key: &str| text(key)?.parse::<u64>().map_err(|_| invalid(path));
+
+        Ok(Journal {
+            path: path.to_path_buf(),
+            identity: Identity {
+                image: text("image")?,
+                entry: text("entry").ok(),
+                size: number("image-size")?,
+                mtime: number("image-mtime")?,
+                hash: text("image-hash")?,
+            },
+            device: text("device")?,
+            serial: text("serial")?,
+            mode: text("mode")?,
+            total: number("total").ok(),
+            synced: number("synced")?,
+        })
+    }
+
+    /// Records that `synced` bytes of the image are on the drive
+    ///
+    /// Written to a temporary file and renamed over the old one, so a crash half way through
+    /// writing it never leaves a journal that claims more than it should
+    pub fn save(&mut self, synced: u64) -> Result<()> {
+        self.synced = synced;
+        let Some(dir) = self.path.parent() else {
+            return Ok(());
+        };
+        fs::create_dir_all(dir)?;
+
+        let mut text = format!("{}\nimage: {}\n", MAGIC, self.identity.image);
+        if let Some(entry) = &self.identity.entry {
+            text += &format!("entry: {}\n", entry);
+        }
+        text += &format!(
+            "image-size: {}\nimage-mtime: {}\nimage-hash: {}\nserial: {}\ndevice: {}\nmode: {}\n",
+            self.identity.size, self.identity.mtime, self.identity.hash, self.serial, self.device, self.mode
+        );
+        if let Some(total) = self.total {
+            text += &format!("total: {}\n", total);
+        }
+        text += &format!("synced: {}\n", synced);
+
+        let temp = self.path.with_extension("journal.tmp");
+        let mut out = File::create(&temp)?;
+        out.write_all(text.as_bytes())?;
+        out.sync_all()?;
+        fs::rename(&temp, &self.path)
+    }
+
+    /// Deletes the journal, once the flash is done or there is nothing left worth carrying on from
+    pub fn remove(&self) {
+        let _ = fs::remove_file(&self.path);
+    }
+
+    /// e.g. "stopped at 24.00 GiB of 30.00 GiB (80%)"
+    pub fn describe(&self) -> String {
+        match self.total {
+            Some(total) => format!(
+                "stopped at {} of {} ({}%)",
+                units::human_size(self.synced),
+                units::human_size(total),
+                (self.synced * 100).checked_div(total).unwrap_or(0)
+            ),
+            None => format!("stopped at {}", units::human_size(self.synced)),
+        }
+    }
+}
+
+/// The error `TailCheck::check` fails with when the drive really holds something else, as opposed to
+/// not being readable right now
+#[derive(Debug)]
+struct Mismatch;
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the drive no longer holds what was written to it before, flash it again from the beginning")
+    }
+}
+
+impl std::error::Error for Mismatch {}
+
+/// Whether `e` says the drive no longer holds the earlier flash, so there is nothing left to carry on from
+pub fn is_mismatch(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|inner| inner.is::<Mismatch>())
+}
+
+/// Reads back `expected.len()` bytes of `device` at `offset` and checks they are what was written
+pub struct TailCheck {
+    device: File,
+    /// With a bmap only the mapped ranges were written, the rest of the drive holds whatever it did
+    mapped: Option<Vec<Range<u64>>>,
+    buf: Vec<u8>,
+}
+
+impl TailCheck {
+    pub fn open(device: &str, bmap: Option<&Bmap>) -> Result<TailCheck> {
+        let mapped = bmap.map(|bmap| bmap.ranges.iter().map(|r| r.bytes.clone()).collect());
+        Ok(TailCheck { device: verify::open_device(device)?, mapped, buf: Vec::new() })
+    }
+
+    /// Fails if the drive does not hold `expected` at `offset`
+    pub fn check(&mut self, offset: u64, expected: &[u8]) -> Result<()> {
+        self.buf.resize(expected.len(), 0);
+        self.device.seek(SeekFrom::Start(offset))?;
+        let n = read_full(&mut self.device, &mut self.buf)?;
+
+        let end = offset + expected.len() as u64;
+        let same = match &self.mapped {
+            Some(mapped) => mapped.iter().filter(|r| r.start < end && r.end > offset).all(|r| {
+                let from = (r.start.max(offset) - offset) as usize;
+                let to = (r.end.min(end) - offset) as usize;
+                to <= n && self.buf[from..to] == expected[from..to]
+            }),
+            None => n == expected.len() && self.buf == expected,
+        };
+        if !same {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, Mismatch));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempPath;
+
+    fn journal(path: PathBuf, entry: Option<&str>, total: Option<u64>) -> Journal {
+        Journal {
+            path,
+            identity: Identity {
+                image: "/home/me/raspios.img.xz".to_string(),
+                entry: entry.map(str::to_string),
+                size: 1204928512,
+                mtime: 1773757501,
+                hash: "9f86d081884c7d65".to_string(),
+            },
+            device: "/dev/sdb".to_string(),
+            serial: "4C530001230101".to_string(),
+            mode: "bmap /home/me/raspios.img.bmap".to_string(),
+            total,
+            synced: 0,
+        }
+    }
+
+    #[test]
+    fn journal_round_trip() {
+        for (entry, total) in [(None, Some(5368709120)), (Some("disk.img"), None)] {
+            let path = TempPath::new("round-trip.journal");
+            let mut saved = journal(path.to_path_buf(), entry, total);
+            saved.save(4294967296).unwrap();
+            let read = Journal::read(&path).unwrap();
+            saved.remove();
+
+            assert_eq!(read.identity, saved.identity);
+            assert_eq!((read.device, read.serial, read.mode), (saved.device, saved.serial, saved.mode));
+            assert_eq!((read.total, read.synced), (total, 4294967296));
+            assert!(!path.exists());
+        }
+    }
+
+    #[test]
+    fn broken_journals_are_refused() {
+        let path = TempPath::file("broken.journal", "something else\nsynced: 1\n");
+        assert!(Journal::read(&path).is_err());
+        fs::write(&path, format!("{}\nimage: x\nsynced: 1\n", MAGIC)).unwrap();
+        assert!(Journal::read(&path).is_err());
+    }
+
+    #[test]
+    fn describe_says_how_far() {
+        let mut j = journal(PathBuf::new(), None, Some(30 << 30));
+        j.synced = 24 << 30;
+        assert_eq!(j.describe(), "stopped at 24.00 GiB of 30.00 GiB (80%)");
+        j.total = None;
+        assert_eq!(j.describe(), "stopped at 24.00 GiB");
+    }
+
+    #[test]
+    fn tail_check_tells_a_mismatch_from_other_errors() {
+        let data: Vec<u8> = (0..=255).cycle().take(8192).collect();
+        let path = TempPath::file("tail.img", &data);
+        let device = path.as_str().to_string();
+
+        let mut tail = TailCheck::open(&device, None).unwrap();
+        tail.check(4096, &data[4096..]).unwrap();
+        let mut changed = data[4096..].to_vec();
+        changed[10] ^= 1;
+        let e = tail.check(4096, &changed).unwrap_err();
+        assert!(is_mismatch(&e));
+
+        // With a bmap only the mapped ranges matter
+        tail.mapped = Some(vec![4096..4100, 5000..6000]);
+        tail.check(4096, &changed).unwrap();
+        changed[1000] ^= 1;
+        assert!(is_mismatch(&tail.check(4096, &changed).unwrap_err()));
+        drop(path);
+
+        let e = TailCheck::open(&device, None).err().unwrap();
+        assert!(!is_mismatch(&e));
+        assert!(!is_mismatch(&io::Error::new(io::ErrorKind::InvalidData, "something else")));
+    }
+}
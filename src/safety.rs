@@ -0,0 +1,397 @@
+//! safety.rs stops tEtcher from overwriting a disk that is in use
+//!
+//! The system disk (holding "/", /boot, /usr, ... or swap) is refused outright
+//! Any other disk with mounted partitions is only flashed once those have been unmounted
+//!
+//! On Linux this reads /proc/self/mountinfo and /proc/swaps, matching mounts to the disk by device
+//! number, and also follows partitions and anything stacked on top of them (LVM, dm-crypt, md RAID)
+//! A disk with something stacked on top is refused even if nothing is mounted, unmounting does not
+//! close an LVM volume group or a dm-crypt mapping
+//! On macOS the output of `mount` is used. On Windows PowerShell is asked which disk %SystemDrive%
+//! is on and which of the disk's partitions have drive letters, and if it cannot say, the disk is refused
+
+use std::io::{self, Result};
+use std::process::Command;
+
+#[cfg(target_os = "linux")]
+use std::collections::HashSet;
+#[cfg(target_os = "linux")]
+use std::fs;
+#[cfg(target_os = "linux")]
+use std::path::Path;
+
+/// Mount points that mean the disk is the one the system is running from
+const SYSTEM_MOUNTS: [&str; 7] = ["/", "/boot", "/boot/efi", "/efi", "/usr", "/var", "/home"];
+
+/// One mounted filesystem that lives on the disk
+#[derive(Debug, Clone)]
+pub struct Mount {
+    /// Where it came from, e.g. /dev/sdb1
+    pub source: String,
+    /// Where it is mounted, e.g. /media/user/BOOT
+    pub mount_point: String,
+    pub fs_type: String,
+}
+
+/// Everything found to be using a disk
+#[derive(Debug, Default)]
+pub struct SafetyReport {
+    pub device: String,
+    /// Why the disk counts as the system disk, if it does
+    pub system_reason: Option<String>,
+    /// Filesystems on the disk that are currently mounted
+    pub mounts: Vec<Mount>,
+    /// Devices stacked on top of the disk (LVM, dm-crypt, md RAID), e.g. "dm-0 (luks-home)"
+    pub holders: Vec<String>,
+}
+
+impl SafetyReport {
+    /// True if nothing stands in the way of flashing
+    pub fn is_clear(&self) -> bool {
+        self.system_reason.is_none() && self.mounts.is_empty() && self.holders.is_empty()
+    }
+
+    /// True if unmounting is all it takes, i.e. it is not the system disk and nothing is stacked on it
+    pub fn can_unmount(&self) -> bool {
+        self.system_reason.is_none() && self.holders.is_empty()
+    }
+
+    /// Turns the report into the error that refuses the flash
+    pub fn to_error(&self) -> io::Error {
+        let msg = match &self.system_reason {
+            Some(reason) => format!("{} is the system disk ({}), refusing to overwrite it", self.device, reason),
+            None if !self.holders.is_empty() => format!(
+                "{} is in use by {} (LVM, dm-crypt or RAID), close those first",
+                self.device,
+                self.holders.join(", ")
+            ),
+            None => {
+                let points: Vec<&str> = self.mounts.iter().map(|m| m.mount_point.as_str()).collect();
+                format!("{} has mounted partitions ({}), unmount them first", self.device, points.join(", "))
+            }
+        };
+        io::Error::new(io::ErrorKind::PermissionDenied, msg)
+    }
+}
+
+/// Works out whether the disk is the system disk, and what on it is mounted
+pub fn check(device: &str) -> Result<SafetyReport> {
+    let mut report = SafetyReport { device: device.to_string(), ..Default::default() };
+
+    #[cfg(target_os = "linux")]
+    linux::check(device, &mut report)?;
+
+    #[cfg(target_os = "macos")]
+    macos::check(device, &mut report)?;
+
+    #[cfg(windows)]
+    windows::check(device, &mut report);
+
+    if report.system_reason.is_none()
+        && let Some(m) = report.mounts.iter().find(|m| SYSTEM_MOUNTS.contains(&m.mount_point.as_str())) {
+        report.system_reason = Some(format!("{} is mounted on {}", m.source, m.mount_point));
+    }
+
+    Ok(report)
+}
+
+/// Fails unless the disk is safe to overwrite, this is the last line of defence before writing
+pub fn ensure_safe(device: &str) -> Result<()> {
+    let report = check(device)?;
+    if report.is_clear() {
+        Ok(())
+    } else {
+        Err(report.to_error())
+    }
+}
+
+/// Unmounts everything the report found, deepest mount points first
+///
+/// Refuses to touch the system disk, or a disk with something stacked on it
+pub fn unmount(report: &SafetyReport) -> Result<()> {
+    if !report.can_unmount() {
+        return Err(report.to_error());
+    }
+
+    let mut points: Vec<&str> = report.mounts.iter().map(|m| m.mount_point.as_str()).collect();
+    points.sort_by_key(|p| std::cmp::Reverse(p.len()));
+    points.dedup();
+
+    for point in points {
+        #[cfg(target_os = "macos")]
+        let status = Command::new("diskutil").args(["unmount", point]).status()?;
+        // Takes the drive letter away, which is as close to unmounting as Windows gets
+        #[cfg(windows)]
+        let status = Command::new("mountvol").args([point, "/D"]).status()?;
+        #[cfg(not(any(target_os = "macos", windows)))]
+        let status = Command::new("umount").arg(point).status()?;
+
+        if !status.success() {
+            return Err(io::Error::other(format!("could not unmount {}", point)));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::*;
+
+    /// Undoes the octal escapes mountinfo uses for spaces and friends, e.g. "\040"
+    pub(super) fn unescape(field: &str) -> String {
+        let bytes = field.as_bytes();
+        let mut out = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            if bytes[i] == b'\\'
+                && i + 3 < bytes.len()
+                && let Ok(digits) = std::str::from_utf8(&bytes[i + 1..i + 4])
+                && let Ok(v) = u8::from_str_radix(digits, 8) {
+                out.push(v);
+                i += 4;
+                continue;
+            }
+            out.push(bytes[i]);
+            i += 1;
+        }
+        String::from_utf8_lossy(&out).into_owned()
+    }
+
+    /// The kernel name of a /dev path, following symlinks, e.g. /dev/disk/by-id/usb-... -> "sdb"
+    fn block_name(path: &str) -> Option<String> {
+        let real = fs::canonicalize(path).ok()?;
+        if !real.starts_with("/dev") {
+            return None;
+        }
+        Some(real.file_name()?.to_string_lossy().into_owned())
+    }
+
+    /// Collects the disk, its partitions and everything stacked on top of them (dm, md), by kernel name
+    ///
+    /// The stacked ones also go into `holders`
+    fn related_devices(name: &str, seen: &mut HashSet<String>, holders: &mut Vec<String>) {
+        if !seen.insert(name.to_string()) {
+            return;
+        }
+        let dir = Path::new("/sys/class/block").join(name);
+
+        // Partitions show up as sub-directories with a "partition" file
+        if let Ok(entries) = fs::read_dir(&dir) {
+            for entry in entries.flatten() {
+                if entry.path().join("partition").exists() {
+                    related_devices(&entry.file_name().to_string_lossy(), seen, holders);
+                }
+            }
+        }
+
+        if let Ok(entries) = fs::read_dir(dir.join("holders")) {
+            for entry in entries.flatten() {
+                let holder = entry.file_name().to_string_lossy().into_owned();
+                if !seen.contains(&holder) {
+                    holders.push(holder_name(&holder));
+                }
+                related_devices(&holder, seen, holders);
+            }
+        }
+    }
+
+    /// "dm-0 (luks-home)" for device mapper devices, which have a name of their own, else just "md0"
+    fn holder_name(name: &str) -> String {
+        match fs::read_to_string(Path::new("/sys/class/block").join(name).join("dm/name")) {
+            Ok(dm) => format!("{} ({})", name, dm.trim()),
+            Err(_) => name.to_string(),
+        }
+    }
+
+    pub(super) fn check(device: &str, report: &mut SafetyReport) -> Result<()> {
+        let Some(mut name) = block_name(device) else {
+            return Ok(());
+        };
+
+        // Flashing a partition still wipes part of its disk, so look at the whole disk
+        let sys = Path::new("/sys/class/block").join(&name);
+        if sys.join("partition").exists()
+            && let Ok(parent) = fs::canonicalize(&sys)
+            && let Some(disk) = parent.parent().and_then(|p| p.file_name()) {
+            name = disk.to_string_lossy().into_owned();
+        }
+
+        let mut names = HashSet::new();
+        related_devices(&name, &mut names, &mut report.holders);
+
+        // "major:minor" numbers, which is how mountinfo names the device
+        let numbers: HashSet<String> = names
+            .iter()
+            .filter_map(|n| fs::read_to_string(Path::new("/sys/class/block").join(n).join("dev")).ok())
+            .map(|s| s.trim().to_string())
+            .collect();
+
+        // Fields: id parent major:minor root mount-point options [optional...] - fstype source super-options
+        for line in fs::read_to_string("/proc/self/mountinfo")?.lines() {
+            let Some((before, after)) = line.split_once(" - ") else { continue };
+            let fields: Vec<&str> = before.split(' ').collect();
+            let after: Vec<&str> = after.split(' ').collect();
+            if fields.len() < 5 || after.len() < 2 {
+                continue;
+            }
+
+            let source = unescape(after[1]);
+            // btrfs and some others report a made-up device number, so match the source path as well
+            let matches = numbers.contains(fields[2])
+                || (source.starts_with("/dev/") && block_name(&source).is_some_and(|n| names.contains(&n)));
+
+            if matches {
+                report.mounts.push(Mount {
+                    source,
+                    mount_point: unescape(fields[4]),
+                    fs_type: after[0].to_string(),
+                });
+            }
+        }
+
+        // Swap on the disk means the running system depends on it
+        if let Ok(swaps) = fs::read_to_string("/proc/swaps") {
+            for line in swaps.lines().skip(1) {
+                let Some(file) = line.split_whitespace().next() else { continue };
+                let file = unescape(file);
+                if block_name(&file).is_some_and(|n| names.contains(&n)) {
+                    report.system_reason = Some(format!("{} is in use as swap", file));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod macos {
+    use super::*;
+
+    /// Matches "/dev/disk4s1 on /Volumes/BOOT (msdos, local, ...)" lines against the disk
+    pub(super) fn check(device: &str, report: &mut SafetyReport) -> Result<()> {
+        // /dev/rdisk4 and /dev/disk4 are the same disk
+        let disk = device.replace("/dev/rdisk", "/dev/disk");
+        let output = Command::new("mount").output()?;
+
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            let Some((source, rest)) = line.split_once(" on ") else { continue };
+            let Some((mount_point, opts)) = rest.rsplit_once(" (") else { continue };
+
+            let on_disk = source == disk || source.strip_prefix(&disk).is_some_and(|s| s.starts_with('s'));
+            if on_disk {
+                report.mounts.push(Mount {
+                    source: source.to_string(),
+                    mount_point: mount_point.to_string(),
+                    fs_type: opts.split(',').next().unwrap_or("").to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+mod windows {
+    use super::*;
+
+    /// Asks PowerShell, giving back its output or `None` if it could not be run
+    fn powershell(command: &str) -> Option<String> {
+        let output = Command::new("powershell").args(["-NoProfile", "-Command", command]).output().ok()?;
+        output.status.success().then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// e.g. \\.\PHYSICALDRIVE1 -> 1
+    fn disk_number(device: &str) -> Option<u32> {
+        device.to_ascii_uppercase().strip_prefix("\\\\.\\PHYSICALDRIVE")?.parse().ok()
+    }
+
+    /// Not being able to tell which disk Windows runs from counts as it being this one, better to
+    /// refuse a flash than to guess
+    pub(super) fn check(device: &str, report: &mut SafetyReport) {
+        let Some(disk) = disk_number(device) else {
+            report.system_reason = Some("only \\\\.\\PHYSICALDRIVEn devices can be checked".to_string());
+            return;
+        };
+
+        let system = powershell("(Get-Partition -DriveLetter $env:SystemDrive[0]).DiskNumber")
+            .and_then(|out| out.trim().parse::<u32>().ok());
+        match system {
+            Some(system) if system == disk => {
+                report.system_reason = Some(format!("{} is on it", std::env::var("SystemDrive").unwrap_or_else(|_| "C:".into())));
+                return;
+            }
+            Some(_) => {}
+            None => {
+                report.system_reason = Some("could not find out which disk Windows is running from".to_string());
+                return;
+            }
+        }
+
+        // Partitions with a drive letter are the Windows idea of mounted, e.g. "1|E|FAT32"
+        let command = format!(
+            "Get-Partition -DiskNumber {} | Where-Object DriveLetter | ForEach-Object {{ '{{0}}|{{1}}|{{2}}' -f $_.PartitionNumber, $_.DriveLetter, (Get-Volume -Partition $_).FileSystem }}",
+            disk
+        );
+        let Some(out) = powershell(&command) else {
+            report.system_reason = Some(format!("could not list the partitions of {}", device));
+            return;
+        };
+        for line in out.lines() {
+            let parts: Vec<&str> = line.split('|').map(str::trim).collect();
+            if parts.len() < 3 || parts[1].is_empty() {
+                continue;
+            }
+            report.mounts.push(Mount {
+                source: format!("{} partition {}", device, parts[0]),
+                mount_point: format!("{}:\\", parts[1]),
+                fs_type: parts[2].to_string(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempPath;
+
+    fn mount(source: &str, mount_point: &str) -> Mount {
+        Mount { source: source.to_string(), mount_point: mount_point.to_string(), fs_type: "vfat".to_string() }
+    }
+
+    #[test]
+    fn mounted_disks_can_be_unmounted_but_not_the_system_disk() {
+        let mut report = SafetyReport { device: "/dev/sdb".to_string(), ..Default::default() };
+        assert!(report.is_clear());
+
+        report.mounts.push(mount("/dev/sdb1", "/media/me/BOOT"));
+        assert!(!report.is_clear() && report.can_unmount());
+        assert_eq!(report.to_error().to_string(), "/dev/sdb has mounted partitions (/media/me/BOOT), unmount them first");
+
+        report.holders.push("dm-0 (luks-home)".to_string());
+        assert!(!report.can_unmount());
+        assert!(report.to_error().to_string().contains("in use by dm-0 (luks-home)"));
+
+        report.system_reason = Some("/dev/sdb2 is mounted on /".to_string());
+        assert!(!report.can_unmount());
+        assert_eq!(report.to_error().kind(), io::ErrorKind::PermissionDenied);
+        assert!(report.to_error().to_string().contains("is the system disk"));
+    }
+
+    #[test]
+    fn plain_files_are_clear() {
+        let path = TempPath::file("safety", b"");
+        let report = check(path.as_str()).unwrap();
+        assert!(report.is_clear(), "{:?}", report);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn mountinfo_escapes() {
+        assert_eq!(linux::unescape("/media/me/My\\040Stick"), "/media/me/My Stick");
+        assert_eq!(linux::unescape("tab\\011and\\134slash"), "tab\tand\\slash");
+        // Not an escape, left alone
+        assert_eq!(linux::unescape("a\\9z\\"), "a\\9z\\");
+    }
+}
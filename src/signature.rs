@@ -0,0 +1,467 @@
+//! signature.rs checks the detached signature on a checksum file, so a SHA256SUMS that was swapped
+//! on a mirror is caught and not just a corrupted download
+//!
+//! Two kinds of signature are looked for next to the checksum file:
+//! - OpenPGP: SHA256SUMS.gpg, SHA256SUMS.asc or SHA256SUMS.sig, checked by running gpgv (part of GnuPG)
+//! - minisign: SHA256SUMS.minisig, checked here
+//!
+//! Only keys in the keyring folder are trusted. It lives in tEtcher's data folder
+//! (~/.local/share/tetcher/keys on Linux), or wherever TETCHER_KEYRING points, and holds
+//! OpenPGP public keys (.gpg or .asc, as "gpg --export" writes them) and minisign public keys (.pub)
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Result, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use blake2::{Blake2b512, Digest};
+use ed25519_dalek::{Signature as Ed25519Signature, Verifier, VerifyingKey};
+
+use crate::rescue;
+
+/// Signature files are looked for with these endings, in this order
+const SIGNATURE_ENDINGS: [&str; 4] = [".gpg", ".asc", ".sig", ".minisig"];
+
+/// What checking a signature found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Status {
+    /// A good signature from a key in the keyring, with who made it
+    Trusted(String),
+    /// Signed, but with a key that is not in the keyring, with the key's id
+    UnknownKey(String),
+    /// The signature does not match, the file was changed after it was signed
+    Bad(String),
+    /// The signature could not be checked at all, with why (e.g. gpgv is not installed)
+    Unchecked(String),
+}
+
+/// A signature found next to a checksum file, and what checking it found
+#[derive(Debug, Clone)]
+pub struct SignatureCheck {
+    /// The signature file, e.g. SHA256SUMS.gpg
+    pub path: PathBuf,
+    pub status: Status,
+}
+
+impl SignatureCheck {
+    pub fn is_bad(&self) -> bool {
+        matches!(self.status, Status::Bad(_))
+    }
+}
+
+impl fmt::Display for SignatureCheck {
+    /// One line for the confirmation screen, e.g. "Signature: trusted, SHA256SUMS.gpg signed by ..."
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let file = self.path.file_name().map_or(String::new(), |n| n.to_string_lossy().into_owned());
+        match &self.status {
+            Status::Trusted(signer) => write!(f, "Signature: trusted, {} signed by {}", file, signer),
+            Status::UnknownKey(key) => write!(f, "Signature: UNKNOWN KEY, {} is signed by {} which is not in the keyring", file, key),
+            Status::Bad(why) => write!(f, "Signature: BAD, {} ({})", file, why),
+            Status::Unchecked(why) => write!(f, "Signature: not checked, {}", why),
+        }
+    }
+}
+
+/// The folder of trusted public keys
+pub fn keyring_dir() -> Option<PathBuf> {
+    match std::env::var_os("TETCHER_KEYRING").filter(|v| !v.is_empty()) {
+        Some(dir) => Some(PathBuf::from(dir)),
+        None => rescue::data_dir().map(|d| d.join("keys")),
+    }
+}
+
+/// Every file in the keyring folder with the given extension
+fn keyring_files(extensions: &[&str]) -> Vec<PathBuf> {
+    let Some(dir) = keyring_dir() else {
+        return Vec::new();
+    };
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut files: Vec<PathBuf> = entries
+        .flatten()
+        .map(|e| e.path())
+        .filter(|p| p.extension().is_some_and(|x| extensions.iter().any(|e| x == *e)))
+        .collect();
+    files.sort();
+    files
+}
+
+/// Looks for a detached signature next to `signed` (normally a checksum file) and checks it
+///
+/// Returns `None` if the file is not signed
+pub fn check(signed: &Path) -> Option<SignatureCheck> {
+    let name = signed.file_name()?.to_string_lossy().into_owned();
+    let path = SIGNATURE_ENDINGS
+        .iter()
+        .map(|ending| signed.with_file_name(format!("{}{}", name, ending)))
+        .find(|p| p.is_file())?;
+
+    let result = if path.extension().is_some_and(|x| x == "minisig") {
+        check_minisign(signed, &path, &keyring_files(&["pub"]))
+    } else {
+        check_openpgp(signed, &path)
+    };
+    let status = result.unwrap_or_else(|e| Status::Unchecked(e.to_string()));
+    Some(SignatureCheck { path, status })
+}
+
+/// Turns ASCII-armored OpenPGP data into the binary packets gpgv reads from a keyring
+fn dearmor(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut lines = text.lines().map(str::trim);
+
+    while lines.by_ref().any(|l| l.starts_with("-----BEGIN PGP")) {
+        // Armor headers ("Comment: ...") end at the first blank line
+        let body: String = lines
+            .by_ref()
+            .skip_while(|l| l.contains(": "))
+            .take_while(|l| !l.starts_with("-----END PGP"))
+            // "=abcd" is the armor checksum, not part of the data
+            .filter(|l| !l.is_empty() && !l.starts_with('='))
+            .collect();
+        out.extend(STANDARD.decode(body).ok()?);
+    }
+    (!out.is_empty()).then_some(out)
+}
+
+/// Runs gpgv on the signature with every OpenPGP key in the keyring
+fn check_openpgp(signed: &Path, signature: &Path) -> Result<Status> {
+    // gpgv takes one binary keyring, so armored keys are decoded and everything goes in one temporary file
+    let mut keyring = Vec::new();
+    for key in keyring_files(&["gpg", "asc"]) {
+        let data = fs::read(&key)?;
+        if data.starts_with(b"-----BEGIN PGP") {
+            keyring.extend(dearmor(&String::from_utf8_lossy(&data)).unwrap_or_default());
+        } else {
+            keyring.extend(data);
+        }
+    }
+    // In a folder only we can get into, so nobody can swap the keyring or plant a symlink there
+    let dir = PrivateDir::new()?;
+    let keyring_path = dir.0.join("keyring.gpg");
+    create_private(&keyring_path)?.write_all(&keyring)?;
+
+    let output = Command::new("gpgv")
+        .arg("--status-fd")
+        .arg("1")
+        .arg("--keyring")
+        .arg(&keyring_path)
+        .arg(signature)
+        .arg(signed)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output();
+    drop(dir);
+
+    let output = match output {
+        Ok(output) => output,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok(Status::Unchecked("gpgv is not installed (it comes with GnuPG)".to_string()));
+        }
+        Err(e) => return Err(e),
+    };
+
+    Ok(gpgv_status(&String::from_utf8_lossy(&output.stdout), output.status.success(), signature))
+}
+
+/// Reads what gpgv printed on its status fd, "[GNUPG:] GOODSIG <key id> <user id>" and friends
+/// (see doc/DETAILS in GnuPG)
+///
+/// The file holds several signatures in some cases, so anything wrong with any of them wins over a
+/// good one. Trusted needs both GOODSIG and VALIDSIG, and gpgv exiting happily
+fn gpgv_status(status: &str, success: bool, signature: &Path) -> Status {
+    let mut found = Vec::new();
+    for line in status.lines() {
+        let Some(line) = line.strip_prefix("[GNUPG:] ") else {
+            continue;
+        };
+        let (keyword, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let (key, user) = rest.split_once(' ').unwrap_or((rest, ""));
+        found.push((keyword, key, user));
+    }
+    let first = |keyword: &str| found.iter().find(|(k, _, _)| *k == keyword).map(|&(_, key, user)| (key, user));
+
+    if let Some((_, user)) = first("BADSIG") {
+        return Status::Bad(format!("does not match, signed by {}", user));
+    }
+    if let Some((_, user)) = first("REVKEYSIG") {
+        return Status::Bad(format!("signed with a revoked key, {}", user));
+    }
+    // gpgv also says ERRSIG for a missing key, NO_PUBKEY tells the two apart
+    if let Some((key, _)) = first("NO_PUBKEY") {
+        return Status::UnknownKey(format!("key {}", key));
+    }
+    if let Some((_, user)) = first("EXPKEYSIG") {
+        return Status::Unchecked(format!("signed with an expired key, {}", user));
+    }
+    if let Some((key, _)) = first("ERRSIG") {
+        return Status::Unchecked(format!("gpgv could not check the signature by key {}", key));
+    }
+    match first("GOODSIG") {
+        Some((key, user)) if success && first("VALIDSIG").is_some() => Status::Trusted(format!("{} ({})", user, key)),
+        _ => Status::Unchecked(format!("gpgv could not read {}", signature.display())),
+    }
+}
+
+/// A new folder in the temp folder that only this user can get into, deleted when dropped
+struct PrivateDir(PathBuf);
+
+impl PrivateDir {
+    fn new() -> Result<PrivateDir> {
+        let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.subsec_nanos());
+        let mut builder = fs::DirBuilder::new();
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+
+        // Creating it fails if the name is taken (by a file, folder or symlink), then another name is tried
+        let mut tries = 0;
+        loop {
+            let path = std::env::temp_dir().join(format!("tetcher-{}-{:08x}", std::process::id(), nanos.wrapping_add(tries)));
+            match builder.create(&path) {
+                Ok(()) => return Ok(PrivateDir(path)),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists && tries < 16 => tries += 1,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Drop for PrivateDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Creates a new file that only this user can read, failing if anything is there already
+fn create_private(path: &Path) -> Result<File> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)
+}
+
+/// The non-empty lines of a minisign key or signature file
+fn minisign_lines(text: &str) -> Vec<&str> {
+    text.lines().map(str::trim).filter(|l| !l.is_empty()).collect()
+}
+
+/// minisign shows key ids as a hex number, the 8 bytes read little-endian
+fn key_id_hex(id: &[u8]) -> String {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(id);
+    format!("{:016X}", u64::from_le_bytes(bytes))
+}
+
+/// Checks a .minisig file against the minisign public keys in `keys`
+///
+/// A signature file is four lines: an untrusted comment, base64 of "Ed"/"ED" + key id (8 bytes) +
+/// signature (64 bytes), a trusted comment, and base64 of a signature over signature + trusted comment
+/// "ED" signs the BLAKE2b-512 of the file rather than the file itself
+fn check_minisign(signed: &Path, signature: &Path, keys: &[PathBuf]) -> Result<Status> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("{} is not a minisign signature", signature.display()));
+
+    let text = fs::read_to_string(signature)?;
+    let lines = minisign_lines(&text);
+    let [_, sig_line, trusted_line, global_line, ..] = lines.as_slice() else {
+        return Err(invalid());
+    };
+    let sig = STANDARD.decode(sig_line).map_err(|_| invalid())?;
+    let global = STANDARD.decode(global_line).map_err(|_| invalid())?;
+    let trusted_comment = trusted_line.strip_prefix("trusted comment: ").ok_or_else(invalid)?;
+    if sig.len() != 74 || global.len() != 64 {
+        return Err(invalid());
+    }
+    let (algorithm, key_id, sig_bytes) = (&sig[..2], &sig[2..10], &sig[10..]);
+
+    // Find the public key with the same id: base64 of "Ed" + key id (8 bytes) + key (32 bytes)
+    let key = keys.iter().find_map(|path| {
+        let text = fs::read_to_string(path).ok()?;
+        let data = minisign_lines(&text).iter().find_map(|l| STANDARD.decode(l).ok().filter(|d| d.len() == 42))?;
+        (&data[2..10] == key_id).then_some((path, data))
+    });
+    let Some((key_path, key_data)) = key else {
+        return Ok(Status::UnknownKey(format!("minisign key {}", key_id_hex(key_id))));
+    };
+    let key = VerifyingKey::try_from(&key_data[10..]).map_err(|_| invalid())?;
+
+    let message = match algorithm {
+        b"ED" => {
+            let mut hasher = Blake2b512::new();
+            io::copy(&mut File::open(signed)?, &mut hasher)?;
+            hasher.finalize().to_vec()
+        }
+        b"Ed" => {
+            let mut data = Vec::new();
+            File::open(signed)?.read_to_end(&mut data)?;
+            data
+        }
+        _ => return Err(invalid()),
+    };
+
+    let sig_bytes = Ed25519Signature::from_slice(sig_bytes).map_err(|_| invalid())?;
+    if key.verify(&message, &sig_bytes).is_err() {
+        return Ok(Status::Bad("does not match the file".to_string()));
+    }
+
+    // The trusted comment (usually a timestamp and the file name) is signed too
+    let mut global_message = sig[10..].to_vec();
+    global_message.extend_from_slice(trusted_comment.as_bytes());
+    let global = Ed25519Signature::from_slice(&global).map_err(|_| invalid())?;
+    if key.verify(&global_message, &global).is_err() {
+        return Ok(Status::Bad("the trusted comment was changed".to_string()));
+    }
+
+    let key_name = key_path.file_name().map_or(String::new(), |n| n.to_string_lossy().into_owned());
+    // Trusted comments are usually "timestamp:... <tab> file:...", tabs make a mess on screen
+    let comment = trusted_comment.replace('\t', ", ");
+    Ok(Status::Trusted(format!("minisign key {} ({}), {}", key_id_hex(key_id), key_name, comment)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempPath;
+
+    const GOOD: &str = "[GNUPG:] NEWSIG\n[GNUPG:] GOODSIG 305957D28A040317 Test Signer <t@example.org>\n[GNUPG:] VALIDSIG 0123456789ABCDEF 2026-03-17 1773757501 0 4 0 22 10 00 0123456789ABCDEF\n";
+
+    fn status(output: &str, success: bool) -> Status {
+        gpgv_status(output, success, Path::new("SHA256SUMS.gpg"))
+    }
+
+    #[test]
+    fn good_signature_is_trusted() {
+        assert_eq!(status(GOOD, true), Status::Trusted("Test Signer <t@example.org> (305957D28A040317)".to_string()));
+    }
+
+    #[test]
+    fn good_signature_needs_validsig_and_a_happy_exit() {
+        assert!(matches!(status(GOOD, false), Status::Unchecked(_)));
+        let no_validsig: String = GOOD.lines().filter(|l| !l.contains("VALIDSIG")).map(|l| format!("{}\n", l)).collect();
+        assert!(matches!(status(&no_validsig, true), Status::Unchecked(_)));
+    }
+
+    #[test]
+    fn a_bad_signature_anywhere_wins() {
+        let output = format!("{}[GNUPG:] NEWSIG\n[GNUPG:] BADSIG 1111222233334444 Someone Else\n", GOOD);
+        assert_eq!(status(&output, false), Status::Bad("does not match, signed by Someone Else".to_string()));
+
+        let output = format!("{}[GNUPG:] ERRSIG 1111222233334444 22 10 00 1773757501 4 -\n", GOOD);
+        assert!(matches!(status(&output, true), Status::Unchecked(_)));
+
+        let output = format!("{}[GNUPG:] EXPKEYSIG 1111222233334444 Old Key\n", GOOD);
+        assert!(matches!(status(&output, true), Status::Unchecked(_)));
+    }
+
+    #[test]
+    fn missing_key_is_unknown() {
+        let output = "[GNUPG:] NEWSIG\n[GNUPG:] ERRSIG 305957D28A040317 22 10 00 1773757501 9 -\n[GNUPG:] NO_PUBKEY 305957D28A040317\n";
+        assert_eq!(status(output, false), Status::UnknownKey("key 305957D28A040317".to_string()));
+    }
+
+    #[test]
+    fn nothing_readable_is_unchecked() {
+        assert!(matches!(status("", false), Status::Unchecked(_)));
+        assert!(matches!(status("gpgv: garbage\n", true), Status::Unchecked(_)));
+    }
+
+    #[test]
+    fn private_dir_is_removed_when_dropped() {
+        let dir = PrivateDir::new().unwrap();
+        let path = dir.0.clone();
+        create_private(&path.join("keyring.gpg")).unwrap();
+        assert!(create_private(&path.join("keyring.gpg")).is_err());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o700);
+        }
+        drop(dir);
+        assert!(!path.exists());
+    }
+
+    /// A minisign public key file and a signature over `data` made with it, `prehashed` for "ED"
+    fn minisign(data: &[u8], prehashed: bool, trusted_comment: &str) -> (String, String) {
+        use ed25519_dalek::{Signer, SigningKey};
+
+        let key = SigningKey::from_bytes(&[7u8; 32]);
+        let id = [1, 2, 3, 4, 5, 6, 7, 8];
+        let mut public = b"Ed".to_vec();
+        public.extend(id);
+        public.extend(key.verifying_key().as_bytes());
+
+        let (algorithm, message) = match prehashed {
+            true => (b"ED", Blake2b512::digest(data).to_vec()),
+            false => (b"Ed", data.to_vec()),
+        };
+        let mut sig = algorithm.to_vec();
+        sig.extend(id);
+        sig.extend(key.sign(&message).to_bytes());
+        let mut global_message = sig[10..].to_vec();
+        global_message.extend(trusted_comment.as_bytes());
+        let global = key.sign(&global_message).to_bytes();
+
+        let public = format!("untrusted comment: minisign public key 0807060504030201\n{}\n", STANDARD.encode(public));
+        let signature = format!(
+            "untrusted comment: signature from minisign secret key\n{}\ntrusted comment: {}\n{}\n",
+            STANDARD.encode(sig),
+            trusted_comment,
+            STANDARD.encode(global)
+        );
+        (public, signature)
+    }
+
+    /// Writes the key, the signed file and its signature, and checks it, the way `check` would
+    fn check_files(name: &str, key: &str, signed: &[u8], signature: &str) -> Result<Status> {
+        let dir = TempPath::dir(name);
+        let (key_path, signed_path, signature_path) = (dir.join("release.pub"), dir.join("SHA256SUMS"), dir.join("SHA256SUMS.minisig"));
+        fs::write(&key_path, key).unwrap();
+        fs::write(&signed_path, signed).unwrap();
+        fs::write(&signature_path, signature).unwrap();
+        check_minisign(&signed_path, &signature_path, &[key_path])
+    }
+
+    #[test]
+    fn minisign_signatures_are_trusted() {
+        for prehashed in [true, false] {
+            let (key, signature) = minisign(b"abc  debian.iso\n", prehashed, "timestamp:1773757501\tfile:SHA256SUMS");
+            let status = check_files(&format!("good-{}", prehashed), &key, b"abc  debian.iso\n", &signature).unwrap();
+            assert_eq!(
+                status,
+                Status::Trusted("minisign key 0807060504030201 (release.pub), timestamp:1773757501, file:SHA256SUMS".to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn minisign_catches_changes() {
+        let (key, signature) = minisign(b"abc  debian.iso\n", true, "timestamp:1773757501");
+        let status = check_files("changed-file", &key, b"abd  debian.iso\n", &signature).unwrap();
+        assert_eq!(status, Status::Bad("does not match the file".to_string()));
+
+        let changed = signature.replace("timestamp:1773757501", "timestamp:1773757502");
+        let status = check_files("changed-comment", &key, b"abc  debian.iso\n", &changed).unwrap();
+        assert_eq!(status, Status::Bad("the trusted comment was changed".to_string()));
+    }
+
+    #[test]
+    fn minisign_unknown_keys_and_garbage() {
+        let (key, signature) = minisign(b"data", true, "c");
+        let status = check_files("no-key", "", b"data", &signature).unwrap();
+        assert_eq!(status, Status::UnknownKey("minisign key 0807060504030201".to_string()));
+
+        assert!(check_files("garbage", &key, b"data", "untrusted comment: x\nnot base64\n").is_err());
+        let short = signature.replace("trusted comment: c", "comment: c");
+        assert!(check_files("no-trusted-comment", &key, b"data", &short).is_err());
+    }
+
+    #[test]
+    fn unsigned_files_have_no_check() {
+        let path = TempPath::file("unsigned", "abc");
+        assert!(check(&path).is_none());
+    }
+}
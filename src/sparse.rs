@@ -0,0 +1,306 @@
+//! sparse.rs lets flash.rs skip the blocks of an image that are all zeros instead of writing them
+//!
+//! Most disk images are mostly empty space, so this can save a lot of time. The drive still has to
+//! read back exactly like the image though, so the skipped parts cannot just be left as they were:
+//! - the whole drive is discarded (TRIM) first, and if the drive promises discarded blocks read
+//!   back as zeros, the skipped parts are simply seeked over
+//! - otherwise each skipped run is zeroed with BLKZEROOUT, which the drive can often do without
+//!   transferring any data (and the kernel falls back to writing zeros where it cannot)
+//! - plain files get holes punched into them instead
+//!
+//! Elsewhere than Linux the zeros are simply written, the result is the same but nothing is saved
+
+use std::fs::File;
+use std::io::Result;
+
+use crate::devio::{BlockWriter, DeviceWriter};
+
+/// Runs of zeros are looked for in pieces of this size, a multiple of every sector size
+const PIECE: usize = 64 * 1024;
+
+/// How the zero runs get onto the target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZeroMethod {
+    /// The target already reads back as zeros, nothing needs doing
+    Seek,
+    /// Block device: BLKZEROOUT the range
+    ZeroOut,
+    /// Plain file: punch a hole over the range
+    PunchHole,
+    /// Write the zeros out like any other data
+    Write,
+}
+
+/// True if `buf` holds nothing but zeros
+pub fn is_zero(buf: &[u8]) -> bool {
+    // Comparing 16 bytes at a time is a good deal faster than byte by byte
+    let mut chunks = buf.chunks_exact(16);
+    chunks.all(|c| c == [0u8; 16]) && chunks.remainder().iter().all(|&b| b == 0)
+}
+
+/// Linux: BLKDISCARD and BLKZEROOUT, both take a [start, length] pair in bytes
+#[cfg(target_os = "linux")]
+const BLKDISCARD: libc::c_ulong = 0x1277;
+#[cfg(target_os = "linux")]
+const BLKZEROOUT: libc::c_ulong = 0x127f;
+
+/// Linux: runs one of the range ioctls on a block device
+#[cfg(target_os = "linux")]
+fn range_ioctl(file: &File, request: libc::c_ulong, start: u64, len: u64) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let range: [u64; 2] = [start, len];
+    // SAFETY: both ioctls only read the two u64s in `range`
+    if unsafe { libc::ioctl(file.as_raw_fd(), request, &range) } == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+/// Linux: whether the drive promises that discarded blocks read back as zeros
+///
+/// Newer kernels always say no here, there is no reliable way to know, so BLKZEROOUT is used
+#[cfg(target_os = "linux")]
+fn discard_zeroes_data(device: &str) -> bool {
+    let Ok(real) = std::fs::canonicalize(device) else {
+        return false;
+    };
+    let name = real.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    // Partitions share their disk's queue
+    ["queue", "../queue"].iter().any(|queue| {
+        std::fs::read_to_string(format!("/sys/class/block/{}/{}/discard_zeroes_data", name, queue))
+            .is_ok_and(|v| v.trim() == "1")
+    })
+}
+
+/// Gets the target ready for skipping zeros, and works out how the skipped parts will be zeroed
+///
+/// Block devices are discarded as a whole here (unless `discard` is false), which is why this must
+/// only run once the user has agreed to overwrite the drive
+fn prepare(file: &File, device: &str, discard: bool) -> ZeroMethod {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::fs::FileTypeExt;
+
+        let Ok(metadata) = file.metadata() else {
+            return ZeroMethod::Write;
+        };
+        if metadata.is_file() {
+            return ZeroMethod::PunchHole;
+        }
+        if !metadata.file_type().is_block_device() {
+            return ZeroMethod::Write;
+        }
+        if !discard {
+            return ZeroMethod::ZeroOut;
+        }
+
+        // A drive that cannot discard is still zeroed properly with BLKZEROOUT
+        let discarded = crate::targ::device_size(device)
+            .ok()
+            .flatten()
+            .is_some_and(|size| range_ioctl(file, BLKDISCARD, 0, size).is_ok());
+        if discarded && discard_zeroes_data(device) { ZeroMethod::Seek } else { ZeroMethod::ZeroOut }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (file, device, discard);
+        ZeroMethod::Write
+    }
+}
+
+/// Writes blocks to the target, skipping over the runs of zeros in them
+///
+/// Neighbouring runs of zeros are joined up, so a long empty stretch is zeroed in one go
+pub struct SparseWriter {
+    device: DeviceWriter,
+    method: ZeroMethod,
+    /// Where the next byte of the image goes
+    pos: u64,
+    /// Start of the run of zeros not yet dealt with, it ends at `pos`
+    zeros_from: Option<u64>,
+    /// Total bytes of zeros that were not written
+    skipped: u64,
+}
+
+impl SparseWriter {
+    /// Gets the target ready (see `prepare`) and wraps it
+    pub fn new(device: DeviceWriter, target: &str) -> SparseWriter {
+        let method = prepare(device.file(), target, true);
+        SparseWriter { device, method, pos: 0, zeros_from: None, skipped: 0 }
+    }
+
+    /// For carrying on an earlier flash (see resume.rs): the drive is not discarded again, that
+    /// would throw away what is already on it, so the skipped parts are zeroed one by one instead
+    pub fn resume(device: DeviceWriter, target: &str) -> SparseWriter {
+        let method = prepare(device.file(), target, false);
+        SparseWriter { device, method, pos: 0, zeros_from: None, skipped: 0 }
+    }
+
+    /// Deals with the run of zeros that ends at `pos`, if there is one
+    fn flush_zeros(&mut self) -> Result<()> {
+        let Some(start) = self.zeros_from.take() else {
+            return Ok(());
+        };
+        let len = self.pos - start;
+        self.skipped += len;
+
+        match self.method {
+            ZeroMethod::Seek => Ok(()),
+            #[cfg(target_os = "linux")]
+            ZeroMethod::ZeroOut => {
+                // Zero-out can still be refused, writing the zeros always works
+                if range_ioctl(self.device.file(), BLKZEROOUT, start, len).is_err() {
+                    self.write_zeros(start, len)?;
+                }
+                Ok(())
+            }
+            #[cfg(target_os = "linux")]
+            ZeroMethod::PunchHole => {
+                use std::os::unix::io::AsRawFd;
+
+                // Past the end of the file there is nothing to punch, `finish` extends it instead
+                // SAFETY: fallocate only takes plain integers
+                let punched = unsafe {
+                    libc::fallocate(
+                        self.device.file().as_raw_fd(),
+                        libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                        start as libc::off_t,
+                        len as libc::off_t,
+                    )
+                } == 0;
+                if !punched {
+                    self.write_zeros(start, len)?;
+                }
+                Ok(())
+            }
+            _ => self.write_zeros(start, len),
+        }
+    }
+
+    /// Writes `len` zeros at `start`, for when nothing cleverer works
+    fn write_zeros(&mut self, start: u64, len: u64) -> Result<()> {
+        let zeros = vec![0u8; PIECE];
+        let mut done = 0;
+        while done < len {
+            let n = (len - done).min(PIECE as u64) as usize;
+            self.device.write_at(start + done, &zeros[..n])?;
+            done += n as u64;
+        }
+        Ok(())
+    }
+}
+
+impl BlockWriter for SparseWriter {
+    fn write_block(&mut self, block: &[u8]) -> Result<()> {
+        for piece in block.chunks(PIECE) {
+            // A short last piece can be any length, and BLKZEROOUT needs whole sectors, so it is written
+            if piece.len() == PIECE && is_zero(piece) {
+                self.zeros_from.get_or_insert(self.pos);
+            } else {
+                self.flush_zeros()?;
+                self.device.write_at(self.pos, piece)?;
+            }
+            self.pos += piece.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn skip(&mut self, len: u64) {
+        self.pos += len;
+    }
+
+    fn device(&mut self) -> &mut DeviceWriter {
+        &mut self.device
+    }
+
+    /// Zeroes the run of zeros so far first, so the progress (and the resume journal) never counts
+    /// zeros that are not on the drive yet
+    fn checkpoint(&mut self) -> Result<bool> {
+        self.flush_zeros()?;
+        self.device.checkpoint()
+    }
+
+    /// Deals with any zeros left at the end and flushes the target
+    ///
+    /// A plain file shorter than the image is grown to its full size, the new part reads as zeros
+    fn finish(&mut self) -> Result<u64> {
+        self.flush_zeros()?;
+        let metadata = self.device.file().metadata()?;
+        if metadata.is_file() && metadata.len() < self.pos {
+            self.device.file().set_len(self.pos)?;
+        }
+        self.device.finish()?;
+        Ok(self.skipped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempPath;
+
+    #[test]
+    fn zeros_are_found_in_any_length() {
+        assert!(is_zero(&[]));
+        assert!(is_zero(&[0u8; 15]));
+        assert!(is_zero(&[0u8; 4096]));
+        let mut buf = [0u8; 33];
+        buf[32] = 1;
+        assert!(!is_zero(&buf));
+        buf[32] = 0;
+        buf[0] = 1;
+        assert!(!is_zero(&buf));
+    }
+
+    /// Flashes `blocks` onto a file holding `existing`, returning what it reads back and how much was skipped
+    fn flash(name: &str, existing: &[u8], skip: u64, blocks: &[Vec<u8>]) -> (Vec<u8>, u64) {
+        let path = TempPath::file(name, existing);
+        let target = path.as_str();
+
+        let device = DeviceWriter::open(target).unwrap();
+        let mut writer = if skip > 0 { SparseWriter::resume(device, target) } else { SparseWriter::new(device, target) };
+        #[cfg(target_os = "linux")]
+        assert_eq!(writer.method, ZeroMethod::PunchHole);
+        writer.skip(skip);
+        for block in blocks {
+            writer.write_block(block).unwrap();
+        }
+        let skipped = writer.finish().unwrap();
+
+        (std::fs::read(&path).unwrap(), skipped)
+    }
+
+    #[test]
+    fn zero_runs_are_skipped_but_read_back_as_zeros() {
+        let first = [vec![1u8; PIECE], vec![0u8; 2 * PIECE]].concat();
+        let second = [vec![0u8; PIECE], vec![2u8; PIECE], vec![0u8; 100]].concat();
+        let image = [first.clone(), second.clone()].concat();
+
+        // Whatever was on the drive before must not show through where zeros were skipped
+        let (written, skipped) = flash("over.img", &vec![0xffu8; image.len()], 0, &[first, second]);
+        assert!(written == image);
+        // The run carries on across blocks, the short zeros at the end are written
+        assert_eq!(skipped, 3 * PIECE as u64);
+    }
+
+    #[test]
+    fn files_grow_to_the_image_size() {
+        let (written, skipped) = flash("grow.img", &[], 0, &[[vec![3u8; PIECE], vec![0u8; 2 * PIECE]].concat()]);
+        assert_eq!(written.len(), 3 * PIECE);
+        assert!(written[..PIECE].iter().all(|&b| b == 3) && is_zero(&written[PIECE..]));
+        assert_eq!(skipped, 2 * PIECE as u64);
+    }
+
+    #[test]
+    fn resumed_flashes_leave_the_start_alone() {
+        let existing = vec![9u8; 3 * PIECE];
+        let (written, skipped) = flash("resume.img", &existing, PIECE as u64, &[vec![0u8; PIECE], vec![4u8; PIECE]]);
+        assert!(written[..PIECE].iter().all(|&b| b == 9));
+        assert!(is_zero(&written[PIECE..2 * PIECE]));
+        assert!(written[2 * PIECE..].iter().all(|&b| b == 4));
+        assert_eq!(skipped, PIECE as u64);
+    }
+}
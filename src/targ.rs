@@ -1,8 +1,8 @@
 #![allow(dead_code)]
 
+use std::collections::HashSet;
 use std::fs;
 use std::io::{self, Result, Write, stdout};
-use std::path::PathBuf;
 use crossterm::terminal::disable_raw_mode;
 use crossterm::{
     execute,
@@ -12,32 +12,96 @@ use crossterm::{
     event::{self, KeyCode, Event},
 };
 use std::process::Command;
+use std::time::{Duration, Instant};
 use crate::flash;
+use crate::image::{ImageReader, ImageSource};
+use crate::ui;
+use crate::units;
 
 /// targ.rs will list all external drives and their model names to select from
 /// 
 /// For example, on windows it might list: "\\.\PHYSICALDRIVE1 - SanDisk Ultra USB 64G"
 /// 
-/// If there are no external devices found, it will print "No removeable drives found
-///                                                        Please plug in a USB and restart the program"
-
-
+/// If there are no external devices found, it waits for one to be plugged in
+///
 /// Unified structure for displaying drives
+///
+/// Everything but the path is optional, as not every OS (or every drive) reports it
+#[derive(Debug, Clone, Default)]
+pub struct DriveInfo {
+    pub path: String,
+    pub model: Option<String>,
+    pub vendor: Option<String>,
+    pub serial: Option<String>,
+    /// Capacity in bytes
+    pub size: Option<u64>,
+    /// How the drive is attached: "usb", "mmc", "nvme", "sata", ...
+    pub transport: Option<String>,
+    pub read_only: bool,
+    pub partitions: Vec<PartitionInfo>,
+}
+
+/// One partition that currently exists on a drive
 #[derive(Debug, Clone)]
-struct DriveInfo {
-    path: String,
-    model: Option<String>,
+pub struct PartitionInfo {
+    /// e.g. "sdb1"
+    pub name: String,
+    /// Size in bytes
+    pub size: u64,
 }
 
-/// Windows: list removable drives with model names
+impl DriveInfo {
+    /// One line for the drive lists, e.g. "/dev/sdb — SanDisk Ultra (usb, 57.30 GiB, serial 4C53...)"
+    pub fn label(&self) -> String {
+        let mut label = self.path.clone();
+
+        let name: Vec<&str> = [&self.vendor, &self.model].iter().filter_map(|s| s.as_deref()).collect();
+        if !name.is_empty() {
+            label.push_str(&format!(" — {}", name.join(" ")));
+        }
+
+        let mut extra = Vec::new();
+        if let Some(transport) = &self.transport {
+            extra.push(transport.clone());
+        }
+        if let Some(size) = self.size {
+            extra.push(units::human_size(size));
+        }
+        if let Some(serial) = &self.serial {
+            extra.push(format!("serial {}", serial));
+        }
+        if self.read_only {
+            extra.push("READ-ONLY".to_string());
+        }
+        if !extra.is_empty() {
+            label.push_str(&format!(" ({})", extra.join(", ")));
+        }
+
+        label
+    }
+
+    /// Several lines for the confirmation screen: the label plus the partitions on the drive
+    pub fn details(&self) -> String {
+        let mut details = self.label();
+        if self.partitions.is_empty() {
+            details.push_str("\n    no partitions");
+        }
+        for part in &self.partitions {
+            details.push_str(&format!("\n    {}  {}", part.name, units::human_size(part.size)));
+        }
+        details
+    }
+}
+
+/// Windows: list removable drives with model names, size, serial and interface
 fn list_flashable_drives_windows() -> Vec<DriveInfo> {
     let mut drives = Vec::new();
 
-    // PowerShell query gets both DeviceID and Model
+    // PowerShell prints one "|" separated line per drive, so model names with spaces survive
     let output = Command::new("powershell")
         .args([
             "-Command",
-            "Get-CimInstance Win32_DiskDrive | Where-Object { $_.MediaType -eq 'Removable Media' -or $_.InterfaceType -eq 'USB' } | Select-Object DeviceID, Model",
+            "Get-CimInstance Win32_DiskDrive | Where-Object { $_.MediaType -eq 'Removable Media' -or $_.InterfaceType -eq 'USB' } | ForEach-Object { '{0}|{1}|{2}|{3}|{4}|{5}' -f $_.DeviceID, $_.Model, $_.Size, $_.SerialNumber, $_.InterfaceType, $_.Partitions }",
         ])
         .output()
         .expect("failed to run PowerShell command");
@@ -45,26 +109,26 @@ fn list_flashable_drives_windows() -> Vec<DriveInfo> {
     let text = String::from_utf8_lossy(&output.stdout);
 
     for line in text.lines() {
-        // Skip headers or blank lines
-        if line.trim().is_empty() || line.contains("DeviceID") {
+        let parts: Vec<&str> = line.split('|').map(str::trim).collect();
+        if parts.len() < 6 || !parts[0].starts_with("\\\\.\\") {
             continue;
         }
 
-        // PowerShell columns can be space-padded; split roughly in half
-        let parts: Vec<&str> = line.split_whitespace().collect();
-        if parts.len() >= 2 {
-            let device_id = parts[0].trim().to_string();
-            let model = parts[1..].join(" ");
-            drives.push(DriveInfo {
-                path: device_id,
-                model: Some(model),
-            });
-        } else if parts.len() == 1 && parts[0].starts_with("\\\\.\\") {
-            drives.push(DriveInfo {
-                path: parts[0].trim().to_string(),
-                model: None,
-            });
-        }
+        let non_empty = |s: &str| (!s.is_empty()).then(|| s.to_string());
+        // Windows only reports a partition count, not their sizes
+        let partitions = (1..=parts[5].parse::<usize>().unwrap_or(0))
+            .map(|i| PartitionInfo { name: format!("Partition {}", i), size: 0 })
+            .collect();
+
+        drives.push(DriveInfo {
+            path: parts[0].to_string(),
+            model: non_empty(parts[1]),
+            size: parts[2].parse().ok(),
+            serial: non_empty(parts[3]),
+            transport: non_empty(parts[4]).map(|t| t.to_ascii_lowercase()),
+            partitions,
+            ..Default::default()
+        });
     }
 
     drives
@@ -83,42 +147,90 @@ fn list_flashable_drives_macos() -> Vec<DriveInfo> {
     let text = String::from_utf8_lossy(&output.stdout);
 
     for line in text.lines() {
-        if line.contains("external, physical") {
-            if let Some(disk_name) = line.split_whitespace().next() {
-                let path = format!("/dev/{}", disk_name);
-
-                // Query model using `diskutil info`
-                let info_output = Command::new("diskutil")
-                    .args(["info", &path])
-                    .output()
-                    .unwrap();
-
-                let info_text = String::from_utf8_lossy(&info_output.stdout);
-                let mut model = None;
-
-                for infoline in info_text.lines() {
-                    if infoline.contains("Device / Media Name:") {
-                        model = Some(
-                            infoline
-                                .split(':')
-                                .nth(1)
-                                .unwrap_or("")
-                                .trim()
-                                .to_string(),
-                        );
-                        break;
+        if line.contains("external, physical")
+            && let Some(disk_name) = line.split_whitespace().next() {
+            let path = format!("/dev/{}", disk_name);
+
+            // Query model using `diskutil info`
+            let info_output = Command::new("diskutil")
+                .args(["info", &path])
+                .output()
+                .unwrap();
+
+            let info_text = String::from_utf8_lossy(&info_output.stdout);
+            let mut drive = DriveInfo { path, ..Default::default() };
+
+            for infoline in info_text.lines() {
+                let Some((key, value)) = infoline.split_once(':') else { continue };
+                let value = value.trim();
+                match key.trim() {
+                    "Device / Media Name" => drive.model = Some(value.to_string()),
+                    "Protocol" => drive.transport = Some(value.to_ascii_lowercase()),
+                    "Media Read-Only" | "Read-Only Media" => drive.read_only = value == "Yes",
+                    // e.g. "Disk Size: 15.5 GB (15518924800 Bytes) (exactly 30310400 512-Byte-Units)"
+                    "Disk Size" => {
+                        drive.size = value
+                            .split_once('(')
+                            .and_then(|(_, rest)| rest.split_whitespace().next())
+                            .and_then(|bytes| bytes.parse().ok());
                     }
+                    _ => {}
                 }
-
-                drives.push(DriveInfo { path, model });
             }
+
+            drives.push(drive);
         }
     }
 
     drives
 }
 
-/// Linux: list removable drives with model names
+/// Linux: reads one sysfs attribute, trimmed, `None` if missing or empty
+fn sysfs_attr(path: impl AsRef<std::path::Path>) -> Option<String> {
+    let value = fs::read_to_string(path).ok()?;
+    let value = value.trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+/// Linux: works out how the drive is attached from where it sits in the sysfs device tree
+fn linux_transport(dev: &str, real: &std::path::Path) -> Option<String> {
+    let real = real.to_string_lossy();
+    let transport = if real.contains("/usb") {
+        "usb"
+    } else if real.contains("/mmc") {
+        "mmc"
+    } else if dev.starts_with("nvme") {
+        "nvme"
+    } else if real.contains("/ata") {
+        "sata"
+    } else if real.contains("/virtio") {
+        "virtio"
+    } else {
+        return None;
+    };
+    Some(transport.to_string())
+}
+
+/// Linux: the serial number, from the block device itself (mmc, nvme) or the USB device above it
+fn linux_serial(sys: &std::path::Path) -> Option<String> {
+    if let Some(serial) = sysfs_attr(sys.join("device/serial")) {
+        return Some(serial);
+    }
+
+    // USB sticks: walk up from the SCSI device until the USB device directory, which holds "serial"
+    let mut dir = fs::canonicalize(sys.join("device")).ok()?;
+    while dir.pop() {
+        if let Some(serial) = sysfs_attr(dir.join("serial")) {
+            return Some(serial);
+        }
+        if dir.file_name().is_some_and(|n| n == "devices") {
+            break;
+        }
+    }
+    None
+}
+
+/// Linux: list removable drives (and anything on USB or an SD card slot) with everything sysfs knows
 fn list_flashable_drives_linux() -> Result<Vec<DriveInfo>> {
     let mut drives = Vec::new();
 
@@ -126,157 +238,441 @@ fn list_flashable_drives_linux() -> Result<Vec<DriveInfo>> {
         let entry = entry?;
         let dev_name = entry.file_name();
         let dev_str = dev_name.to_string_lossy();
-        let removable_path = format!("/sys/block/{}/removable", dev_str);
-
-        if let Ok(contents) = fs::read_to_string(&removable_path) {
-            if contents.trim() == "1" {
-                let model_path = format!("/sys/block/{}/device/model", dev_str);
-                let model = fs::read_to_string(&model_path)
-                    .ok()
-                    .map(|s| s.trim().to_string());
-                let dev_path = format!("/dev/{}", dev_str);
-                if fs::metadata(&dev_path).is_ok() {
-                    drives.push(DriveInfo {
-                        path: dev_path,
-                        model,
-                    });
-                }
-            }
+        let sys = entry.path();
+        let real = fs::canonicalize(&sys).unwrap_or_else(|_| sys.clone());
+        let transport = linux_transport(&dev_str, &real);
+
+        // USB SSDs and card readers often claim not to be removable, so go by the bus as well
+        let removable = sysfs_attr(sys.join("removable")).as_deref() == Some("1");
+        let external = matches!(transport.as_deref(), Some("usb") | Some("mmc"));
+        if !removable && !external {
+            continue;
         }
+
+        let dev_path = format!("/dev/{}", dev_str);
+        if fs::metadata(&dev_path).is_err() {
+            continue;
+        }
+
+        // Partitions are sub-directories holding a "partition" file, sizes are in 512 byte sectors
+        let mut partitions: Vec<PartitionInfo> = fs::read_dir(&sys)?
+            .flatten()
+            .filter(|p| p.path().join("partition").exists())
+            .map(|p| PartitionInfo {
+                name: p.file_name().to_string_lossy().into_owned(),
+                size: sysfs_attr(p.path().join("size")).and_then(|s| s.parse::<u64>().ok()).unwrap_or(0) * 512,
+            })
+            .collect();
+        partitions.sort_by(|a, b| a.name.cmp(&b.name));
+
+        drives.push(DriveInfo {
+            path: dev_path,
+            // SD cards keep their product name in "name" rather than "model"
+            model: sysfs_attr(sys.join("device/model")).or_else(|| sysfs_attr(sys.join("device/name"))),
+            vendor: sysfs_attr(sys.join("device/vendor")),
+            serial: linux_serial(&sys),
+            size: sysfs_attr(sys.join("size")).and_then(|s| s.parse::<u64>().ok()).map(|s| s * 512),
+            transport,
+            read_only: sysfs_attr(sys.join("ro")).as_deref() == Some("1"),
+            partitions,
+        });
     }
     Ok(drives)
 }
 
-/// Menu UI for selecting which drive to flash to
-pub fn menu(file_in: &PathBuf) -> Result<()> {
-    print!("\x1B[H\x1B[2J");
-    io::stdout().flush()?;
+/// Lists the removable drives on whichever OS this is
+pub fn list_drives() -> Result<Vec<DriveInfo>> {
+    #[cfg(target_os = "windows")]
+    return Ok(list_flashable_drives_windows());
 
-    let mut extselected = 0;
-    let mut stdout = stdout();
+    #[cfg(target_os = "macos")]
+    return Ok(list_flashable_drives_macos());
 
-    let extdevs: Vec<DriveInfo>;
+    #[cfg(target_os = "linux")]
+    return list_flashable_drives_linux();
+}
 
-    #[cfg(target_os = "windows")]
+/// Linux: BLKGETSIZE64, the size of a block device in bytes
+#[cfg(target_os = "linux")]
+const BLKGETSIZE64: libc::c_ulong = 0x8008_1272;
+
+/// The capacity of the drive at `path` in bytes
+///
+/// `None` if it is not a drive (e.g. a plain file being written to), or the size cannot be found out
+pub fn device_size(path: &str) -> Result<Option<u64>> {
+    #[cfg(target_os = "linux")]
     {
-        extdevs = list_flashable_drives_windows();
-        if extdevs.is_empty() {
-            println!("No removable drives detected.");
-            println!("Insert a USB drive and restart the program.");
-            return Ok(());
+        use std::os::unix::fs::FileTypeExt;
+        use std::os::unix::io::AsRawFd;
+
+        if !fs::metadata(path)?.file_type().is_block_device() {
+            return Ok(None);
+        }
+
+        let file = fs::File::open(path)?;
+        let mut size: u64 = 0;
+        // SAFETY: BLKGETSIZE64 writes a single u64 into `size`
+        if unsafe { libc::ioctl(file.as_raw_fd(), BLKGETSIZE64, &mut size) } == 0 {
+            return Ok(Some(size));
         }
+
+        // The ioctl can be refused, sysfs has the same number in 512 byte sectors
+        let real = fs::canonicalize(path)?;
+        let name = real.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        Ok(sysfs_attr(format!("/sys/class/block/{}/size", name)).and_then(|s| s.parse::<u64>().ok()).map(|s| s * 512))
     }
 
-    #[cfg(target_os = "macos")]
+    #[cfg(not(target_os = "linux"))]
     {
-        extdevs = list_flashable_drives_macos();
-        if extdevs.is_empty() {
-            println!("No removable drives detected.");
-            println!("Insert a USB drive and restart the program.");
-            return Ok(());
+        use std::io::{Seek, SeekFrom};
+
+        if fs::metadata(path).is_ok_and(|m| m.is_file()) {
+            return Ok(None);
+        }
+        // Raw disks on macOS and Windows report their size as their end
+        let end = fs::File::open(path)?.seek(SeekFrom::End(0))?;
+        Ok((end > 0).then_some(end))
+    }
+}
+
+/// Fails with a clear message if an image of `image_size` bytes cannot fit on the drive
+///
+/// Does nothing if either size is unknown, e.g. a gzip image or a plain file as the target
+pub fn check_fits(device: &str, image_size: Option<u64>) -> Result<()> {
+    let (Some(image_size), Some(capacity)) = (image_size, device_size(device)?) else {
+        return Ok(());
+    };
+    if image_size > capacity {
+        return Err(io::Error::new(
+            io::ErrorKind::StorageFull,
+            format!(
+                "{} is too small: the image is {} but the drive only holds {}",
+                device,
+                units::human_size(image_size),
+                units::human_size(capacity)
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// True if the drive is known to be too small for an image of `image_size` bytes
+fn too_small(drive: &DriveInfo, image_size: Option<u64>) -> bool {
+    matches!((drive.size, image_size), (Some(capacity), Some(image)) if image > capacity)
+}
+
+/// Lets the user pick a single drive, for modes that read from a drive rather than flash it
+///
+/// Returns `None` if there are no drives or the user backs out
+pub fn pick_drive(title: &str) -> Result<Option<DriveInfo>> {
+    let drives = list_drives()?;
+    if drives.is_empty() {
+        print!("\x1B[H\x1B[2J");
+        print!("No removable drives detected.\r\nInsert a USB drive and try again.");
+        ui::pause()?;
+        return Ok(None);
+    }
+
+    let labels: Vec<String> = drives.iter().map(DriveInfo::label).collect();
+    let labels: Vec<&str> = labels.iter().map(String::as_str).collect();
+    Ok(ui::choose(&title.blue().bold().to_string(), &labels)?.map(|i| drives[i].clone()))
+}
+
+/// How often the drive list is checked for drives being plugged in or pulled out
+///
+/// Linux is told by the kernel (see `uevent`) and only has to look at /sys/block when something
+/// happened, other systems re-run the whole (slower) listing
+#[cfg(target_os = "linux")]
+const WATCH_INTERVAL: Duration = Duration::from_millis(250);
+#[cfg(not(target_os = "linux"))]
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Notices drives being plugged in or pulled out, so the menus can refresh by themselves
+struct DriveWatcher {
+    last: Vec<String>,
+    last_check: Instant,
+    /// A uevent came in that /sys/block has not been looked at for yet, because it came too soon
+    /// after the last look
+    pending: bool,
+    /// `None` if the kernel's uevents cannot be listened to, /sys/block is then read every `WATCH_INTERVAL`
+    #[cfg(target_os = "linux")]
+    uevents: Option<uevent::Socket>,
+}
+
+impl DriveWatcher {
+    fn new() -> DriveWatcher {
+        DriveWatcher {
+            last: block_devices(),
+            last_check: Instant::now(),
+            pending: false,
+            #[cfg(target_os = "linux")]
+            uevents: uevent::Socket::open(),
+        }
+    }
+
+    /// True if a block device has appeared, gone or changed size since the last time this said so
+    fn changed(&mut self) -> bool {
+        #[cfg(target_os = "linux")]
+        let event = self.uevents.as_ref().map(|uevents| uevents.block_event());
+        #[cfg(not(target_os = "linux"))]
+        let event = None;
+        self.check(event, block_devices)
+    }
+
+    /// `event` is whether a block device uevent came in, `None` without a uevent socket to ask
+    ///
+    /// Reading the socket drains it, so an event that comes too soon after the last look (sdb's
+    /// remove right after sdb1's) is kept as `pending` and looked at on a later call
+    fn check(&mut self, event: Option<bool>, devices: impl FnOnce() -> Vec<String>) -> bool {
+        match event {
+            Some(true) => self.pending = true,
+            Some(false) if !self.pending => return false,
+            _ => {}
+        }
+
+        if self.last_check.elapsed() < WATCH_INTERVAL {
+            return false;
+        }
+        self.last_check = Instant::now();
+        self.pending = false;
+
+        let now = devices();
+        if now == self.last {
+            return false;
         }
+        self.last = now;
+        true
     }
+}
 
+/// Something that changes whenever a drive is plugged in or pulled out
+///
+/// On Linux it is each /sys/block entry with its size, so a card going into an empty reader counts too
+fn block_devices() -> Vec<String> {
     #[cfg(target_os = "linux")]
     {
-        extdevs = list_flashable_drives_linux()?;
-        if extdevs.is_empty() {
-            println!("No removable drives detected.");
-            println!("Insert a USB drive and restart the program.");
-            return Ok(());
+        let mut devices: Vec<String> = fs::read_dir("/sys/block")
+            .map(|entries| {
+                entries
+                    .flatten()
+                    .map(|e| {
+                        let size = sysfs_attr(e.path().join("size")).unwrap_or_default();
+                        format!("{}={}", e.file_name().to_string_lossy(), size)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        devices.sort();
+        devices
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    list_drives()
+        .map(|drives| drives.iter().map(|d| format!("{}={:?}={:?}", d.path, d.serial, d.size)).collect())
+        .unwrap_or_default()
+}
+
+/// Listens to the kernel's uevents, which say when a block device comes, goes or changes
+#[cfg(target_os = "linux")]
+mod uevent {
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+    pub struct Socket(OwnedFd);
+
+    impl Socket {
+        /// `None` if it is not allowed, e.g. in some containers
+        pub fn open() -> Option<Socket> {
+            // SAFETY: plain socket calls, the address is a zeroed sockaddr_nl of the right size
+            unsafe {
+                let fd = libc::socket(
+                    libc::AF_NETLINK,
+                    libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                    libc::NETLINK_KOBJECT_UEVENT,
+                );
+                if fd < 0 {
+                    return None;
+                }
+                let socket = Socket(OwnedFd::from_raw_fd(fd));
+
+                let mut addr: libc::sockaddr_nl = std::mem::zeroed();
+                addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+                // The kernel's own uevents, rather than the ones udev passes on after it
+                addr.nl_groups = 1;
+                let len = std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t;
+                (libc::bind(fd, (&raw const addr).cast(), len) == 0).then_some(socket)
+            }
         }
+
+        /// Reads every uevent that has come in, true if any of them was about a block device
+        pub fn block_event(&self) -> bool {
+            let mut buf = [0u8; 8192];
+            let mut block = false;
+            loop {
+                // SAFETY: reads into `buf`, at most its length. The socket does not block
+                let n = unsafe { libc::recv(self.0.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len(), 0) };
+                if n <= 0 {
+                    return block;
+                }
+                // e.g. "add@/devices/...\0ACTION=add\0...\0SUBSYSTEM=block\0..."
+                block |= buf[..n as usize].split(|&b| b == 0).any(|field| field == b"SUBSYSTEM=block");
+            }
+        }
+    }
+}
+
+/// True if `drive` is still plugged in as the same physical drive, not just something else at the same path
+fn still_present(drive: &DriveInfo, drives: &[DriveInfo]) -> bool {
+    drives
+        .iter()
+        .any(|d| d.path == drive.path && d.serial == drive.serial && d.size == drive.size)
+}
+
+/// Fails if any of the drives was pulled out or replaced since it was picked, e.g. another stick
+/// plugged in while the final warning was up and got the same /dev/sdX
+pub fn ensure_unchanged(drives: &[DriveInfo]) -> Result<()> {
+    let now = list_drives()?;
+    match drives.iter().find(|d| !still_present(d, &now)) {
+        Some(gone) => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} is no longer the drive that was picked (pulled out or replaced), nothing was written", gone.path),
+        )),
+        None => Ok(()),
     }
+}
 
+/// Menu UI for selecting which drive (or drives) to flash to
+///
+/// Space ticks several drives to flash them all at once, Enter with nothing ticked uses the highlighted drive
+///
+/// The list refreshes by itself as drives are plugged in and pulled out, and waits if there are none
+pub fn menu(file_in: &ImageSource) -> Result<()> {
+    print!("\x1B[H\x1B[2J");
+    io::stdout().flush()?;
+
+    let mut extselected = 0;
+    let mut stdout = stdout();
+
+    let mut watcher = DriveWatcher::new();
+    let mut extdevs = list_drives()?;
+
+    // Drives the image cannot fit on are greyed out, if the image size is known up front
+    let image_size = ImageReader::open(file_in).ok().and_then(|r| r.uncompressed_size);
+
+    // Which drives have been ticked with Space, by path so the ticks survive a refresh
+    let mut ticked: HashSet<String> = HashSet::new();
+    // Shown under the list, e.g. when a drive was pulled out mid-confirmation
+    let mut notice: Option<String> = None;
+
+    // Only redrawn after a key or a change in the drives, redrawing every time round flickers
+    let mut redraw = true;
     loop {
-        println!("External devices found:");
+        if watcher.changed() {
+            let highlighted = extdevs.get(extselected).map(|d| d.path.clone());
+            extdevs = list_drives()?;
+            ticked.retain(|path| extdevs.iter().any(|d| &d.path == path));
+
+            // Keep the highlight on the same drive if it is still there
+            extselected = highlighted
+                .and_then(|path| extdevs.iter().position(|d| d.path == path))
+                .unwrap_or(extselected.min(extdevs.len().saturating_sub(1)));
+            redraw = true;
+        }
 
-        for (i, item) in extdevs.iter().enumerate() {
-            execute!(stdout, cursor::MoveTo(0, (i + 2) as u16))?;
-            execute!(stdout, terminal::Clear(ClearType::CurrentLine))?;
+        if redraw {
+            redraw = false;
+            execute!(stdout, cursor::MoveTo(0, 0), terminal::Clear(ClearType::FromCursorDown))?;
 
-            let label = if let Some(model) = &item.model {
-                format!("{} — {}", item.path, model)
+            if extdevs.is_empty() {
+                println!("No removable drives detected.\r");
+                print!("Insert a USB drive, it will show up here.");
             } else {
-                item.path.clone()
-            };
+                print!("External devices found:");
+            }
 
-            if i == extselected {
-                print!("  {}", label.on_white().black());
-            } else {
-                print!("  {}", label);
+            for (i, item) in extdevs.iter().enumerate() {
+                execute!(stdout, cursor::MoveTo(0, (i + 2) as u16))?;
+
+                let tick = if ticked.contains(&item.path) { "[x]" } else { "[ ]" };
+                let mut label = format!("{} {}", tick, item.label());
+                let small = too_small(item, image_size);
+                if small {
+                    label.push_str(&format!(" — too small, the image is {}", units::human_size(image_size.unwrap_or(0))));
+                }
+
+                if i == extselected {
+                    print!("  {}", label.on_white().black());
+                } else if small {
+                    print!("  {}", label.dark_grey());
+                } else {
+                    print!("  {}", label);
+                }
+            }
+
+            let footer = extdevs.len().max(1) + 3;
+            if let Some(msg) = &notice {
+                execute!(stdout, cursor::MoveTo(0, footer as u16))?;
+                print!("{}", msg.as_str().red());
             }
+            execute!(stdout, cursor::MoveTo(0, (footer + 1) as u16))?;
+            print!("{}", "Space: tick several drives to flash at once   Enter: continue   Esc: back".dark_grey());
+
+            stdout.flush()?;
         }
 
-        stdout.flush()?;
+        // Wake up now and then to look for drives, rather than blocking until a key is pressed
+        if !event::poll(WATCH_INTERVAL)? {
+            continue;
+        }
+        redraw = true;
 
         if let Event::Key(ev) = event::read()? {
             match ev.code {
-                KeyCode::Up => {
-                    if extselected > 0 {
-                        extselected -= 1;
-                    }
+                KeyCode::Up => extselected = extselected.saturating_sub(1),
+                KeyCode::Down if extselected + 1 < extdevs.len() => extselected += 1,
+                // A drive that is too small cannot be ticked, nor used on its own with Enter. With
+                // drives ticked, Enter goes by those wherever the highlight is
+                KeyCode::Char(' ') | KeyCode::Enter
+                    if (ev.code == KeyCode::Char(' ') || ticked.is_empty())
+                        && extdevs.get(extselected).is_some_and(|d| too_small(d, image_size)) =>
+                {
+                    notice = Some(format!("{} is too small for this image", extdevs[extselected].path));
                 }
-                KeyCode::Down => {
-                    if extselected < extdevs.len() - 1 {
-                        extselected += 1;
+                KeyCode::Char(' ') if !extdevs.is_empty() => {
+                    let path = &extdevs[extselected].path;
+                    if !ticked.remove(path) {
+                        ticked.insert(path.clone());
                     }
                 }
-                KeyCode::Enter => {
-                    let mut confselected: usize = 0;
-                    let conf: Vec<&str> = vec!["Yes", "No"];
-                    let selected_device = extdevs[extselected].clone();
-
-                    loop {
-                        print!("\x1B[H\x1B[2J");
-                        println!(
-                            "Do you want to flash {} to {}?",
-                            file_in.to_string_lossy(),
-                            selected_device.path
-                        );
-                        for (i, confitem) in conf.iter().enumerate() {
-                            execute!(stdout, cursor::MoveTo(0, (i + 2) as u16))?;
-                            execute!(stdout, terminal::Clear(ClearType::CurrentLine))?;
-                            if i == confselected {
-                                print!("{}", confitem.on_white().black());
-                            } else {
-                                print!("{}", confitem);
-                            }
-                        }
+                KeyCode::Enter if !extdevs.is_empty() => {
+                    notice = None;
+
+                    // Ticked drives win, otherwise just the highlighted one
+                    let mut selected_drives: Vec<DriveInfo> =
+                        extdevs.iter().filter(|d| ticked.contains(&d.path)).cloned().collect();
+                    if selected_drives.is_empty() {
+                        selected_drives.push(extdevs[extselected].clone());
+                    }
 
-                        stdout.flush()?;
-
-                        use std::process::exit;
-
-                        if let Event::Key(confev) = event::read()? {
-                            match confev.code {
-                                KeyCode::Up => {
-                                    if confselected > 0 {
-                                        confselected -= 1;
-                                    }
-                                }
-                                KeyCode::Down => {
-                                    if confselected < conf.len() - 1 {
-                                        confselected += 1;
-                                    }
-                                }
-                                KeyCode::Enter => match confselected {
-                                    0 => {
-                                        execute!(
-                                            stdout,
-                                            terminal::Clear(ClearType::All),
-                                            cursor::MoveTo(0, 0)
-                                        )?;
-                                        flash::menu(&file_in.to_string_lossy(), &selected_device.path)?;
-                                        disable_raw_mode()?;
-                                        execute!(stdout, cursor::Show)?;
-                                        std::process::exit(0);
-
-                                    }
-                                    1 => exit(0),
-                                    _ => {}
-                                },
-                                _ => {}
-                            }
+                    match confirm_drives(file_in, &selected_drives, &mut watcher)? {
+                        Confirmation::Yes => {
+                            execute!(
+                                stdout,
+                                terminal::Clear(ClearType::All),
+                                cursor::MoveTo(0, 0)
+                            )?;
+                            flash::menu(file_in, &selected_drives)?;
+                            disable_raw_mode()?;
+                            execute!(stdout, cursor::Show)?;
+                            std::process::exit(0);
+                        }
+                        Confirmation::No => std::process::exit(0),
+                        Confirmation::Back => {}
+                        Confirmation::Removed(path) => {
+                            notice = Some(format!("{} was removed, nothing was written", path));
+                            // The watcher has already seen the change, so refresh the list here
+                            extdevs = list_drives()?;
+                            ticked.retain(|p| extdevs.iter().any(|d| &d.path == p));
+                            extselected = extselected.min(extdevs.len().saturating_sub(1));
                         }
                     }
                 }
@@ -286,3 +682,158 @@ pub fn menu(file_in: &PathBuf) -> Result<()> {
         }
     }
 }
+
+/// How the "Do you want to flash" screen ended
+enum Confirmation {
+    Yes,
+    No,
+    /// Esc, back to the drive list
+    Back,
+    /// One of the drives was pulled out while the question was up
+    Removed(String),
+}
+
+/// Asks whether to flash the chosen drives, showing everything known about them
+///
+/// Keeps an eye on the drives while waiting, a drive that disappears cancels the question
+fn confirm_drives(file_in: &ImageSource, drives: &[DriveInfo], watcher: &mut DriveWatcher) -> Result<Confirmation> {
+    let mut stdout = stdout();
+    let mut confselected: usize = 0;
+    let conf = ["Yes", "No"];
+
+    // The drive details, so two identical sticks can be told apart before anything is written
+    let details: Vec<String> = drives
+        .iter()
+        .flat_map(|d| d.details().lines().map(str::to_string).collect::<Vec<_>>())
+        .collect();
+
+    let mut redraw = true;
+    loop {
+        if watcher.changed() {
+            let now = list_drives()?;
+            if let Some(gone) = drives.iter().find(|d| !still_present(d, &now)) {
+                return Ok(Confirmation::Removed(gone.path.clone()));
+            }
+        }
+
+        if redraw {
+            redraw = false;
+            execute!(stdout, cursor::MoveTo(0, 0), terminal::Clear(ClearType::FromCursorDown))?;
+            print!("Do you want to flash {} to:", file_in);
+            for (i, line) in details.iter().enumerate() {
+                execute!(stdout, cursor::MoveTo(0, (i + 1) as u16))?;
+                print!("  {}", line);
+            }
+            for (i, confitem) in conf.iter().enumerate() {
+                execute!(stdout, cursor::MoveTo(0, (details.len() + i + 2) as u16))?;
+                if i == confselected {
+                    print!("{}", confitem.on_white().black());
+                } else {
+                    print!("{}", confitem);
+                }
+            }
+
+            stdout.flush()?;
+        }
+
+        if !event::poll(WATCH_INTERVAL)? {
+            continue;
+        }
+        redraw = true;
+
+        if let Event::Key(confev) = event::read()? {
+            match confev.code {
+                KeyCode::Up => confselected = confselected.saturating_sub(1),
+                KeyCode::Down if confselected < conf.len() - 1 => confselected += 1,
+                KeyCode::Enter if confselected == 0 => return Ok(Confirmation::Yes),
+                KeyCode::Enter => return Ok(Confirmation::No),
+                KeyCode::Esc => return Ok(Confirmation::Back),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempPath;
+
+    fn stick() -> DriveInfo {
+        DriveInfo {
+            path: "/dev/sdb".to_string(),
+            model: Some("Ultra".to_string()),
+            vendor: Some("SanDisk".to_string()),
+            serial: Some("4C530001230101".to_string()),
+            size: Some(61_530_439_680),
+            transport: Some("usb".to_string()),
+            read_only: false,
+            partitions: vec![PartitionInfo { name: "sdb1".to_string(), size: 268_435_456 }],
+        }
+    }
+
+    #[test]
+    fn labels_show_what_is_known() {
+        assert_eq!(stick().label(), "/dev/sdb — SanDisk Ultra (usb, 57.30 GiB, serial 4C530001230101)");
+        assert_eq!(stick().details(), "/dev/sdb — SanDisk Ultra (usb, 57.30 GiB, serial 4C530001230101)\n    sdb1  256.00 MiB");
+
+        let bare = DriveInfo { path: "/dev/sdc".to_string(), read_only: true, ..Default::default() };
+        assert_eq!(bare.label(), "/dev/sdc (READ-ONLY)");
+        assert_eq!(bare.details(), "/dev/sdc (READ-ONLY)\n    no partitions");
+    }
+
+    #[test]
+    fn a_different_stick_at_the_same_path_is_not_the_same_drive() {
+        let picked = stick();
+        assert!(still_present(&picked, &[stick()]));
+
+        let swapped = DriveInfo { serial: Some("0000".to_string()), ..stick() };
+        assert!(!still_present(&picked, &[swapped]));
+        let resized = DriveInfo { size: Some(1 << 30), ..stick() };
+        assert!(!still_present(&picked, &[resized]));
+        let moved = DriveInfo { path: "/dev/sdc".to_string(), ..stick() };
+        assert!(!still_present(&picked, &[moved]));
+        assert!(!still_present(&picked, &[]));
+    }
+
+    #[test]
+    fn too_small_only_when_both_sizes_are_known() {
+        let drive = stick();
+        assert!(too_small(&drive, Some(61_530_439_681)));
+        assert!(!too_small(&drive, Some(61_530_439_680)));
+        assert!(!too_small(&drive, None));
+        assert!(!too_small(&DriveInfo { size: None, ..stick() }, Some(u64::MAX)));
+    }
+
+    #[test]
+    fn plain_files_have_no_capacity() {
+        let path = TempPath::file("targ", [0u8; 512]);
+        assert_eq!(device_size(path.as_str()).unwrap(), None);
+        // So anything fits, the file just grows
+        assert!(check_fits(path.as_str(), Some(u64::MAX)).is_ok());
+    }
+
+    #[test]
+    fn uevents_too_soon_after_a_look_are_not_lost() {
+        let mut watcher = DriveWatcher {
+            last: vec!["sdb=120127488".to_string(), "sdb1=524288".to_string()],
+            last_check: Instant::now(),
+            pending: false,
+            #[cfg(target_os = "linux")]
+            uevents: None,
+        };
+        let unplugged = || vec!["sda=976773168".to_string()];
+
+        // sdb's remove comes right after sdb1's, /sys/block is not looked at yet
+        assert!(!watcher.check(Some(true), unplugged));
+        assert!(watcher.pending);
+        // Nothing more comes in, but the first event still gets looked at once the interval is up
+        watcher.last_check -= WATCH_INTERVAL;
+        assert!(watcher.check(Some(false), unplugged));
+        assert_eq!(watcher.last, unplugged());
+
+        // And then it is dealt with, /sys/block is left alone until the next event
+        watcher.last_check -= WATCH_INTERVAL;
+        assert!(!watcher.check(Some(false), || panic!("/sys/block read without an event")));
+    }
+}
@@ -0,0 +1,102 @@
+//! testutil.rs holds what the unit tests share, it is only built for `cargo test`
+
+use std::cell::RefCell;
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Keeps the paths of tests running at the same time apart
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// What `rescue::data_dir` gives the test running on this thread, see `DataDir`
+    static DATA_DIR: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+}
+
+/// A file or folder in the temp folder, removed again when dropped, even when an assertion failed
+pub struct TempPath(PathBuf);
+
+impl TempPath {
+    /// A fresh path ending in `name`, nothing is created yet
+    pub fn new(name: &str) -> TempPath {
+        let n = NEXT.fetch_add(1, Ordering::Relaxed);
+        TempPath(std::env::temp_dir().join(format!("tetcher-test-{}-{}-{}", std::process::id(), n, name)))
+    }
+
+    /// A file holding `contents`
+    pub fn file(name: &str, contents: impl AsRef<[u8]>) -> TempPath {
+        let path = TempPath::new(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    /// An empty folder
+    pub fn dir(name: &str) -> TempPath {
+        let path = TempPath::new(name);
+        fs::create_dir(&path).unwrap();
+        path
+    }
+
+    /// The path as a string, the way device paths are passed around
+    pub fn as_str(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+}
+
+impl Deref for TempPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        if self.0.is_dir() {
+            let _ = fs::remove_dir_all(&self.0);
+        } else {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+}
+
+/// Stands in for tEtcher's data folder (see `rescue::data_dir`) for as long as it is kept, so the
+/// rescue files and resume journals a test makes end up in the temp folder
+///
+/// Tests without one have no data folder at all, nothing they do is saved in the user's home
+pub struct DataDir(TempPath);
+
+impl DataDir {
+    pub fn new() -> DataDir {
+        let dir = TempPath::dir("data");
+        DATA_DIR.set(Some(dir.to_path_buf()));
+        DataDir(dir)
+    }
+}
+
+impl Deref for DataDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for DataDir {
+    fn drop(&mut self) {
+        DATA_DIR.set(None);
+    }
+}
+
+/// The folder of the current test's `DataDir`, if it has one
+pub fn data_dir() -> Option<PathBuf> {
+    DATA_DIR.with_borrow(Clone::clone)
+}
@@ -0,0 +1,99 @@
+//! ui.rs holds the small menu building blocks shared by the newer screens
+//!
+//! They look and behave like the hand-written menus elsewhere: arrow keys to move,
+//! Enter to pick, Esc to back out
+
+use std::io::{Result, Write, stdout};
+
+use crossterm::{
+    cursor, execute,
+    event::{self, Event, KeyCode},
+    style::{Color, Stylize},
+    terminal::{self, ClearType},
+};
+
+/// Shows a title (which may span several lines) and a list of options, returning the index picked
+///
+/// Returns `None` if the user pressed Esc
+pub fn choose(title: &str, options: &[&str]) -> Result<Option<usize>> {
+    let mut stdout = stdout();
+    let mut selected = 0;
+    let title_lines = title.lines().count();
+
+    loop {
+        execute!(stdout, cursor::MoveTo(0, 0), terminal::Clear(ClearType::FromCursorDown))?;
+        for (i, line) in title.lines().enumerate() {
+            execute!(stdout, cursor::MoveTo(0, i as u16))?;
+            print!("{}", line);
+        }
+
+        for (i, item) in options.iter().enumerate() {
+            execute!(stdout, cursor::MoveTo(0, (title_lines + 1 + i) as u16))?;
+            if i == selected {
+                print!("  {}", item.on_white().black());
+            } else {
+                print!("  {}", item);
+            }
+        }
+
+        stdout.flush()?;
+
+        if let Event::Key(key) = event::read()? {
+            match key.code {
+                KeyCode::Up => selected = selected.saturating_sub(1),
+                KeyCode::Down if selected < options.len() - 1 => selected += 1,
+                KeyCode::Enter => return Ok(Some(selected)),
+                KeyCode::Esc => return Ok(None),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Asks a yes/no question, Esc counts as no
+pub fn confirm(title: &str) -> Result<bool> {
+    Ok(choose(title, &["Yes", "No"])? == Some(0))
+}
+
+/// Lets the user type a line of text, starting from `default`
+///
+/// Returns `None` if the user pressed Esc
+pub fn prompt(title: &str, default: &str) -> Result<Option<String>> {
+    let mut stdout = stdout();
+    let mut text = default.to_string();
+
+    execute!(stdout, cursor::Show)?;
+    let result = loop {
+        execute!(stdout, cursor::MoveTo(0, 0), terminal::Clear(ClearType::FromCursorDown))?;
+        print!("{}", title.with(Color::Blue));
+        execute!(stdout, cursor::MoveTo(0, 2))?;
+        print!("> {}", text);
+        stdout.flush()?;
+
+        if let Event::Key(key) = event::read()? {
+            match key.code {
+                KeyCode::Char(c) => text.push(c),
+                KeyCode::Backspace => {
+                    text.pop();
+                }
+                KeyCode::Enter => break Some(text),
+                KeyCode::Esc => break None,
+                _ => {}
+            }
+        }
+    };
+    execute!(stdout, cursor::Hide)?;
+
+    Ok(result)
+}
+
+/// Leaves a message on screen until a key is pressed
+pub fn pause() -> Result<()> {
+    print!("\r\n\r\n{}", "Press any key to continue".dark_grey());
+    stdout().flush()?;
+    loop {
+        if let Event::Key(_) = event::read()? {
+            return Ok(());
+        }
+    }
+}
@@ -0,0 +1,182 @@
+//! uring.rs is the io_uring write backend, built with `cargo build --features uring`
+//!
+//! With plain O_DIRECT every write waits for the drive before the next one is started, which leaves
+//! fast targets (USB 3.2 NVMe enclosures and the like) idle between writes. Here the data is copied
+//! into one of `DEPTH` aligned slots and handed to the kernel, so up to `DEPTH` writes are in flight
+//! at once and the writer only waits when every slot is busy
+//!
+//! devio.rs picks this when the running kernel supports it (5.6 and newer) and otherwise carries on
+//! with its blocking writes
+
+use std::fs::File;
+use std::io::{self, Result};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+
+use io_uring::{IoUring, Probe, opcode, types};
+
+use crate::devio::AlignedBuf;
+
+/// How many writes can be in flight at once
+const DEPTH: usize = 8;
+/// Size of each write, 1 MB
+const SLOT: usize = 1024 * 1024;
+
+/// A queue of O_DIRECT writes to one drive
+pub struct WriteRing {
+    ring: IoUring,
+    /// Our own handle on the drive, kept open for as long as writes are in flight
+    file: File,
+    slots: Vec<AlignedBuf>,
+    /// Where each slot is being written and how many bytes, `None` if the slot is free
+    busy: Vec<Option<(u64, usize)>>,
+}
+
+impl WriteRing {
+    /// Sets up a ring for `file`, `None` if the kernel has no io_uring or no IORING_OP_WRITE
+    pub fn new(file: &File) -> Option<WriteRing> {
+        let ring = IoUring::new(DEPTH as u32).ok()?;
+        let mut probe = Probe::new();
+        ring.submitter().register_probe(&mut probe).ok()?;
+        if !probe.is_supported(opcode::Write::CODE) {
+            return None;
+        }
+
+        Some(WriteRing {
+            ring,
+            file: file.try_clone().ok()?,
+            slots: (0..DEPTH).map(|_| AlignedBuf::new(SLOT)).collect(),
+            busy: vec![None; DEPTH],
+        })
+    }
+
+    /// Queues `data` to be written at `offset`, both must be in whole sectors
+    ///
+    /// Returns once it is all queued, not written. An error can belong to an earlier write
+    pub fn write(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        let mut pos = offset;
+        for chunk in data.chunks(SLOT) {
+            let slot = self.free_slot()?;
+            self.slots[slot][..chunk.len()].copy_from_slice(chunk);
+            self.busy[slot] = Some((pos, chunk.len()));
+
+            let entry = opcode::Write::new(types::Fd(self.file.as_raw_fd()), self.slots[slot].as_ptr(), chunk.len() as u32)
+                .offset(pos)
+                .build()
+                .user_data(slot as u64);
+            // SAFETY: the slot's buffer stays alive and untouched until its completion is reaped,
+            // `Drop` waits for every write still in flight
+            unsafe {
+                self.ring.submission().push(&entry).map_err(|_| io::Error::other("io_uring submission queue is full"))?;
+            }
+            self.ring.submit()?;
+            pos += chunk.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Waits for every write in flight to finish
+    pub fn drain(&mut self) -> Result<()> {
+        let mut result = Ok(());
+        let in_flight = |busy: &[Option<(u64, usize)>]| busy.iter().filter(|b| b.is_some()).count();
+        while in_flight(&self.busy) > 0 {
+            let before = in_flight(&self.busy);
+            // Keep waiting after a failed write, the slots must not be reused while the kernel has
+            // them. Only give up if the ring itself has stopped working
+            if let Err(e) = self.wait() {
+                if in_flight(&self.busy) == before {
+                    return Err(e);
+                }
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+
+    /// A free slot, waiting for a write to finish if they are all busy
+    fn free_slot(&mut self) -> Result<usize> {
+        loop {
+            if let Some(slot) = self.busy.iter().position(Option::is_none) {
+                return Ok(slot);
+            }
+            self.wait()?;
+        }
+    }
+
+    /// Waits for at least one write to finish and frees the slots of all that have
+    fn wait(&mut self) -> Result<()> {
+        match self.ring.submit_and_wait(1) {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => return Ok(()),
+            Err(e) => return Err(e),
+            Ok(_) => {}
+        }
+
+        let done: Vec<(u64, i32)> = self.ring.completion().map(|c| (c.user_data(), c.result())).collect();
+        let mut result = Ok(());
+        for (slot, written) in done {
+            let slot = slot as usize;
+            let Some((offset, len)) = self.busy[slot].take() else { continue };
+            let outcome = if written < 0 {
+                Err(io::Error::from_raw_os_error(-written))
+            } else {
+                // A short write is rare, the rest is written out the blocking way
+                let written = (written as usize).min(len);
+                self.file.write_all_at(&self.slots[slot][written..len], offset + written as u64)
+            };
+            if let Err(e) = outcome && result.is_ok() {
+                result = Err(e);
+            }
+        }
+        result
+    }
+}
+
+impl Drop for WriteRing {
+    fn drop(&mut self) {
+        let _ = self.drain();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempPath;
+
+    #[test]
+    fn queued_writes_all_land() {
+        let path = TempPath::new("writes");
+        let file = File::create(&path).unwrap();
+        // Kernels without io_uring (or sandboxes that block it) get the blocking writes instead
+        let Some(mut ring) = WriteRing::new(&file) else {
+            return;
+        };
+
+        // More than `DEPTH` slots' worth, so writing has to wait for slots to come free
+        let data: Vec<u8> = (0..(DEPTH + 3) * SLOT + 4096).map(|i| (i % 253) as u8).collect();
+        ring.write(4096, &data[..5 * SLOT]).unwrap();
+        ring.write(4096 + 5 * SLOT as u64, &data[5 * SLOT..]).unwrap();
+        ring.drain().unwrap();
+        drop(ring);
+
+        let written = std::fs::read(&path).unwrap();
+        assert!(written[..4096].iter().all(|&b| b == 0));
+        assert!(written[4096..] == data[..]);
+    }
+
+    #[test]
+    fn failed_writes_come_out_of_drain() {
+        let path = TempPath::file("read-only", [0u8; 4096]);
+        let file = File::open(&path).unwrap();
+        let Some(mut ring) = WriteRing::new(&file) else {
+            return;
+        };
+
+        // Opened read only, so the kernel refuses the write once it gets to it
+        let queued = ring.write(0, &[1u8; 4096]);
+        assert!(queued.is_err() || ring.drain().is_err());
+        // Nothing is left in flight afterwards
+        ring.drain().unwrap();
+    }
+}
@@ -1,8 +1,19 @@
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Result, stdout, Write};
+use std::io::{Read, Result, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::PathBuf;
 use std::process::Command;
-use sha2::{Sha256, Digest};
+use std::thread;
 use crossterm::style::Stylize;
+use crossterm::terminal::{disable_raw_mode, enable_raw_mode};
+use sha2::{Digest, Sha256};
+use crate::bmap::{self, Bmap, BmapChoice};
+use crate::image::{ImageReader, ImageSource, read_full};
+use crate::pool;
+use crate::progress::Progress;
+use crate::targ;
+use crate::ui;
+use crate::units;
 
 //
 // --- Cross-Platform Device Open Helper ---
@@ -10,14 +21,14 @@ use crossterm::style::Stylize;
 
 // On UNIX (Linux, macOS)
 #[cfg(unix)]
-fn open_device(path: &str) -> Result<File> {
+pub(crate) fn open_device(path: &str) -> Result<File> {
     // macOS note: prefer /dev/rdiskX (raw) over /dev/diskX for speed
     OpenOptions::new().read(true).open(path)
 }
 
 // On Windows
 #[cfg(windows)]
-fn open_device(path: &str) -> Result<File> {
+pub(crate) fn open_device(path: &str) -> Result<File> {
     use std::os::windows::fs::OpenOptionsExt;
     use winapi::um::winbase::FILE_FLAG_NO_BUFFERING;
 
@@ -43,68 +54,462 @@ fn flush_system() {
     // Do nothing; Windows flushes on file close
 }
 
+//
+// --- Verify Report ---
+//
+
+/// How many separate differing ranges are kept, a completely wrong drive would otherwise list millions
+const MAX_RANGES: usize = 32;
+
+/// The outcome of comparing the image with the device byte for byte
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// Size of the image, i.e. how many bytes should have been written
+    pub expected: u64,
+    /// How many bytes were actually compared
+    pub checked: u64,
+    /// Total number of bytes that differ
+    pub mismatched_bytes: u64,
+    /// The first differing byte ranges, with touching ranges merged
+    pub mismatches: Vec<Range<u64>>,
+    /// Set when there were more differing ranges than `MAX_RANGES`
+    pub more_ranges: bool,
+    /// Offset at which the device ran out of data before the image did
+    pub truncated_at: Option<u64>,
+}
+
+impl VerifyReport {
+    /// True when every byte of the image was found on the device
+    pub fn is_ok(&self) -> bool {
+        self.mismatched_bytes == 0 && self.truncated_at.is_none()
+    }
+
+    /// Records that bytes `start..end` differ, merging with the previous range when they touch
+    fn add_mismatch(&mut self, start: u64, end: u64) {
+        self.mismatched_bytes += end - start;
+
+        if let Some(last) = self.mismatches.last_mut()
+            && last.end == start {
+            last.end = end;
+            return;
+        }
+
+        if self.mismatches.len() < MAX_RANGES {
+            self.mismatches.push(start..end);
+        } else {
+            self.more_ranges = true;
+        }
+    }
+
+    /// Compares one chunk that starts at `offset` in the image
+    fn compare(&mut self, offset: u64, iso: &[u8], dev: &[u8]) {
+        if iso == dev {
+            return;
+        }
+
+        let mut start = None;
+        for (i, (a, b)) in iso.iter().zip(dev).enumerate() {
+            match (a == b, start) {
+                (false, None) => start = Some(i),
+                (true, Some(s)) => {
+                    self.add_mismatch(offset + s as u64, offset + i as u64);
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(s) = start {
+            self.add_mismatch(offset + s as u64, offset + iso.len() as u64);
+        }
+    }
+
+    /// Prints a human readable summary, e.g. which ranges differ or where the device was cut short
+    pub fn print(&self) {
+        if self.is_ok() {
+            println!("{}", format!("✅ Verification successful — all {} bytes match!", self.checked).green().bold());
+            return;
+        }
+
+        println!("{}", "❌ Verification failed — the device does not match the image.".red().bold());
+
+        if self.mismatched_bytes > 0 {
+            println!("{} bytes differ in these ranges:", self.mismatched_bytes);
+            for range in &self.mismatches {
+                println!(
+                    "  {:#014x} – {:#014x}  ({} bytes at {:.2} GiB)",
+                    range.start,
+                    range.end,
+                    range.end - range.start,
+                    range.start as f64 / GIB
+                );
+            }
+            if self.more_ranges {
+                println!("  ... and more");
+            }
+        }
+
+        if let Some(at) = self.truncated_at {
+            println!(
+                "The device ended after {} bytes ({:.2} GiB), but the image is {} bytes — the write was truncated",
+                at,
+                at as f64 / GIB,
+                self.expected
+            );
+        }
+    }
+}
+
+const GIB: f64 = 1024.0 * 1024.0 * 1024.0;
+
 //
 // --- Verify Function ---
 //
 
-/// Verifies that the ISO image was written correctly to a device by comparing SHA-256 hashes.
-/// Works on Linux, macOS, and Windows.
+/// Verifies a drive the way it was flashed: with a bmap only the mapped ranges were written,
+/// so only those are read back and checked against the bmap's checksums
+pub fn verify_flashed(iso_path: &ImageSource, device_path: &str, bmap: Option<&Bmap>) -> Result<VerifyReport> {
+    match bmap {
+        Some(bmap) => verify_ranges(bmap, device_path),
+        None => verify(iso_path, device_path),
+    }
+}
+
+/// Reads back every range the bmap lists and checks it against its checksum
 ///
-/// * Reads exactly `iso_size` bytes from the device.
-/// * Prints progress and returns `Ok(true)` if hashes match.
-pub fn verify(iso_path: &str, device_path: &str) -> Result<bool> {
+/// A range that does not match is reported whole, there is nothing to compare it with byte for byte
+pub fn verify_ranges(bmap: &Bmap, device_path: &str) -> Result<VerifyReport> {
     const BS: usize = 4 * 1024 * 1024; // 4 MB buffer
 
-    // Flush any pending write buffers to disk
     flush_system();
 
-    // Get ISO file size to know how many bytes to read from the device
-    let iso_size = File::open(iso_path)?.metadata()?.len();
-
-    let mut iso_file = File::open(iso_path)?;
     let mut dev_file = open_device(device_path)?;
+    let mut buf = vec![0u8; BS];
+    let mut report = VerifyReport { expected: bmap.mapped_bytes(), ..Default::default() };
+    let mut progress = Progress::new(Some(report.expected));
+
+    println!("{}", format!("Verifying the ranges listed in {}...", bmap.path.display()).blue().bold());
+
+    for range in &bmap.ranges {
+        dev_file.seek(SeekFrom::Start(range.bytes.start))?;
+        let mut hasher = bmap.checksum_type.hasher();
+        let mut left = range.bytes.end - range.bytes.start;
+
+        while left > 0 {
+            let want = left.min(BS as u64) as usize;
+            let n = read_full(&mut dev_file, &mut buf[..want])?;
+            hasher.update(&buf[..n]);
+            report.checked += n as u64;
+            left -= n as u64;
+            if n < want {
+                progress.finish(report.checked)?;
+                report.truncated_at = Some(range.bytes.end - left);
+                report.print();
+                return Ok(report);
+            }
+            progress.print(report.checked)?;
+        }
+
+        let actual = bmap::hex(&hasher.finalize());
+        if range.checksum.as_ref().is_some_and(|expected| *expected != actual) {
+            report.add_mismatch(range.bytes.start, range.bytes.end);
+        }
+    }
+    progress.finish(report.checked)?;
+
+    report.print();
+    Ok(report)
+}
+
+/// Verifies that the ISO image was written correctly to a device by comparing them chunk by chunk.
+/// Works on Linux, macOS, and Windows.
+///
+/// * Compressed images are decompressed on the fly, just like when flashing.
+/// * Reads exactly as many bytes from the device as the image holds, or less if the device is smaller.
+/// * Every byte is compared, so parts skipped with `skip_zeros` have to really read back as zeros.
+/// * Prints progress and returns a report listing every range that differs.
+pub fn verify(iso_path: &ImageSource, device_path: &str) -> Result<VerifyReport> {
+    // Flush any pending write buffers to disk
+    flush_system();
 
-    let mut iso_hash = Sha256::new();
-    let mut dev_hash = Sha256::new();
-    let mut buffer = vec![0u8; BS];
+    let iso_file = ImageReader::open(iso_path)?;
+    // Nothing past the image is read, the reading runs ahead of the comparing and the rest of a big
+    // drive could take a while (or fail to read) for nothing
+    let dev_file = open_device(device_path)?.take(iso_file.uncompressed_size.unwrap_or(u64::MAX));
+    let mut progress = match iso_file.uncompressed_size {
+        Some(total) => Progress::new(Some(total)),
+        None => Progress::new(None).estimated_by(iso_file.progress_meter()),
+    };
 
-    let mut bytes_read: u64 = 0;
-    let mut stdout = stdout();
+    let mut report = VerifyReport::default();
 
     println!("{}", "Verifying flashed image...".blue().bold());
 
-    while bytes_read < iso_size {
-        let to_read = std::cmp::min(BS as u64, iso_size - bytes_read) as usize;
+    // The image and the device are read at the same time, each on its own thread, and compared here
+    thread::scope(|scope| -> Result<()> {
+        let (iso_blocks, _) = pool::read_ahead(scope, iso_file);
+        let (dev_blocks, _) = pool::read_ahead(scope, dev_file);
+        let mut dev_blocks = dev_blocks.iter();
 
-        // Read ISO and device chunks
-        let iso_bytes = iso_file.read(&mut buffer[..to_read])?;
-        let dev_bytes = dev_file.read(&mut buffer[..to_read])?;
+        while let Some(iso_block) = iso_blocks.recv().ok().transpose()? {
+            let dev_block = dev_blocks.next().transpose()?;
+            let dev_block = dev_block.as_deref().unwrap_or(&[]);
 
-        if iso_bytes == 0 || dev_bytes == 0 {
-            break;
+            let iso_bytes = iso_block.len();
+            let dev_bytes = dev_block.len().min(iso_bytes);
+            report.compare(report.checked, &iso_block[..dev_bytes], &dev_block[..dev_bytes]);
+            report.checked += dev_bytes as u64;
+
+            if dev_bytes < iso_bytes {
+                report.truncated_at = Some(report.checked);
+                // Read out the rest of the image, so the report can say how big it should have been
+                report.expected = report.checked + (iso_bytes - dev_bytes) as u64;
+                for block in iso_blocks.iter() {
+                    report.expected += block?.len() as u64;
+                }
+                return Ok(());
+            }
+
+            report.expected = report.checked;
+            progress.print(report.checked)?;
         }
+        Ok(())
+    })?;
 
-        iso_hash.update(&buffer[..iso_bytes]);
-        dev_hash.update(&buffer[..dev_bytes]);
+    progress.finish(report.checked)?;
+    report.print();
+    Ok(report)
+}
 
-        bytes_read += iso_bytes as u64;
+//
+// --- Verify Against A Known SHA-256 ---
+//
+
+/// The outcome of hashing the start of a device and comparing it with a known SHA-256
+#[derive(Debug)]
+pub struct DigestReport {
+    /// The SHA-256 that was given, lower case hex
+    pub expected: String,
+    /// The SHA-256 of what was read from the device
+    pub actual: String,
+    /// How many bytes should have been hashed, i.e. the image size
+    pub size: u64,
+    /// How many bytes were actually hashed, less than `size` if the device is smaller
+    pub checked: u64,
+}
 
-        // Print progress percentage
-        let percent = (bytes_read as f64 / iso_size as f64) * 100.0;
-        print!("\rProgress: {:>6.2}%", percent);
-        stdout.flush()?;
+impl DigestReport {
+    /// True if the whole size was read and the digests match
+    pub fn is_ok(&self) -> bool {
+        self.checked == self.size && self.actual == self.expected
     }
 
-    println!("\nCalculating hashes...");
+    pub fn print(&self) {
+        if self.is_ok() {
+            println!("{}", format!("✅ Verification successful — the first {} bytes match the SHA-256!", self.size).green().bold());
+            return;
+        }
+
+        println!("{}", "❌ Verification failed — the device does not match the SHA-256.".red().bold());
+        if self.checked < self.size {
+            println!("The device ended after {} bytes, but the image is {} bytes", self.checked, self.size);
+        } else {
+            println!("  expected {}", self.expected);
+            println!("  found    {}", self.actual);
+        }
+    }
+}
 
-    let iso_digest = iso_hash.finalize();
-    let dev_digest = dev_hash.finalize();
+/// Tidies up a SHA-256 typed or pasted in, `None` unless it is 64 hex digits
+pub fn parse_sha256(text: &str) -> Option<String> {
+    let text = text.trim().to_ascii_lowercase();
+    (text.len() == 64 && text.bytes().all(|b| b.is_ascii_hexdigit())).then_some(text)
+}
 
-    if iso_digest == dev_digest {
-        println!("{}", "✅ Verification successful — hashes match!".green().bold());
-        Ok(true)
+/// Hashes the first `size` bytes of the device and compares them with `expected`
+///
+/// For when the image itself is not at hand, only its SHA-256 and size (e.g. from a download page)
+pub fn verify_sha256(device_path: &str, expected: &str, size: u64) -> Result<DigestReport> {
+    flush_system();
+
+    let dev_file = open_device(device_path)?;
+    let mut hash = Sha256::new();
+    let mut checked: u64 = 0;
+    let mut progress = Progress::new(Some(size));
+
+    println!("{}", "Verifying against the SHA-256...".blue().bold());
+
+    // The device is read ahead on its own thread while the blocks already read are hashed
+    thread::scope(|scope| -> Result<()> {
+        let (blocks, _) = pool::read_ahead(scope, dev_file.take(size));
+        for block in blocks {
+            let block = block?;
+            hash.update(&block[..]);
+            checked += block.len() as u64;
+            progress.print(checked)?;
+        }
+        Ok(())
+    })?;
+
+    progress.finish(checked)?;
+    let report = DigestReport {
+        expected: expected.to_string(),
+        actual: format!("{:x}", hash.finalize()),
+        size,
+        checked,
+    };
+    report.print();
+    Ok(report)
+}
+
+/// Menu flow for checking a drive that was flashed earlier, against an image or a known SHA-256
+pub fn menu(current_dir: &mut PathBuf) -> Result<()> {
+    let Some(reference) = ui::choose(
+        &"What should the drive be checked against?".blue().bold().to_string(),
+        &["The image file", "A known SHA-256 and size"],
+    )?
+    else {
+        return Ok(());
+    };
+
+    // Either an image, or a SHA-256 with the number of bytes it covers
+    let mut image = None;
+    let mut digest = None;
+
+    if reference == 0 {
+        let Some(source) = crate::pick_image("Please navigate to the image the drive was flashed with", current_dir)? else {
+            return Ok(());
+        };
+        image = Some(source);
     } else {
-        println!("{}", "❌ Verification failed — hashes differ.".red().bold());
-        Ok(false)
+        let Some(sha256) = ui::prompt("SHA-256 of the image:", "")? else {
+            return Ok(());
+        };
+        let Some(sha256) = parse_sha256(&sha256) else {
+            print!("\x1B[H\x1B[2J");
+            print!("{}", "That is not a SHA-256, it should be 64 hexadecimal digits".red());
+            return ui::pause();
+        };
+        let Some(size) = ui::prompt("Size of the image in bytes:", "")? else {
+            return Ok(());
+        };
+        let Ok(size) = size.trim().parse::<u64>() else {
+            print!("\x1B[H\x1B[2J");
+            print!("{}", "The size has to be a whole number of bytes".red());
+            return ui::pause();
+        };
+        digest = Some((sha256, size));
+    }
+
+    let Some(drive) = targ::pick_drive("Which drive do you want to verify?")? else {
+        return Ok(());
+    };
+
+    // The reports print plain lines, so leave raw mode while they run
+    print!("\x1B[H\x1B[2J");
+    disable_raw_mode()?;
+    let outcome = match (&image, &digest) {
+        (Some(source), _) => {
+            println!("Checking {} against {}", drive.label(), source);
+            // A drive flashed with a bmap only holds the mapped ranges
+            BmapChoice::Auto.load(source).and_then(|bmap| verify_flashed(source, &drive.path, bmap.as_ref())).map(|_| ())
+        }
+        (_, Some((sha256, size))) => {
+            println!("Checking the first {} of {} against {}", units::human_size(*size), drive.label(), sha256);
+            verify_sha256(&drive.path, sha256, *size).map(|_| ())
+        }
+        _ => Ok(()),
+    };
+    if let Err(e) = outcome {
+        println!("{}", format!("Could not read {}: {}", drive.path, e).red());
+    }
+    enable_raw_mode()?;
+    ui::pause()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempPath;
+
+    #[test]
+    fn touching_ranges_are_merged_across_chunks() {
+        let mut report = VerifyReport::default();
+        report.compare(0, b"aaaaaaaa", b"aaaaaXXX");
+        report.compare(8, b"aaaaaaaa", b"XXaaaaaX");
+        assert_eq!(report.mismatches, vec![5..10, 15..16]);
+        assert_eq!(report.mismatched_bytes, 6);
+        assert!(!report.is_ok());
+    }
+
+    #[test]
+    fn matching_chunks_leave_no_trace() {
+        let mut report = VerifyReport::default();
+        report.compare(0, b"same", b"same");
+        assert!(report.is_ok() && report.mismatches.is_empty());
+
+        report.truncated_at = Some(4);
+        assert!(!report.is_ok());
+    }
+
+    #[test]
+    fn ranges_stop_being_listed_after_max_ranges() {
+        let mut report = VerifyReport::default();
+        let iso = vec![0u8; MAX_RANGES * 4];
+        let dev: Vec<u8> = (0..iso.len()).map(|i| (i % 2) as u8).collect();
+        report.compare(0, &iso, &dev);
+        assert_eq!(report.mismatches.len(), MAX_RANGES);
+        assert!(report.more_ranges);
+        // Every differing byte is still counted
+        assert_eq!(report.mismatched_bytes, iso.len() as u64 / 2);
+    }
+
+    #[test]
+    fn verify_finds_the_differences_in_a_file() {
+        let data: Vec<u8> = (0..=255).cycle().take(3 << 20).collect();
+        let image = TempPath::file("verify.img", &data);
+
+        // Bigger than the image, the rest is not compared
+        let mut on_drive = data.clone();
+        on_drive[1 << 20] ^= 1;
+        on_drive.extend([7u8; 4096]);
+        let device = TempPath::file("verify.dev", &on_drive);
+        let report = verify(&ImageSource::file(&*image), device.as_str()).unwrap();
+        assert_eq!((report.checked, report.expected), (3 << 20, 3 << 20));
+        assert_eq!(report.mismatches, vec![1 << 20..(1 << 20) + 1]);
+
+        // Smaller than the image
+        std::fs::write(&device, &data[..1 << 20]).unwrap();
+        let report = verify(&ImageSource::file(&*image), device.as_str()).unwrap();
+        assert_eq!(report.truncated_at, Some(1 << 20));
+        assert_eq!(report.expected, 3 << 20);
+    }
+
+    #[test]
+    fn sha256_is_tidied_up() {
+        let sum = "E3B0C44298FC1C149AFBF4C8996FB92427AE41E4649B934CA495991B7852B855";
+        assert_eq!(parse_sha256(&format!("  {}\n", sum)), Some(sum.to_ascii_lowercase()));
+        assert_eq!(parse_sha256(&sum[1..]), None);
+        assert_eq!(parse_sha256(&sum.replace('E', "g")), None);
+        assert_eq!(parse_sha256(&format!("{}  image.iso", sum)), None);
+    }
+
+    #[test]
+    fn verify_sha256_hashes_only_the_image_size() {
+        let mut on_drive = b"hello world".to_vec();
+        on_drive.extend([0u8; 4096]);
+        let device = TempPath::file("sha.dev", &on_drive);
+
+        let hello = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+        let report = verify_sha256(device.as_str(), hello, 11).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.actual, hello);
+
+        let report = verify_sha256(device.as_str(), hello, 12).unwrap();
+        assert!(!report.is_ok());
+
+        // The device ends before the image would
+        let report = verify_sha256(device.as_str(), hello, 1 << 20).unwrap();
+        assert_eq!((report.checked, report.size), (on_drive.len() as u64, 1 << 20));
+        assert!(!report.is_ok());
     }
 }